@@ -0,0 +1,372 @@
+use bytes::Bytes;
+use http::{header, Method, Request, Response, StatusCode};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Incoming;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::info;
+
+use crate::config::Config;
+use crate::cookies::{handle_endpoint, CookieJar};
+use crate::fault::Faults;
+use crate::har::{iso8601, Recorder};
+use crate::intercept::{Breakpoint, Edit, Intercept};
+use crate::mock::MockRule;
+
+/// Paths under this prefix are served by the proxy itself
+pub const ADMIN_PREFIX: &str = "/__holy/";
+
+/// Requests kept for `GET /__holy/requests`
+const HISTORY_SIZE: usize = 200;
+
+/// Changes made through the admin API; they outlive config reloads
+#[derive(Default)]
+pub struct Admin {
+    mocks: RwLock<Arc<Mocks>>,
+    history: Mutex<VecDeque<Value>>,
+    faults: Faults,
+    intercept: Intercept,
+}
+
+/// Mock rules added at runtime, checked before the config file's
+#[derive(Clone, Default)]
+pub struct Mocks {
+    next_id: u64,
+    ids: Vec<u64>,
+    pub rules: Vec<MockRule>,
+}
+
+impl Mocks {
+    fn add(&mut self, rule: MockRule) -> u64 {
+        self.next_id += 1;
+        self.ids.push(self.next_id);
+        self.rules.push(rule);
+        self.next_id
+    }
+}
+
+impl Admin {
+    /// Start with fault injection seeded from `--fault-seed`
+    pub fn new(fault_seed: Option<u64>) -> Self {
+        Self {
+            faults: Faults::new(fault_seed),
+            ..Default::default()
+        }
+    }
+
+    /// The fault injection switch and random source
+    pub fn faults(&self) -> &Faults {
+        &self.faults
+    }
+
+    /// Runtime breakpoints and the messages paused at them
+    pub fn intercept(&self) -> &Intercept {
+        &self.intercept
+    }
+
+    /// The runtime mock rules in effect
+    pub fn mocks(&self) -> Arc<Mocks> {
+        Arc::clone(&self.mocks.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Apply a change to the mock rules; requests see all of it or none
+    fn update_mocks<T>(&self, change: impl FnOnce(&mut Mocks) -> T) -> T {
+        let mut current = self.mocks.write().unwrap_or_else(|e| e.into_inner());
+        let mut mocks = Mocks::clone(&current);
+        let result = change(&mut mocks);
+        *current = Arc::new(mocks);
+        result
+    }
+
+    /// Remember a handled request for `GET /__holy/requests`
+    pub fn log_request(&self, method: &Method, uri: &http::Uri, status: StatusCode, started: SystemTime, duration: Duration) {
+        if uri.path().starts_with(ADMIN_PREFIX) {
+            return;
+        }
+        let entry = json!({
+            "time": iso8601(started),
+            "method": method.as_str(),
+            "uri": uri.to_string(),
+            "status": status.as_u16(),
+            "duration_ms": duration.as_millis() as u64,
+        });
+        let mut history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+        if history.len() == HISTORY_SIZE {
+            history.pop_front();
+        }
+        history.push_back(entry);
+    }
+
+    /// Serve an admin endpoint; callers must present `--admin-token` as a bearer token
+    pub async fn handle(
+        &self,
+        req: Request<Incoming>,
+        config: &Config,
+        cookies: &CookieJar,
+        recorder: Option<&Arc<Recorder>>,
+    ) -> Response<Full<Bytes>> {
+        let Some(token) = &config.admin_token else {
+            return json_response(
+                StatusCode::NOT_FOUND,
+                json!({ "error": "Admin API is disabled. Use --admin-token to enable it." }),
+            );
+        };
+        let presented = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        if !presented.is_some_and(|presented| constant_time_eq(presented.as_bytes(), token.as_bytes())) {
+            return json_response(StatusCode::UNAUTHORIZED, json!({ "error": "Missing or invalid admin token" }));
+        }
+
+        let method = req.method().clone();
+        let path = req.uri().path()[ADMIN_PREFIX.len()..].trim_end_matches('/').to_string();
+
+        // The caller's own jar, chosen by its Origin or X-Holy-Cors-Session header
+        if path == "cookies" {
+            let origin = req.headers().get(header::ORIGIN).and_then(|v| v.to_str().ok()).unwrap_or_default();
+            return handle_endpoint(&method, config.cookie_jar, req.headers(), origin, cookies);
+        }
+
+        let query = req.uri().query().unwrap_or_default().to_string();
+        let body = match req.into_body().collect().await {
+            Ok(collected) => collected.to_bytes(),
+            Err(_) => return json_response(StatusCode::BAD_REQUEST, json!({ "error": "Failed to read request body" })),
+        };
+
+        let segments: Vec<&str> = path.split('/').collect();
+        let result = match (&method, segments.as_slice()) {
+            (&Method::GET, ["mocks"]) => Ok((StatusCode::OK, self.list_mocks())),
+            (&Method::POST, ["mocks"]) => parse_mock(config, &body).map(|rule| {
+                let id = self.update_mocks(|mocks| mocks.add(rule));
+                info!("Admin API: added mock {}", id);
+                (StatusCode::CREATED, json!({ "id": id }))
+            }),
+            (&Method::PUT, ["mocks"]) => parse_mocks(config, &body).map(|rules| {
+                let ids: Vec<u64> = self.update_mocks(|mocks| {
+                    mocks.ids.clear();
+                    mocks.rules.clear();
+                    rules.into_iter().map(|rule| mocks.add(rule)).collect()
+                });
+                info!("Admin API: replaced mocks with {} rule(s)", ids.len());
+                (StatusCode::OK, json!({ "ids": ids }))
+            }),
+            (&Method::DELETE, ["mocks"]) => {
+                let removed = self.update_mocks(|mocks| {
+                    mocks.ids.clear();
+                    std::mem::take(&mut mocks.rules).len()
+                });
+                Ok((StatusCode::OK, json!({ "removed": removed })))
+            }
+            (&Method::DELETE, ["mocks", id]) => {
+                let id = id.parse::<u64>().unwrap_or_default();
+                let removed = self.update_mocks(|mocks| {
+                    let i = mocks.ids.iter().position(|&other| other == id)?;
+                    mocks.ids.remove(i);
+                    Some(mocks.rules.remove(i))
+                });
+                match removed {
+                    Some(_) => Ok((StatusCode::OK, json!({ "removed": 1 }))),
+                    None => Err((StatusCode::NOT_FOUND, format!("No mock with id {}", id))),
+                }
+            }
+            (&Method::GET, ["faults"]) => Ok((StatusCode::OK, self.fault_status(config))),
+            (&Method::PUT, ["faults"]) => serde_json::from_slice::<FaultSettings>(&body)
+                .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid fault settings: {}", e)))
+                .map(|settings| {
+                    if let Some(enabled) = settings.enabled {
+                        self.faults.set_enabled(enabled);
+                        info!("Admin API: fault injection {}", if enabled { "enabled" } else { "disabled" });
+                    }
+                    if let Some(seed) = settings.seed {
+                        self.faults.reseed(seed);
+                        info!("Admin API: fault injection reseeded with {}", seed);
+                    }
+                    (StatusCode::OK, self.fault_status(config))
+                }),
+            (&Method::GET, ["breakpoints"]) => Ok((
+                StatusCode::OK,
+                json!({ "config": config.breakpoints, "runtime": *self.intercept.breakpoints() }),
+            )),
+            (&Method::PUT, ["breakpoints"]) => parse_breakpoints(config, &body).map(|breakpoints| {
+                info!("Admin API: set {} breakpoint(s)", breakpoints.len());
+                let count = breakpoints.len();
+                self.intercept.set_breakpoints(breakpoints);
+                (StatusCode::OK, json!({ "breakpoints": count }))
+            }),
+            (&Method::DELETE, ["breakpoints"]) => {
+                let removed = self.intercept.breakpoints().len();
+                self.intercept.set_breakpoints(Vec::new());
+                Ok((StatusCode::OK, json!({ "removed": removed })))
+            }
+            (&Method::GET, ["paused"]) => Ok((StatusCode::OK, self.intercept.list())),
+            (&Method::POST, ["paused", id, "resume"]) => {
+                let edit = match body.is_empty() {
+                    true => Ok(Edit::default()),
+                    false => serde_json::from_slice::<Edit>(&body)
+                        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid edit: {}", e))),
+                };
+                let id = id.parse::<u64>().unwrap_or_default();
+                edit.and_then(|edit| self.intercept.resume(id, edit)).map(|()| {
+                    info!("Admin API: resumed #{}", id);
+                    (StatusCode::OK, json!({ "resumed": id }))
+                })
+            }
+            (&Method::DELETE, ["paused", id]) => {
+                let id = id.parse::<u64>().unwrap_or_default();
+                self.intercept.drop_paused(id).map(|()| {
+                    info!("Admin API: dropped #{}", id);
+                    (StatusCode::OK, json!({ "dropped": id }))
+                })
+            }
+            (&Method::DELETE, ["cookie-jars"]) => Ok((StatusCode::OK, json!({ "cleared": cookies.clear_all() }))),
+            (&Method::DELETE, ["recording"]) => match recorder {
+                Some(recorder) => recorder
+                    .reset()
+                    .await
+                    .map(|removed| (StatusCode::OK, json!({ "removed": removed })))
+                    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e)),
+                None => Err((StatusCode::NOT_FOUND, "Not recording. Use --record to enable it.".to_string())),
+            },
+            (&Method::GET, ["requests"]) => {
+                let limit = url::form_urlencoded::parse(query.as_bytes())
+                    .find(|(name, _)| name == "limit")
+                    .and_then(|(_, value)| value.parse::<usize>().ok())
+                    .unwrap_or(HISTORY_SIZE);
+                let history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+                let recent: Vec<&Value> = history.iter().skip(history.len().saturating_sub(limit)).collect();
+                Ok((StatusCode::OK, json!({ "requests": recent })))
+            }
+            (&Method::DELETE, ["requests"]) => {
+                let mut history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+                let removed = history.len();
+                history.clear();
+                Ok((StatusCode::OK, json!({ "removed": removed })))
+            }
+            _ => Err((StatusCode::NOT_FOUND, format!("No admin endpoint {} {}{}", method, ADMIN_PREFIX, path))),
+        };
+
+        match result {
+            Ok((status, body)) => json_response(status, body),
+            Err((status, message)) => json_response(status, json!({ "error": message })),
+        }
+    }
+
+    fn fault_status(&self, config: &Config) -> Value {
+        json!({
+            "enabled": self.faults.enabled(),
+            "seed": self.faults.seed(),
+            "rules": config.faults,
+        })
+    }
+
+    fn list_mocks(&self) -> Value {
+        let mocks = self.mocks();
+        let list: Vec<Value> = mocks
+            .ids
+            .iter()
+            .zip(&mocks.rules)
+            .map(|(id, rule)| {
+                let mut value = serde_json::to_value(rule).unwrap_or_default();
+                value["id"] = json!(id);
+                value
+            })
+            .collect();
+        json!({ "mocks": list })
+    }
+}
+
+/// Body of `PUT /__holy/faults`; unset fields are left alone
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FaultSettings {
+    enabled: Option<bool>,
+    seed: Option<u64>,
+}
+
+fn parse_mock(config: &Config, body: &[u8]) -> Result<MockRule, (StatusCode, String)> {
+    let rule: MockRule =
+        serde_json::from_slice(body).map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid mock rule: {}", e)))?;
+    check_mock(config, &rule).map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid mock rule: {}", e)))?;
+    Ok(rule)
+}
+
+fn parse_mocks(config: &Config, body: &[u8]) -> Result<Vec<MockRule>, (StatusCode, String)> {
+    let rules: Vec<MockRule> =
+        serde_json::from_slice(body).map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid mock rules: {}", e)))?;
+    for (i, rule) in rules.iter().enumerate() {
+        check_mock(config, rule).map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid mock rule {}: {}", i, e)))?;
+    }
+    Ok(rules)
+}
+
+fn parse_breakpoints(config: &Config, body: &[u8]) -> Result<Vec<Breakpoint>, (StatusCode, String)> {
+    let breakpoints: Vec<Breakpoint> =
+        serde_json::from_slice(body).map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid breakpoints: {}", e)))?;
+    for (i, breakpoint) in breakpoints.iter().enumerate() {
+        config
+            .check_route("route", breakpoint.route.as_deref())
+            .and_then(|()| breakpoint.validate())
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid breakpoint {}: {}", i, e)))?;
+    }
+    Ok(breakpoints)
+}
+
+fn check_mock(config: &Config, rule: &MockRule) -> Result<(), String> {
+    config.check_route("route", rule.route.as_deref())?;
+    rule.validate()
+}
+
+fn json_response(status: StatusCode, body: Value) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .unwrap()
+}
+
+/// Compare secrets without revealing where they differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_mocks() {
+        let admin = Admin::default();
+        let before = admin.mocks();
+        let id = admin.update_mocks(|mocks| mocks.add(MockRule::default()));
+        admin.update_mocks(|mocks| mocks.add(MockRule::default()));
+        assert_eq!(id, 1);
+        assert!(before.rules.is_empty());
+        assert_eq!(admin.mocks().ids, vec![1, 2]);
+        assert_eq!(admin.list_mocks()["mocks"][1]["id"], 2);
+    }
+
+    #[test]
+    fn test_history() {
+        let admin = Admin::default();
+        for i in 0..HISTORY_SIZE + 5 {
+            let uri: http::Uri = format!("/https://example.com/{}", i).parse().unwrap();
+            admin.log_request(&Method::GET, &uri, StatusCode::OK, SystemTime::now(), Duration::ZERO);
+        }
+        admin.log_request(&Method::GET, &"/__holy/requests".parse().unwrap(), StatusCode::OK, SystemTime::now(), Duration::ZERO);
+        let history = admin.history.lock().unwrap();
+        assert_eq!(history.len(), HISTORY_SIZE);
+        assert_eq!(history[0]["uri"], "/https://example.com/5");
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+    }
+}
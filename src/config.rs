@@ -1,5 +1,24 @@
-use clap::Parser;
-use std::collections::HashSet;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+use crate::config_file::{self, FileConfig};
+use crate::cookies::{CookieJarMode, SetCookieRule};
+use crate::credentials::{CredentialRule, Secrets};
+use crate::fault::FaultRule;
+use crate::intercept::Breakpoint;
+use crate::mock::MockRule;
+use crate::oauth::OAuth2Rule;
+use crate::openapi::OpenApiRule;
+use crate::redirect::RedirectPolicy;
+use crate::replay::{MatchOn, ReplayMode};
+use crate::resolve::ResolveOverride;
+use crate::rules::{HeaderRule, RuleContext};
+use crate::sigv4::{AwsCredentials, SigV4Rule};
+use crate::transform::BodyRule;
+use crate::unix::{UnixTarget, UNIX_PREFIX};
 
 /// Default allowed origins (bugdays.com)
 const DEFAULT_ORIGINS: &[&str] = &[
@@ -16,6 +35,13 @@ const DEFAULT_ORIGINS: &[&str] = &[
 #[command(version)]
 #[command(about = "Holy CORS! A fast CORS proxy for developers", long_about = None)]
 pub struct Config {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Configuration file (default: ./holy-cors.toml, then $XDG_CONFIG_HOME/holy-cors/holy-cors.toml)
+    #[arg(long = "config", env = "HOLY_CORS_CONFIG", value_name = "PATH", global = true)]
+    pub config_file: Option<PathBuf>,
+
     /// Port to listen on
     #[arg(short, long, default_value = "2345", env = "HOLY_CORS_PORT")]
     pub port: u16,
@@ -35,9 +61,339 @@ pub struct Config {
     /// Bind address (default: 0.0.0.0)
     #[arg(long, default_value = "0.0.0.0", env = "HOLY_CORS_BIND")]
     pub bind: String,
+
+    /// Address to listen on: IP:PORT, [IPv6]:PORT or unix:PATH (can be specified multiple times, overrides --bind/--port)
+    #[arg(long = "listen", env = "HOLY_CORS_LISTEN", value_delimiter = ',', value_name = "ADDRESS")]
+    pub listen: Vec<String>,
+
+    /// Seconds to let in-flight requests finish on shutdown
+    #[arg(long = "shutdown-timeout", default_value = "10", env = "HOLY_CORS_SHUTDOWN_TIMEOUT", value_name = "SECONDS")]
+    pub shutdown_timeout: u64,
+
+    /// Extra CA certificates (PEM) to trust for upstream TLS
+    #[arg(long = "ca-file", env = "HOLY_CORS_CA_FILE", value_name = "PATH")]
+    pub ca_file: Option<PathBuf>,
+
+    /// Use the bundled Mozilla roots instead of the system certificate store
+    #[arg(long = "bundled-roots", env = "HOLY_CORS_BUNDLED_ROOTS", default_value = "false")]
+    pub bundled_roots: bool,
+
+    /// Skip TLS certificate verification for a host (can be specified multiple times)
+    #[arg(long = "insecure-host", env = "HOLY_CORS_INSECURE_HOSTS", value_delimiter = ',', value_name = "HOST")]
+    pub insecure_hosts: Vec<String>,
+
+    /// Client certificate (PEM) for mutual TLS with a host, as HOST=PATH
+    #[arg(long = "client-cert", value_name = "HOST=PATH")]
+    pub client_certs: Vec<String>,
+
+    /// Client private key (PEM) for a host, as HOST=PATH (defaults to the certificate file)
+    #[arg(long = "client-key", value_name = "HOST=PATH")]
+    pub client_keys: Vec<String>,
+
+    /// Connect to ADDR instead of resolving HOST, as HOST:PORT:ADDR[:PORT] (can be specified multiple times)
+    #[arg(long = "resolve", env = "HOLY_CORS_RESOLVE", value_delimiter = ',', value_name = "HOST:PORT:ADDR")]
+    pub resolve: Vec<String>,
+
+    /// Unix socket that /unix:SOCKET:PATH targets may reach (can be specified multiple times);
+    /// sockets behind route aliases are always allowed
+    #[arg(long = "allow-unix-socket", env = "HOLY_CORS_ALLOW_UNIX_SOCKETS", value_delimiter = ',', value_name = "PATH")]
+    pub allow_unix_sockets: Vec<PathBuf>,
+
+    /// Keep upstream cookies server-side, per browser origin or per X-Holy-Cors-Session header
+    #[arg(long = "cookie-jar", env = "HOLY_CORS_COOKIE_JAR", value_enum, default_value = "off", value_name = "MODE")]
+    pub cookie_jar: CookieJarMode,
+
+    /// What to do with upstream redirects: passthrough, rewrite (Location through the proxy) or follow
+    #[arg(long = "redirects", env = "HOLY_CORS_REDIRECTS", value_enum, default_value = "passthrough", value_name = "POLICY")]
+    pub redirects: RedirectPolicy,
+
+    /// Maximum number of redirects to follow with --redirects follow
+    #[arg(long = "max-redirects", env = "HOLY_CORS_MAX_REDIRECTS", default_value = "10", value_name = "N")]
+    pub max_redirects: u32,
+
+    /// Rewrite URLs in HTML and CSS responses to go through the proxy (for browsing sites through it)
+    #[arg(long = "rewrite-urls", env = "HOLY_CORS_REWRITE_URLS", default_value = "false")]
+    pub rewrite_urls: bool,
+
+    /// Record proxied traffic to a HAR file
+    #[arg(long = "record", env = "HOLY_CORS_RECORD", value_name = "PATH")]
+    pub record: Option<PathBuf>,
+
+    /// Largest body (or WebSocket message) kept in full when recording
+    #[arg(long = "record-max-body", env = "HOLY_CORS_RECORD_MAX_BODY", default_value = "1048576", value_name = "BYTES")]
+    pub record_max_body: usize,
+
+    /// Serve responses recorded in a HAR file instead of contacting the upstream
+    #[arg(long = "replay", env = "HOLY_CORS_REPLAY", value_name = "PATH")]
+    pub replay: Option<PathBuf>,
+
+    /// What to do with requests that have no recorded response: strict (502) or fallthrough (forward)
+    #[arg(long = "replay-mode", env = "HOLY_CORS_REPLAY_MODE", value_enum, default_value = "strict", value_name = "MODE")]
+    pub replay_mode: ReplayMode,
+
+    /// Request parts that select a recorded response: method, url, path, body, header:NAME
+    #[arg(long = "replay-match", env = "HOLY_CORS_REPLAY_MATCH", value_delimiter = ',', default_value = "method,url", value_name = "PART")]
+    pub replay_match: Vec<String>,
+
+    /// Seed for the random choices of fault injection, so a run can be repeated
+    #[arg(long = "fault-seed", env = "HOLY_CORS_FAULT_SEED", value_name = "N")]
+    pub fault_seed: Option<u64>,
+
+    /// Seconds a request or response waits at a breakpoint before failing with 504
+    #[arg(long = "breakpoint-timeout", env = "HOLY_CORS_BREAKPOINT_TIMEOUT", default_value = "120", value_name = "SECONDS")]
+    pub breakpoint_timeout: u64,
+
+    /// Enable the admin API under /__holy/ for callers presenting this bearer token
+    #[arg(long = "admin-token", env = "HOLY_CORS_ADMIN_TOKEN", value_name = "TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+
+    /// TOML file of named secrets for credential injection
+    #[arg(long = "secrets-file", env = "HOLY_CORS_SECRETS_FILE", value_name = "PATH")]
+    pub secrets_file: Option<PathBuf>,
+
+    /// Route aliases from the config file: name -> target
+    #[arg(skip)]
+    pub routes: BTreeMap<String, String>,
+
+    /// Canned responses served instead of the upstream, from the config file
+    #[arg(skip)]
+    pub mocks: Vec<MockRule>,
+
+    /// OpenAPI documents to validate against and mock from, from the config file
+    #[arg(skip)]
+    pub openapi: Vec<OpenApiRule>,
+
+    /// Latency, errors and broken bodies injected for matching upstreams, from the config file
+    #[arg(skip)]
+    pub faults: Vec<FaultRule>,
+
+    /// Requests and responses paused for editing, from the config file
+    #[arg(skip)]
+    pub breakpoints: Vec<Breakpoint>,
+
+    /// Rewrite rules for headers sent upstream, from the config file
+    #[arg(skip)]
+    pub request_headers: Vec<HeaderRule>,
+
+    /// Rewrite rules for headers returned to the browser, from the config file
+    #[arg(skip)]
+    pub response_headers: Vec<HeaderRule>,
+
+    /// Transforms for bodies sent upstream, from the config file
+    #[arg(skip)]
+    pub request_body: Vec<BodyRule>,
+
+    /// Transforms for bodies returned to the browser, from the config file
+    #[arg(skip)]
+    pub response_body: Vec<BodyRule>,
+
+    /// Set-Cookie rewrite rules, from the config file
+    #[arg(skip)]
+    pub set_cookie: Vec<SetCookieRule>,
+
+    /// Credentials injected for matching upstreams, from the config file
+    #[arg(skip)]
+    pub credentials: Vec<CredentialRule>,
+
+    /// AWS SigV4 signing for matching upstreams, from the config file
+    #[arg(skip)]
+    pub aws_sigv4: Vec<SigV4Rule>,
+
+    /// OAuth2 token management for matching upstreams, from the config file
+    #[arg(skip)]
+    pub oauth2: Vec<OAuth2Rule>,
+
+    /// Values loaded from the secrets file
+    #[arg(skip)]
+    pub secrets: Secrets,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ConfigCommand {
+    /// Print the effective configuration (file, environment and flags merged)
+    Print,
+}
+
+/// An address the proxy accepts connections on
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ListenAddr {
+    /// Parse `IP:PORT`, `[IPv6]:PORT` or `unix:PATH`
+    pub fn parse(s: &str) -> Result<Self, String> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(format!("--listen expects unix:PATH, got '{}'", s));
+            }
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+
+        s.parse::<SocketAddr>()
+            .map(ListenAddr::Tcp)
+            .map_err(|_| format!("--listen expects IP:PORT, [IPv6]:PORT or unix:PATH, got '{}'", s))
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "http://{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// The proxy path a target is reached through and the upstream path it stands
+/// for, e.g. `/github` for `/v3` or `/https://api.example.com` for the root
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyPrefix {
+    pub prefix: String,
+    /// Upstream base path, without a trailing slash
+    pub base: String,
+}
+
+impl ProxyPrefix {
+    /// Map an upstream path to the proxy path reaching it, if it is under the base
+    pub fn map(&self, upstream_path: &str) -> Option<String> {
+        let rest = upstream_path.strip_prefix(self.base.as_str())?;
+        if !rest.is_empty() && !rest.starts_with('/') {
+            return None;
+        }
+        Some(format!("{}{}", self.prefix, rest))
+    }
+}
+
+/// Client certificate and key used for mutual TLS with one upstream host
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientIdentity {
+    pub cert: PathBuf,
+    pub key: PathBuf,
 }
 
 impl Config {
+    /// Parse the command line and environment (exits on invalid arguments)
+    pub fn args() -> ArgMatches {
+        Self::command().get_matches()
+    }
+
+    /// Build the config from parsed arguments, layered over the config file.
+    /// Returns the config and the file it was loaded from, if any.
+    pub fn load(matches: &ArgMatches) -> Result<(Self, Option<PathBuf>), String> {
+        let mut config = Self::from_arg_matches(matches).map_err(|e| e.to_string())?;
+
+        let path = config_file::discover(config.config_file.as_deref());
+        let mut text = None;
+        if let Some(path) = &path {
+            let (file, source) = FileConfig::load(path)?;
+            file.apply(&mut config, matches);
+            text = Some(source);
+        }
+        if let Some(secrets_file) = &config.secrets_file {
+            config.secrets = Secrets::load(secrets_file)?;
+        }
+
+        // Point errors in file entries, like `mocks[1]: ...`, at their line
+        config.validate().map_err(|e| match (&path, &text) {
+            (Some(path), Some(text)) => match config_file::locate(text, &e) {
+                Some(line) => format!("{}:{}: {}", path.display(), line, e),
+                None => e,
+            },
+            _ => e,
+        })?;
+        Ok((config, path))
+    }
+
+    /// Check options that can only be validated once all layers are merged
+    pub fn validate(&self) -> Result<(), String> {
+        self.listen_addrs()?;
+        self.client_identities()?;
+        self.resolve_overrides()?;
+        for part in &self.replay_match {
+            if let MatchOn::Header(name) = MatchOn::parse(part)? {
+                if self.credentials.iter().any(|rule| rule.headers.keys().any(|key| key.0 == name)) {
+                    return Err(format!("Cannot replay match on {}: it is injected by a credentials rule, and recordings redact it", name));
+                }
+            }
+        }
+
+        for (i, rule) in self.mocks.iter().enumerate() {
+            let section = format!("mocks[{}]", i);
+            self.check_route(&section, rule.route.as_deref())?;
+            rule.validate().map_err(|e| format!("{}: {}", section, e))?;
+        }
+        for (i, rule) in self.faults.iter().enumerate() {
+            let section = format!("faults[{}]", i);
+            self.check_route(&section, rule.route.as_deref())?;
+            rule.validate().map_err(|e| format!("{}: {}", section, e))?;
+        }
+        for (i, breakpoint) in self.breakpoints.iter().enumerate() {
+            let section = format!("breakpoints[{}]", i);
+            self.check_route(&section, breakpoint.route.as_deref())?;
+            breakpoint.validate().map_err(|e| format!("{}: {}", section, e))?;
+        }
+        if !self.breakpoints.is_empty() && self.admin_token.is_none() {
+            return Err("breakpoints need --admin-token, since paused requests are released through the admin API".to_string());
+        }
+        for (i, rule) in self.openapi.iter().enumerate() {
+            self.check_route(&format!("openapi[{}]", i), rule.route.as_deref())?;
+        }
+        for (name, rules) in [("request_headers", &self.request_headers), ("response_headers", &self.response_headers)] {
+            for (i, rule) in rules.iter().enumerate() {
+                self.check_route(&format!("{}[{}]", name, i), rule.route.as_deref())?;
+            }
+        }
+        for (name, rules) in [("request_body", &self.request_body), ("response_body", &self.response_body)] {
+            for (i, rule) in rules.iter().enumerate() {
+                let section = format!("{}[{}]", name, i);
+                self.check_route(&section, rule.route.as_deref())?;
+                rule.validate().map_err(|e| format!("{}: {}", section, e))?;
+            }
+        }
+        for (i, rule) in self.set_cookie.iter().enumerate() {
+            self.check_route(&format!("set_cookie[{}]", i), rule.route.as_deref())?;
+        }
+        for (i, rule) in self.credentials.iter().enumerate() {
+            let section = format!("credentials[{}]", i);
+            self.check_route(&section, rule.route.as_deref())?;
+            for source in rule.sources() {
+                source.resolve(&self.secrets).map_err(|e| format!("{}: {}", section, e))?;
+            }
+        }
+        for (i, rule) in self.aws_sigv4.iter().enumerate() {
+            let section = format!("aws_sigv4[{}]", i);
+            self.check_route(&section, rule.route.as_deref())?;
+            AwsCredentials::load(rule.profile.as_deref()).map_err(|e| format!("{}: {}", section, e))?;
+        }
+        for (i, rule) in self.oauth2.iter().enumerate() {
+            let section = format!("oauth2[{}]", i);
+            self.check_route(&section, rule.route.as_deref())?;
+            rule.validate(&self.secrets).map_err(|e| format!("{}: {}", section, e))?;
+        }
+        Ok(())
+    }
+
+    /// Check that a rule's `route` names a configured route
+    pub fn check_route(&self, section: &str, route: Option<&str>) -> Result<(), String> {
+        match route {
+            Some(route) if !self.routes.contains_key(route) => {
+                Err(format!("{}: unknown route '{}'", section, route))
+            }
+            _ => Ok(()),
+        }
+    }
+
     /// Get all allowed origins as a HashSet for efficient lookup
     pub fn allowed_origins(&self) -> HashSet<String> {
         let mut origins: HashSet<String> = DEFAULT_ORIGINS.iter().map(|s| s.to_string()).collect();
@@ -53,9 +409,118 @@ impl Config {
         self.allowed_origins().contains(origin)
     }
 
-    /// Get the socket address to bind to
-    pub fn socket_addr(&self) -> String {
-        format!("{}:{}", self.bind, self.port)
+    /// Get the addresses to listen on, from --listen or --bind/--port
+    pub fn listen_addrs(&self) -> Result<Vec<ListenAddr>, String> {
+        if !self.listen.is_empty() {
+            return self.listen.iter().map(|s| ListenAddr::parse(s)).collect();
+        }
+
+        // Accept the IPv6 form with or without brackets
+        let bind = self.bind.trim_start_matches('[').trim_end_matches(']');
+        let ip: IpAddr = bind
+            .parse()
+            .map_err(|_| format!("--bind expects an IP address, got '{}'", self.bind))?;
+        Ok(vec![ListenAddr::Tcp(SocketAddr::new(ip, self.port))])
+    }
+
+    /// Check if a Unix socket target may be proxied to: only through a route
+    /// alias or when the socket is on the allowlist
+    pub fn allows_unix_socket(&self, target: &UnixTarget, route: Option<&str>) -> bool {
+        route.is_some_and(|route| self.routes.get(route).is_some_and(|t| t.starts_with(UNIX_PREFIX)))
+            || self.allow_unix_sockets.contains(&target.socket)
+    }
+
+    /// Check if TLS verification is disabled for a host
+    pub fn is_insecure_host(&self, host: &str) -> bool {
+        self.insecure_hosts.iter().any(|h| h.eq_ignore_ascii_case(host))
+    }
+
+    /// Get the mutual TLS identities keyed by (lowercase) host
+    pub fn client_identities(&self) -> Result<HashMap<String, ClientIdentity>, String> {
+        let mut certs = HashMap::new();
+        for entry in &self.client_certs {
+            let (host, path) = split_host_value(entry, "--client-cert")?;
+            certs.insert(host, PathBuf::from(path));
+        }
+
+        let mut keys = HashMap::new();
+        for entry in &self.client_keys {
+            let (host, path) = split_host_value(entry, "--client-key")?;
+            if !certs.contains_key(&host) {
+                return Err(format!("--client-key given for '{}' without a --client-cert", host));
+            }
+            keys.insert(host, PathBuf::from(path));
+        }
+
+        Ok(certs
+            .into_iter()
+            .map(|(host, cert)| {
+                let key = keys.remove(&host).unwrap_or_else(|| cert.clone());
+                (host, ClientIdentity { cert, key })
+            })
+            .collect())
+    }
+
+    /// Get the route name and target for a route alias path (`/NAME/rest`), if one matches
+    pub fn route_target(&self, path: &str, query: Option<&str>) -> Option<(String, String)> {
+        let path = path.strip_prefix('/').unwrap_or(path);
+        let (name, rest) = path.split_once('/').unwrap_or((path, ""));
+        let target = self.routes.get(name)?;
+
+        let mut url = if target.starts_with(UNIX_PREFIX) && target[UNIX_PREFIX.len()..].contains(':') {
+            // unix:SOCKET:/base - append to the base path
+            format!("{}/{}", target.trim_end_matches('/'), rest)
+        } else if target.starts_with(UNIX_PREFIX) {
+            format!("{}:/{}", target, rest)
+        } else {
+            format!("{}/{}", target.trim_end_matches('/'), rest)
+        };
+
+        if let Some(query) = query {
+            url.push('?');
+            url.push_str(query);
+        }
+        Some((name.to_string(), url))
+    }
+
+    /// How the proxy exposes a request's target
+    pub fn proxy_prefix(&self, ctx: &RuleContext) -> ProxyPrefix {
+        let target_path = |target: &str| match UnixTarget::parse(target) {
+            Some(unix) => unix.path,
+            None => url::Url::parse(target).map(|u| u.path().to_string()).unwrap_or_default(),
+        };
+
+        match ctx.route.as_ref().and_then(|route| Some((route, self.routes.get(route)?))) {
+            Some((route, target)) => {
+                let base = target_path(target).split('?').next().unwrap_or_default().trim_end_matches('/').to_string();
+                ProxyPrefix {
+                    prefix: format!("/{}", route),
+                    base,
+                }
+            }
+            None => ProxyPrefix {
+                prefix: match UnixTarget::parse(&ctx.target_url) {
+                    Some(unix) => format!("/{}{}:", UNIX_PREFIX, unix.socket.display()),
+                    None => format!("/{}", ctx.target_origin()),
+                },
+                base: String::new(),
+            },
+        }
+    }
+
+    /// Get the parsed --resolve DNS overrides
+    pub fn resolve_overrides(&self) -> Result<Vec<ResolveOverride>, String> {
+        self.resolve.iter().map(|s| ResolveOverride::parse(s)).collect()
+    }
+}
+
+/// Split a `HOST=VALUE` argument, lowercasing the host
+fn split_host_value(entry: &str, flag: &str) -> Result<(String, String), String> {
+    match entry.split_once('=') {
+        Some((host, value)) if !host.is_empty() && !value.is_empty() => {
+            Ok((host.to_ascii_lowercase(), value.to_string()))
+        }
+        _ => Err(format!("{} expects HOST=PATH, got '{}'", flag, entry)),
     }
 }
 
@@ -63,15 +528,13 @@ impl Config {
 mod tests {
     use super::*;
 
+    fn test_config() -> Config {
+        Config::parse_from(["holy-cors"])
+    }
+
     #[test]
     fn test_default_origins() {
-        let config = Config {
-            port: 2345,
-            allow_origins: vec![],
-            allow_all: false,
-            verbose: false,
-            bind: "0.0.0.0".to_string(),
-        };
+        let config = test_config();
 
         assert!(config.is_origin_allowed("https://bugdays.com"));
         assert!(config.is_origin_allowed("https://www.bugdays.com"));
@@ -81,11 +544,8 @@ mod tests {
     #[test]
     fn test_custom_origin() {
         let config = Config {
-            port: 2345,
             allow_origins: vec!["http://localhost:3000".to_string()],
-            allow_all: false,
-            verbose: false,
-            bind: "0.0.0.0".to_string(),
+            ..test_config()
         };
 
         assert!(config.is_origin_allowed("http://localhost:3000"));
@@ -95,14 +555,135 @@ mod tests {
     #[test]
     fn test_allow_all() {
         let config = Config {
-            port: 2345,
-            allow_origins: vec![],
             allow_all: true,
-            verbose: false,
-            bind: "0.0.0.0".to_string(),
+            ..test_config()
         };
 
         assert!(config.is_origin_allowed("https://anything.com"));
         assert!(config.is_origin_allowed("http://localhost:9999"));
     }
+
+    #[test]
+    fn test_listen_addrs() {
+        let config = Config {
+            bind: "::1".to_string(),
+            ..test_config()
+        };
+        assert_eq!(
+            config.listen_addrs().unwrap(),
+            vec![ListenAddr::Tcp("[::1]:2345".parse().unwrap())]
+        );
+
+        let config = Config {
+            listen: vec![
+                "127.0.0.1:8080".to_string(),
+                "[::]:8080".to_string(),
+                "unix:/tmp/holy-cors.sock".to_string(),
+            ],
+            ..test_config()
+        };
+        assert_eq!(
+            config.listen_addrs().unwrap(),
+            vec![
+                ListenAddr::Tcp("127.0.0.1:8080".parse().unwrap()),
+                ListenAddr::Tcp("[::]:8080".parse().unwrap()),
+                ListenAddr::Unix(PathBuf::from("/tmp/holy-cors.sock")),
+            ]
+        );
+
+        let config = Config {
+            listen: vec!["::1:2345".to_string()],
+            ..test_config()
+        };
+        assert!(config.listen_addrs().is_err());
+    }
+
+    #[test]
+    fn test_client_identities() {
+        let config = Config {
+            client_certs: vec![
+                "API.internal=/certs/api.pem".to_string(),
+                "db.internal=/certs/db.pem".to_string(),
+            ],
+            client_keys: vec!["api.internal=/certs/api.key".to_string()],
+            ..test_config()
+        };
+
+        let identities = config.client_identities().unwrap();
+        assert_eq!(identities["api.internal"].key, PathBuf::from("/certs/api.key"));
+        assert_eq!(identities["db.internal"].key, PathBuf::from("/certs/db.pem"));
+    }
+
+    #[test]
+    fn test_route_target() {
+        let mut config = test_config();
+        config.routes.insert("github".to_string(), "https://api.github.com/".to_string());
+        config.routes.insert("docker".to_string(), "unix:/var/run/docker.sock".to_string());
+
+        assert_eq!(
+            config.route_target("/github/users/octocat", Some("page=2")),
+            Some((
+                "github".to_string(),
+                "https://api.github.com/users/octocat?page=2".to_string()
+            ))
+        );
+        assert_eq!(
+            config.route_target("/docker/v1.43/info", None).map(|(_, url)| url),
+            Some("unix:/var/run/docker.sock:/v1.43/info".to_string())
+        );
+        assert_eq!(config.route_target("/https://example.com", None), None);
+    }
+
+    #[test]
+    fn test_allows_unix_socket() {
+        let mut config = test_config();
+        config.routes.insert("docker".to_string(), "unix:/var/run/docker.sock".to_string());
+        config.routes.insert("github".to_string(), "https://api.github.com".to_string());
+        let docker = UnixTarget::parse("unix:/var/run/docker.sock:/info").unwrap();
+        let app = UnixTarget::parse("unix:/tmp/app.sock:/health").unwrap();
+
+        assert!(config.allows_unix_socket(&docker, Some("docker")));
+        assert!(!config.allows_unix_socket(&docker, None));
+        assert!(!config.allows_unix_socket(&docker, Some("github")));
+        assert!(!config.allows_unix_socket(&app, None));
+
+        config.allow_unix_sockets.push(PathBuf::from("/tmp/app.sock"));
+        assert!(config.allows_unix_socket(&app, None));
+        assert!(!config.allows_unix_socket(&docker, None));
+    }
+
+    #[test]
+    fn test_proxy_prefix() {
+        let mut config = test_config();
+        config.routes.insert("api".to_string(), "https://example.com/v2/".to_string());
+
+        let routed = RuleContext {
+            route: Some("api".to_string()),
+            target_url: "https://example.com/v2/users".to_string(),
+            ..RuleContext::default()
+        };
+        let prefix = config.proxy_prefix(&routed);
+        assert_eq!(prefix.map("/v2/users").as_deref(), Some("/api/users"));
+        assert_eq!(prefix.map("/v2").as_deref(), Some("/api"));
+        assert_eq!(prefix.map("/v20"), None);
+
+        let direct = RuleContext {
+            target_url: "http://localhost:8080/a/b".to_string(),
+            ..RuleContext::default()
+        };
+        assert_eq!(
+            config.proxy_prefix(&direct).map("/a").as_deref(),
+            Some("/http://localhost:8080/a")
+        );
+    }
+
+    #[test]
+    fn test_client_key_without_cert() {
+        let config = Config {
+            client_keys: vec!["api.internal=/certs/api.key".to_string()],
+            ..test_config()
+        };
+
+        assert!(config.client_identities().is_err());
+    }
 }
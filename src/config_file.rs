@@ -0,0 +1,490 @@
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use toml::de::{DeTable, DeValue};
+
+use crate::config::{Config, ListenAddr};
+use crate::cookies::{CookieJarMode, SetCookieRule};
+use crate::credentials::CredentialRule;
+use crate::fault::FaultRule;
+use crate::intercept::Breakpoint;
+use crate::mock::MockRule;
+use crate::oauth::OAuth2Rule;
+use crate::openapi::OpenApiRule;
+use crate::redirect::RedirectPolicy;
+use crate::replay::ReplayMode;
+use crate::resolve::ResolveOverride;
+use crate::rules::HeaderRule;
+use crate::sigv4::SigV4Rule;
+use crate::transform::BodyRule;
+use crate::unix::{UnixTarget, UNIX_PREFIX};
+
+/// Name of the configuration file looked up in the working directory
+pub const CONFIG_FILE_NAME: &str = "holy-cors.toml";
+
+/// Contents of `holy-cors.toml`
+///
+/// Every option is optional; anything set here is overridden by the
+/// environment and the command line.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bind: Option<String>,
+    #[serde(default, deserialize_with = "listen_addrs", skip_serializing_if = "Option::is_none")]
+    pub listen: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allow_origins: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allow_all: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verbose: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shutdown_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_file: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bundled_roots: Option<bool>,
+    #[serde(default, deserialize_with = "resolve_overrides", skip_serializing_if = "Option::is_none")]
+    pub resolve: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cookie_jar: Option<CookieJarMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirects: Option<RedirectPolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_redirects: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rewrite_urls: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub record: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub record_max_body: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replay: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replay_mode: Option<ReplayMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replay_match: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allow_unix_sockets: Option<Vec<PathBuf>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fault_seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub breakpoint_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secrets_file: Option<PathBuf>,
+
+    /// Named shortcuts: `/NAME/rest` proxies to `TARGET/rest`
+    #[serde(default, deserialize_with = "route_targets", skip_serializing_if = "BTreeMap::is_empty")]
+    pub routes: BTreeMap<String, String>,
+
+    /// Per-host upstream settings, keyed by host name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hosts: BTreeMap<String, HostConfig>,
+
+    /// Canned responses for matching requests; the first match wins
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mocks: Vec<MockRule>,
+
+    /// OpenAPI documents for matching upstreams
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub openapi: Vec<OpenApiRule>,
+
+    /// Faults injected for matching upstreams; every matching rule applies
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub faults: Vec<FaultRule>,
+
+    /// Requests and responses to pause for editing
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub breakpoints: Vec<Breakpoint>,
+
+    /// Rewrite rules for headers sent upstream, applied in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub request_headers: Vec<HeaderRule>,
+
+    /// Rewrite rules for headers returned to the browser, applied after the CORS headers
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub response_headers: Vec<HeaderRule>,
+
+    /// Transforms for request bodies, applied in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub request_body: Vec<BodyRule>,
+
+    /// Transforms for response bodies, applied in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub response_body: Vec<BodyRule>,
+
+    /// Rewrite rules for upstream Set-Cookie headers
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub set_cookie: Vec<SetCookieRule>,
+
+    /// Credentials injected into requests for matching upstreams
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub credentials: Vec<CredentialRule>,
+
+    /// AWS SigV4 signing for matching upstreams
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aws_sigv4: Vec<SigV4Rule>,
+
+    /// OAuth2 tokens fetched and injected for matching upstreams
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub oauth2: Vec<OAuth2Rule>,
+}
+
+/// Settings for one upstream host
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostConfig {
+    /// Skip TLS certificate verification (development only!)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub insecure_skip_verify: bool,
+    /// Client certificate (PEM) for mutual TLS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_cert: Option<PathBuf>,
+    /// Client private key (PEM), defaults to the certificate file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_key: Option<PathBuf>,
+}
+
+impl FileConfig {
+    /// Parse a configuration file, reporting errors with line numbers.
+    /// The text is returned too, to point later validation errors at lines.
+    pub fn load(path: &Path) -> Result<(Self, String), String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let file = Self::parse(&text).map_err(|e| format!("Invalid config file {}: {}", path.display(), e))?;
+        Ok((file, text))
+    }
+
+    fn parse(text: &str) -> Result<Self, String> {
+        let file: FileConfig = toml::from_str(text).map_err(|e| e.to_string())?;
+
+        for (host, host_config) in &file.hosts {
+            if host_config.client_key.is_some() && host_config.client_cert.is_none() {
+                return Err(format!("hosts.\"{}\": client_key requires client_cert", host));
+            }
+        }
+        Ok(file)
+    }
+
+    /// Layer this file under the command line and environment
+    ///
+    /// Precedence is CLI > environment > file > built-in defaults. Lists
+    /// are replaced, not merged; `[hosts]` entries apply unless the same
+    /// host is configured on the command line or environment.
+    pub fn apply(self, config: &mut Config, matches: &ArgMatches) {
+        let from_file = |id: &str| {
+            !matches!(
+                matches.value_source(id),
+                Some(ValueSource::CommandLine) | Some(ValueSource::EnvVariable)
+            )
+        };
+
+        macro_rules! layer {
+            ($($field:ident),*) => {
+                $(
+                    if let Some(value) = self.$field {
+                        if from_file(stringify!($field)) {
+                            config.$field = value;
+                        }
+                    }
+                )*
+            };
+        }
+        layer!(port, bind, listen, allow_origins, allow_all, verbose, shutdown_timeout, bundled_roots, resolve, cookie_jar, redirects, max_redirects, rewrite_urls, record_max_body, replay_mode, replay_match, breakpoint_timeout, allow_unix_sockets);
+
+        if self.ca_file.is_some() && from_file("ca_file") {
+            config.ca_file = self.ca_file;
+        }
+        if self.record.is_some() && from_file("record") {
+            config.record = self.record;
+        }
+        if self.replay.is_some() && from_file("replay") {
+            config.replay = self.replay;
+        }
+        if self.fault_seed.is_some() && from_file("fault_seed") {
+            config.fault_seed = self.fault_seed;
+        }
+        if self.secrets_file.is_some() && from_file("secrets_file") {
+            config.secrets_file = self.secrets_file;
+        }
+
+        for (host, host_config) in self.hosts {
+            let host = host.to_ascii_lowercase();
+            if host_config.insecure_skip_verify && !config.is_insecure_host(&host) {
+                config.insecure_hosts.push(host.clone());
+            }
+            let has_cert = config
+                .client_certs
+                .iter()
+                .any(|entry| entry.split_once('=').is_some_and(|(h, _)| h.eq_ignore_ascii_case(&host)));
+            if let (Some(cert), false) = (host_config.client_cert, has_cert) {
+                config.client_certs.push(format!("{}={}", host, cert.display()));
+                if let Some(key) = host_config.client_key {
+                    config.client_keys.push(format!("{}={}", host, key.display()));
+                }
+            }
+        }
+
+        config.routes = self.routes;
+        config.mocks = self.mocks;
+        config.openapi = self.openapi;
+        config.faults = self.faults;
+        config.breakpoints = self.breakpoints;
+        config.request_headers = self.request_headers;
+        config.response_headers = self.response_headers;
+        config.request_body = self.request_body;
+        config.response_body = self.response_body;
+        config.set_cookie = self.set_cookie;
+        config.credentials = self.credentials;
+        config.aws_sigv4 = self.aws_sigv4;
+        config.oauth2 = self.oauth2;
+    }
+
+    /// Describe the effective configuration in file form
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        let mut hosts: BTreeMap<String, HostConfig> = BTreeMap::new();
+        for host in &config.insecure_hosts {
+            hosts.entry(host.to_ascii_lowercase()).or_default().insecure_skip_verify = true;
+        }
+        for (host, identity) in config.client_identities()? {
+            let entry = hosts.entry(host).or_default();
+            entry.client_cert = Some(identity.cert);
+            entry.client_key = Some(identity.key);
+        }
+
+        Ok(Self {
+            port: Some(config.port),
+            bind: Some(config.bind.clone()),
+            listen: Some(config.listen.clone()),
+            allow_origins: Some(config.allow_origins.clone()),
+            allow_all: Some(config.allow_all),
+            verbose: Some(config.verbose),
+            shutdown_timeout: Some(config.shutdown_timeout),
+            ca_file: config.ca_file.clone(),
+            bundled_roots: Some(config.bundled_roots),
+            resolve: Some(config.resolve.clone()),
+            cookie_jar: Some(config.cookie_jar),
+            redirects: Some(config.redirects),
+            max_redirects: Some(config.max_redirects),
+            rewrite_urls: Some(config.rewrite_urls),
+            record: config.record.clone(),
+            record_max_body: Some(config.record_max_body),
+            replay: config.replay.clone(),
+            replay_mode: Some(config.replay_mode),
+            replay_match: Some(config.replay_match.clone()),
+            allow_unix_sockets: Some(config.allow_unix_sockets.clone()),
+            fault_seed: config.fault_seed,
+            breakpoint_timeout: Some(config.breakpoint_timeout),
+            secrets_file: config.secrets_file.clone(),
+            routes: config.routes.clone(),
+            hosts,
+            mocks: config.mocks.clone(),
+            openapi: config.openapi.clone(),
+            faults: config.faults.clone(),
+            breakpoints: config.breakpoints.clone(),
+            request_headers: config.request_headers.clone(),
+            response_headers: config.response_headers.clone(),
+            request_body: config.request_body.clone(),
+            response_body: config.response_body.clone(),
+            set_cookie: config.set_cookie.clone(),
+            credentials: config.credentials.clone(),
+            aws_sigv4: config.aws_sigv4.clone(),
+            oauth2: config.oauth2.clone(),
+        })
+    }
+
+    /// Render as TOML
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).unwrap_or_default()
+    }
+}
+
+/// Find the configuration file: --config, then the working directory, then $XDG_CONFIG_HOME
+pub fn discover(explicit: Option<&Path>) -> Option<PathBuf> {
+    if let Some(path) = explicit {
+        return Some(path.to_path_buf());
+    }
+
+    let local = PathBuf::from(CONFIG_FILE_NAME);
+    if local.is_file() {
+        return Some(local);
+    }
+
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    let global = config_home.join("holy-cors").join(CONFIG_FILE_NAME);
+    global.is_file().then_some(global)
+}
+
+/// Deserialize an optional list of strings, checking each with `check`
+fn checked_list<'de, D>(deserializer: D, check: fn(&str) -> Result<(), String>) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let values = Vec::<String>::deserialize(deserializer)?;
+    for value in &values {
+        check(value).map_err(serde::de::Error::custom)?;
+    }
+    Ok(Some(values))
+}
+
+fn listen_addrs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<String>>, D::Error> {
+    checked_list(deserializer, |s| ListenAddr::parse(s).map(|_| ()))
+}
+
+fn resolve_overrides<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<String>>, D::Error> {
+    checked_list(deserializer, |s| ResolveOverride::parse(s).map(|_| ()))
+}
+
+fn route_targets<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<String, String>, D::Error> {
+    let routes = BTreeMap::<String, RouteTarget>::deserialize(deserializer)?;
+    for name in routes.keys() {
+        if name.is_empty() || name.contains('/') || name.contains('.') {
+            return Err(serde::de::Error::custom(format!(
+                "route name '{}' must be a single path segment without dots",
+                name
+            )));
+        }
+    }
+    Ok(routes.into_iter().map(|(name, target)| (name, target.0)).collect())
+}
+
+/// The line of the entry an error names, like `mocks[1]: ...` or `routes: ...`
+pub fn locate(text: &str, error: &str) -> Option<usize> {
+    let (section, _) = error.split_once(": ")?;
+    let (name, index) = match section.strip_suffix(']').and_then(|s| s.split_once('[')) {
+        Some((name, index)) => (name, Some(index.parse::<usize>().ok()?)),
+        None => (section, None),
+    };
+    let document = DeTable::parse(text).ok()?;
+    let value = document.get_ref().get(name)?;
+    let span = match (value.get_ref(), index) {
+        (DeValue::Array(entries), Some(index)) => entries.get(index)?.span(),
+        _ => value.span(),
+    };
+    Some(text[..span.start].matches('\n').count() + 1)
+}
+
+/// A route target, validated where it appears so errors point at its line
+struct RouteTarget(String);
+
+impl<'de> Deserialize<'de> for RouteTarget {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let target = String::deserialize(deserializer)?;
+        let valid = if target.starts_with(UNIX_PREFIX) {
+            UnixTarget::parse(&target).is_some()
+        } else {
+            url::Url::parse(&target).is_ok_and(|u| matches!(u.scheme(), "http" | "https"))
+        };
+
+        if !valid {
+            return Err(serde::de::Error::custom(format!(
+                "route must target an http(s) URL or unix:PATH, got '{}'",
+                target
+            )));
+        }
+        Ok(RouteTarget(target))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    fn layered(file: &str, args: &[&str]) -> Config {
+        let matches = Config::command().get_matches_from(args);
+        let mut config = Config::from_arg_matches(&matches).unwrap();
+        FileConfig::parse(file).unwrap().apply(&mut config, &matches);
+        config
+    }
+
+    #[test]
+    fn test_file_overrides_defaults() {
+        let config = layered(
+            r#"
+            port = 9000
+            allow_origins = ["http://localhost:3000"]
+
+            [routes]
+            github = "https://api.github.com"
+
+            [hosts."staging.internal"]
+            insecure_skip_verify = true
+            "#,
+            &["holy-cors"],
+        );
+
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.allow_origins, vec!["http://localhost:3000"]);
+        assert_eq!(config.routes["github"], "https://api.github.com");
+        assert!(config.is_insecure_host("staging.internal"));
+    }
+
+    #[test]
+    fn test_cli_overrides_file() {
+        let config = layered("port = 9000\nverbose = true", &["holy-cors", "--port", "8000"]);
+
+        assert_eq!(config.port, 8000);
+        assert!(config.verbose);
+    }
+
+    #[test]
+    fn test_errors_have_line_numbers() {
+        let err = FileConfig::parse("port = 2345\nlisten = [\"::1:2345\"]\n").unwrap_err();
+        assert!(err.contains("line 2"), "{}", err);
+
+        let err = FileConfig::parse("port = 2345\n\n[routes]\nbad = \"ftp://example.com\"\n").unwrap_err();
+        assert!(err.contains("line 4"), "{}", err);
+
+        let err = FileConfig::parse("prot = 2345\n").unwrap_err();
+        assert!(err.contains("line 1"), "{}", err);
+
+        let text = "[[mocks]]\nurl = \"https://a.example.com/\"\n\n[[mocks]]\nroute = \"nope\"\n";
+        assert_eq!(locate(text, "mocks[1]: unknown route 'nope'"), Some(4));
+        assert_eq!(locate("faults = [{ status = 503 }, { rate = 2.0 }]\n", "faults[1]: rate must be 0-1"), Some(1));
+        assert_eq!(locate(text, "breakpoints need --admin-token"), None);
+    }
+
+    #[test]
+    fn test_header_rules() {
+        let config = layered(
+            r#"
+            [[request_headers]]
+            remove = ["Origin"]
+
+            [[request_headers]]
+            host = "api.example.com"
+            set = { "User-Agent" = "holy-cors" }
+            "#,
+            &["holy-cors"],
+        );
+        assert_eq!(config.request_headers.len(), 2);
+        assert_eq!(config.request_headers[1].host.as_deref(), Some("api.example.com"));
+
+        let err = FileConfig::parse("[[request_headers]]
+set = { \"X-Id\" = \"{nope}\" }
+").unwrap_err();
+        assert!(err.contains("line 2"), "{}", err);
+    }
+
+    #[test]
+    fn test_print_round_trips() {
+        let config = layered("[hosts.\"api.internal\"]\nclient_cert = \"/certs/api.pem\"\n", &["holy-cors"]);
+        let printed = FileConfig::from_config(&config).unwrap().to_toml();
+        let reparsed = FileConfig::parse(&printed).unwrap();
+
+        assert_eq!(reparsed.port, Some(2345));
+        assert_eq!(
+            reparsed.hosts["api.internal"].client_key,
+            Some(PathBuf::from("/certs/api.pem"))
+        );
+    }
+}
@@ -0,0 +1,531 @@
+use bytes::Bytes;
+use clap::ValueEnum;
+use http::{header, HeaderMap, HeaderValue, Method, Response, StatusCode};
+use http_body_util::Full;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::Url;
+
+use crate::config::ProxyPrefix;
+use crate::cors::error_response;
+use crate::rules::{rule_matches, RuleContext};
+use crate::unix::UnixTarget;
+
+/// Header naming the cookie jar to use in `session` mode
+pub const SESSION_HEADER: &str = "x-holy-cors-session";
+
+/// Registry-controlled suffixes under which anyone can register a name; cookies
+/// may not be scoped to them (a subset of the Public Suffix List, besides bare TLDs)
+const PUBLIC_SUFFIXES: &[&str] = &[
+    "co.uk", "org.uk", "ac.uk", "gov.uk", "me.uk", "ltd.uk", "plc.uk", "com.au", "net.au", "org.au", "edu.au",
+    "gov.au", "co.nz", "org.nz", "co.jp", "ne.jp", "or.jp", "ac.jp", "co.kr", "or.kr", "com.br", "net.br", "org.br",
+    "com.cn", "net.cn", "org.cn", "com.hk", "com.tw", "com.sg", "com.my", "com.mx", "com.ar", "com.tr", "co.in",
+    "net.in", "org.in", "co.za", "co.il", "com.ua", "com.pl", "co.id", "com.ph", "com.vn", "com.eg", "com.sa",
+    "github.io", "gitlab.io", "herokuapp.com", "appspot.com", "web.app", "firebaseapp.com", "vercel.app",
+    "netlify.app", "pages.dev", "workers.dev", "azurewebsites.net", "cloudfront.net", "s3.amazonaws.com",
+    "blogspot.com", "ngrok.io", "ngrok-free.app",
+];
+
+/// How upstream cookies are kept for the browser
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CookieJarMode {
+    /// Pass cookies through untouched
+    #[default]
+    Off,
+    /// One jar per browser origin
+    Origin,
+    /// One jar per X-Holy-Cors-Session header value (per origin without it)
+    Session,
+}
+
+impl CookieJarMode {
+    /// Name of the jar a request uses, if the jar is enabled. Requests without
+    /// an Origin (or session header) get no jar rather than a shared one.
+    pub fn jar_key(self, headers: &HeaderMap, origin: &str) -> Option<String> {
+        let by_origin = (!origin.is_empty()).then(|| origin.to_string());
+        match self {
+            CookieJarMode::Off => None,
+            CookieJarMode::Origin => by_origin,
+            CookieJarMode::Session => headers
+                .get(SESSION_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .filter(|session| !session.is_empty())
+                .map(|session| format!("session:{}", session))
+                .or(by_origin),
+        }
+    }
+}
+
+/// A stored cookie (RFC 6265 section 5.3)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Cookie {
+    pub name: String,
+    /// Never shown by the jar endpoint
+    #[serde(skip_serializing)]
+    pub value: String,
+    pub domain: String,
+    /// Only sent to exactly `domain`, not its subdomains
+    pub host_only: bool,
+    pub path: String,
+    pub secure: bool,
+    pub http_only: bool,
+    /// Unix timestamp; session cookies have none
+    pub expires: Option<u64>,
+}
+
+impl Cookie {
+    /// Parse a `Set-Cookie` value received from `url`; invalid cookies are ignored
+    pub fn parse(set_cookie: &str, url: &Url) -> Option<Self> {
+        let host = url.host_str()?.to_ascii_lowercase();
+        let mut parts = set_cookie.split(';');
+        let (name, value) = parts.next()?.split_once('=')?;
+        let name = name.trim();
+        if name.is_empty() {
+            return None;
+        }
+
+        let mut cookie = Cookie {
+            name: name.to_string(),
+            value: value.trim().to_string(),
+            domain: host.clone(),
+            host_only: true,
+            path: default_path(url.path()),
+            secure: false,
+            http_only: false,
+            expires: None,
+        };
+
+        let mut max_age = None;
+        for attribute in parts {
+            let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            let value = value.trim();
+            match key.trim().to_ascii_lowercase().as_str() {
+                "domain" if !value.is_empty() => {
+                    let domain = value.trim_start_matches('.').to_ascii_lowercase();
+                    if !domain_matches(&host, &domain) {
+                        return None;
+                    }
+                    // A public suffix is only accepted as the host itself, and then stays host-only
+                    if is_public_suffix(&domain) {
+                        if domain != host {
+                            return None;
+                        }
+                        continue;
+                    }
+                    cookie.domain = domain;
+                    cookie.host_only = false;
+                }
+                "path" if value.starts_with('/') => cookie.path = value.to_string(),
+                "secure" => cookie.secure = true,
+                "httponly" => cookie.http_only = true,
+                "max-age" => max_age = value.parse::<i64>().ok(),
+                "expires" => {
+                    if let Some(time) = parse_expires(value) {
+                        cookie.expires = Some(unix_time(time));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // Max-Age wins over Expires
+        if let Some(seconds) = max_age {
+            let now = unix_time(SystemTime::now());
+            cookie.expires = Some(if seconds <= 0 { 0 } else { now + seconds as u64 });
+        }
+        Some(cookie)
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|at| at <= now)
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+        let host = host.to_ascii_lowercase();
+        let host_ok = if self.host_only {
+            host == self.domain
+        } else {
+            domain_matches(&host, &self.domain)
+        };
+        host_ok && path_matches(url.path(), &self.path) && (!self.secure || url.scheme() == "https")
+    }
+}
+
+/// Cookies captured from upstream responses, one jar per key
+#[derive(Default)]
+pub struct CookieJar {
+    jars: Mutex<HashMap<String, Vec<Cookie>>>,
+}
+
+impl CookieJar {
+    /// Store the `Set-Cookie` headers of a response from `url`
+    pub fn store(&self, key: &str, url: &Url, headers: &HeaderMap) {
+        let now = unix_time(SystemTime::now());
+        let mut jars = self.jars.lock().unwrap_or_else(|e| e.into_inner());
+        let jar = jars.entry(key.to_string()).or_default();
+
+        for value in headers.get_all(header::SET_COOKIE) {
+            let Some(cookie) = value.to_str().ok().and_then(|v| Cookie::parse(v, url)) else {
+                continue;
+            };
+            jar.retain(|c| !(c.name == cookie.name && c.domain == cookie.domain && c.path == cookie.path));
+            if !cookie.is_expired(now) {
+                jar.push(cookie);
+            }
+        }
+    }
+
+    /// The `Cookie` header value for a request to `url`, if any cookies apply
+    pub fn cookie_header(&self, key: &str, url: &Url) -> Option<HeaderValue> {
+        let now = unix_time(SystemTime::now());
+        let mut jars = self.jars.lock().unwrap_or_else(|e| e.into_inner());
+        let jar = jars.get_mut(key)?;
+        jar.retain(|c| !c.is_expired(now));
+
+        // Longer paths first, as browsers do
+        let mut matching: Vec<&Cookie> = jar.iter().filter(|c| c.matches(url)).collect();
+        matching.sort_by_key(|c| std::cmp::Reverse(c.path.len()));
+        if matching.is_empty() {
+            return None;
+        }
+
+        let value = matching
+            .iter()
+            .map(|c| format!("{}={}", c.name, c.value))
+            .collect::<Vec<_>>()
+            .join("; ");
+        HeaderValue::from_str(&value).ok()
+    }
+
+    /// Unexpired cookies in a jar
+    pub fn list(&self, key: &str) -> Vec<Cookie> {
+        let now = unix_time(SystemTime::now());
+        let jars = self.jars.lock().unwrap_or_else(|e| e.into_inner());
+        jars.get(key)
+            .map(|jar| jar.iter().filter(|c| !c.is_expired(now)).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Empty a jar, returning how many cookies it held
+    pub fn clear(&self, key: &str) -> usize {
+        self.jars.lock().unwrap_or_else(|e| e.into_inner()).remove(key).map_or(0, |jar| jar.len())
+    }
+
+    /// Empty every jar, returning how many cookies they held
+    pub fn clear_all(&self) -> usize {
+        self.jars.lock().unwrap_or_else(|e| e.into_inner()).drain().map(|(_, jar)| jar.len()).sum()
+    }
+}
+
+/// What to do with a cookie's `Domain` attribute
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DomainRewrite {
+    /// Remove it, making the cookie host-only for the proxy
+    Drop,
+    /// Set it to the proxy's host
+    Proxy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// Rewrite `Set-Cookie` headers so the browser accepts them for the proxy
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SetCookieRule {
+    /// Target host, exact or `*.example.com`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Route alias name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain: Option<DomainRewrite>,
+    /// Prefix `Path` with the proxy path of the target
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub prefix_path: bool,
+    /// Set `SameSite`; `None` also sets `Secure` unless `secure = false`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub same_site: Option<SameSite>,
+    /// Add or remove `Secure`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secure: Option<bool>,
+}
+
+impl SetCookieRule {
+    pub fn matches(&self, ctx: &RuleContext) -> bool {
+        rule_matches(self.host.as_deref(), self.route.as_deref(), ctx)
+    }
+
+    /// Rewrite one `Set-Cookie` value for a response to `upstream_path`
+    fn rewrite(&self, set_cookie: &str, proxy_host: Option<&str>, prefix: &ProxyPrefix, upstream_path: &str) -> String {
+        let mut parts = set_cookie.split(';');
+        let mut out = vec![parts.next().unwrap_or_default().trim().to_string()];
+        let mut path = None;
+        let mut secure = false;
+
+        for attribute in parts {
+            let attribute = attribute.trim();
+            let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            let key = key.trim().to_ascii_lowercase();
+            match key.as_str() {
+                "domain" if self.domain.is_some() => {}
+                "path" if self.prefix_path => path = Some(value.trim().to_string()),
+                "samesite" if self.same_site.is_some() => {}
+                "secure" => secure = true,
+                _ if attribute.is_empty() => {}
+                _ => out.push(attribute.to_string()),
+            }
+        }
+
+        if let (Some(DomainRewrite::Proxy), Some(host)) = (self.domain, proxy_host) {
+            out.push(format!("Domain={}", host));
+        }
+        if self.prefix_path {
+            let path = path
+                .filter(|p| p.starts_with('/'))
+                .unwrap_or_else(|| default_path(upstream_path));
+            // Paths outside a route's base can only be reached through the prefix itself
+            let path = prefix.map(&path).unwrap_or_else(|| prefix.prefix.clone());
+            out.push(format!("Path={}", path.trim_end_matches('/')));
+        }
+        if let Some(same_site) = self.same_site {
+            out.push(format!("SameSite={:?}", same_site));
+        }
+        let secure = self.secure.unwrap_or(secure || self.same_site == Some(SameSite::None));
+        if secure {
+            out.push("Secure".to_string());
+        }
+        out.join("; ")
+    }
+}
+
+/// Apply matching `[[set_cookie]]` rules to the `Set-Cookie` headers of a response.
+/// `proxy_host` is the host the browser reached the proxy on.
+pub fn rewrite_set_cookies(
+    rules: &[SetCookieRule],
+    headers: &mut HeaderMap,
+    ctx: &RuleContext,
+    proxy_host: Option<&str>,
+    prefix: &ProxyPrefix,
+) {
+    let matching: Vec<&SetCookieRule> = rules.iter().filter(|rule| rule.matches(ctx)).collect();
+    if matching.is_empty() || !headers.contains_key(header::SET_COOKIE) {
+        return;
+    }
+
+    let upstream_path = match UnixTarget::parse(&ctx.target_url) {
+        Some(unix) => unix.path,
+        None => Url::parse(&ctx.target_url).map(|u| u.path().to_string()).unwrap_or_default(),
+    };
+    let upstream_path = upstream_path.split('?').next().unwrap_or_default();
+
+    let rewritten: Vec<HeaderValue> = headers
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .filter_map(|value| {
+            let mut cookie = value.to_str().ok()?.to_string();
+            for rule in &matching {
+                cookie = rule.rewrite(&cookie, proxy_host, prefix, upstream_path);
+            }
+            HeaderValue::from_str(&cookie).ok()
+        })
+        .collect();
+
+    headers.remove(header::SET_COOKIE);
+    for value in rewritten {
+        headers.append(header::SET_COOKIE, value);
+    }
+}
+
+/// Serve the cookie jar endpoint for the caller's jar; cookie values are not shown
+pub fn handle_endpoint(
+    method: &Method,
+    mode: CookieJarMode,
+    headers: &HeaderMap,
+    origin: &str,
+    jar: &CookieJar,
+) -> Response<Full<Bytes>> {
+    if mode == CookieJarMode::Off {
+        return error_response(StatusCode::NOT_FOUND, "Cookie jar is disabled. Use --cookie-jar to enable it.");
+    }
+    let Some(key) = mode.jar_key(headers, origin) else {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "Requests without an Origin or X-Holy-Cors-Session header have no cookie jar",
+        );
+    };
+
+    let body = match *method {
+        Method::GET => serde_json::json!({ "jar": key, "cookies": jar.list(&key) }),
+        Method::DELETE => serde_json::json!({ "jar": key, "cleared": jar.clear(&key) }),
+        _ => return error_response(StatusCode::METHOD_NOT_ALLOWED, "Use GET to list or DELETE to clear cookies"),
+    };
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .unwrap()
+}
+
+/// Add the jar's cookies to the ones the browser sent
+pub fn merge_cookie_header(headers: &mut HeaderMap, jar_cookies: HeaderValue) {
+    let merged = match headers.get(header::COOKIE).and_then(|v| v.to_str().ok()) {
+        Some(existing) if !existing.is_empty() => {
+            let jar = jar_cookies.to_str().unwrap_or_default();
+            HeaderValue::from_str(&format!("{}; {}", existing, jar)).unwrap_or(jar_cookies)
+        }
+        _ => jar_cookies,
+    };
+    headers.insert(header::COOKIE, merged);
+}
+
+/// Bare TLDs (`com`) and known public suffixes (`co.uk`)
+fn is_public_suffix(domain: &str) -> bool {
+    !domain.contains('.') || PUBLIC_SUFFIXES.contains(&domain)
+}
+
+fn domain_matches(host: &str, domain: &str) -> bool {
+    host == domain || host.strip_suffix(domain).is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+fn path_matches(request_path: &str, cookie_path: &str) -> bool {
+    request_path == cookie_path
+        || request_path.strip_prefix(cookie_path).is_some_and(|rest| {
+            cookie_path.ends_with('/') || rest.starts_with('/')
+        })
+}
+
+/// The directory of the request path (RFC 6265 section 5.1.4)
+fn default_path(path: &str) -> String {
+    match path.rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(i) => path[..i].to_string(),
+    }
+}
+
+fn parse_expires(value: &str) -> Option<SystemTime> {
+    // Cookies often use `Wed, 21-Oct-2015 07:28:00 GMT`
+    httpdate::parse_http_date(value)
+        .or_else(|_| httpdate::parse_http_date(&value.replace('-', " ")))
+        .ok()
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(set_cookies: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in set_cookies {
+            headers.append(header::SET_COOKIE, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn cookies_for(jar: &CookieJar, url: &str) -> Option<String> {
+        jar.cookie_header("app", &Url::parse(url).unwrap())
+            .map(|v| v.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn test_domain_and_path_rules() {
+        let jar = CookieJar::default();
+        let url = Url::parse("https://api.example.com/auth/login").unwrap();
+        jar.store(
+            "app",
+            &url,
+            &response(&[
+                "session=abc; Path=/; Secure; HttpOnly",
+                "shared=1; Domain=.example.com; Path=/",
+                "scoped=2",
+                "evil=3; Domain=other.com",
+            ]),
+        );
+        let url = Url::parse("https://shop.example.co.uk/").unwrap();
+        jar.store("app", &url, &response(&["tld=1; Domain=uk", "suffix=1; Domain=co.uk", "own=1; Domain=example.co.uk"]));
+        assert_eq!(cookies_for(&jar, "https://www.example.co.uk/").as_deref(), Some("own=1"));
+        assert_eq!(cookies_for(&jar, "https://other.co.uk/"), None);
+
+        assert_eq!(
+            cookies_for(&jar, "https://api.example.com/auth/me").as_deref(),
+            Some("scoped=2; session=abc; shared=1")
+        );
+        assert_eq!(cookies_for(&jar, "https://www.example.com/").as_deref(), Some("shared=1"));
+        // Secure cookies aren't sent over http
+        assert_eq!(cookies_for(&jar, "http://api.example.com/").as_deref(), Some("shared=1"));
+        assert_eq!(cookies_for(&jar, "https://other.com/"), None);
+        // Other jars are separate
+        assert!(jar.list("other-app").is_empty());
+    }
+
+    #[test]
+    fn test_jar_key() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(CookieJarMode::Origin.jar_key(&headers, "http://localhost:3000").as_deref(), Some("http://localhost:3000"));
+        assert_eq!(CookieJarMode::Origin.jar_key(&headers, ""), None);
+        assert_eq!(CookieJarMode::Session.jar_key(&headers, ""), None);
+        headers.insert(SESSION_HEADER, HeaderValue::from_static("alice"));
+        assert_eq!(CookieJarMode::Session.jar_key(&headers, "").as_deref(), Some("session:alice"));
+        assert_eq!(CookieJarMode::Off.jar_key(&headers, "http://localhost:3000"), None);
+    }
+
+    #[test]
+    fn test_rewrite_set_cookie() {
+        let rule: SetCookieRule = toml::from_str(
+            r#"domain = "drop"
+prefix_path = true
+same_site = "None""#,
+        )
+        .unwrap();
+        let ctx = RuleContext {
+            route: Some("api".to_string()),
+            target_url: "https://api.example.com/v2/auth/login".to_string(),
+            ..RuleContext::default()
+        };
+        let prefix = ProxyPrefix {
+            prefix: "/api".to_string(),
+            base: "/v2".to_string(),
+        };
+
+        let mut headers = response(&[
+            "sid=abc; Domain=api.example.com; Path=/v2; SameSite=Lax; HttpOnly",
+            "pref=dark",
+        ]);
+        rewrite_set_cookies(&[rule], &mut headers, &ctx, Some("localhost"), &prefix);
+
+        let values: Vec<_> = headers.get_all(header::SET_COOKIE).iter().collect();
+        assert_eq!(values[0], "sid=abc; HttpOnly; Path=/api; SameSite=None; Secure");
+        assert_eq!(values[1], "pref=dark; Path=/api/auth; SameSite=None; Secure");
+    }
+
+    #[test]
+    fn test_replace_and_expire() {
+        let jar = CookieJar::default();
+        let url = Url::parse("https://example.com/").unwrap();
+        jar.store("app", &url, &response(&["a=1", "b=1"]));
+        jar.store("app", &url, &response(&["a=2", "b=; Max-Age=0"]));
+        assert_eq!(cookies_for(&jar, "https://example.com/").as_deref(), Some("a=2"));
+
+        jar.store("app", &url, &response(&["c=1; Expires=Wed, 21-Oct-2015 07:28:00 GMT"]));
+        assert_eq!(jar.list("app").len(), 1);
+        assert_eq!(jar.clear("app"), 1);
+        assert_eq!(cookies_for(&jar, "https://example.com/"), None);
+    }
+}
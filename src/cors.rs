@@ -9,6 +9,7 @@ const CORS_METHODS: &str = "GET, POST, PUT, PATCH, DELETE, HEAD, OPTIONS";
 const CORS_MAX_AGE: &str = "86400";
 
 /// Check if the request origin is allowed
+#[allow(clippy::result_large_err)]
 pub fn check_origin(headers: &HeaderMap, config: &Config) -> Result<String, Response<Full<Bytes>>> {
     // Get the Origin header
     let origin = match headers.get(header::ORIGIN) {
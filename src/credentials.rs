@@ -0,0 +1,278 @@
+use http::header::{self, HeaderMap, HeaderValue};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+use crate::rules::{rule_matches, HeaderKey, RuleContext};
+
+/// Where a secret value comes from
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub enum SecretSource {
+    /// An environment variable
+    Env(String),
+    /// A key in the secrets file (--secrets-file)
+    Secret(String),
+    /// The contents of a file (trailing newline removed)
+    File(PathBuf),
+}
+
+impl fmt::Display for SecretSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretSource::Env(name) => write!(f, "environment variable {}", name),
+            SecretSource::Secret(name) => write!(f, "secret '{}'", name),
+            SecretSource::File(path) => write!(f, "file {}", path.display()),
+        }
+    }
+}
+
+impl SecretSource {
+    /// Look up the secret value
+    pub fn resolve(&self, secrets: &Secrets) -> Result<Secret, String> {
+        let value = match self {
+            SecretSource::Env(name) => std::env::var(name).ok(),
+            SecretSource::Secret(name) => secrets.0.get(name).map(|s| s.0.clone()),
+            SecretSource::File(path) => std::fs::read_to_string(path)
+                .ok()
+                .map(|s| s.trim_end_matches(['\r', '\n']).to_string()),
+        };
+
+        match value {
+            Some(value) if !value.is_empty() => Ok(Secret(value)),
+            _ => Err(format!("{} is not set", self)),
+        }
+    }
+}
+
+/// A secret value; never printed
+#[derive(Clone, Default, PartialEq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        Self(value)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(***)")
+    }
+}
+
+/// Values from the secrets file, a TOML table of `name = "value"`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Secrets(BTreeMap<String, Secret>);
+
+impl Secrets {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read secrets file {}: {}", path.display(), e))?;
+        // Don't echo the file in errors: it would print secret values
+        let table: BTreeMap<String, String> = toml::from_str(&text)
+            .map_err(|e| match e.span() {
+                Some(span) => format!(
+                    "Invalid secrets file {}: error at line {}",
+                    path.display(),
+                    text[..span.start].matches('\n').count() + 1
+                ),
+                None => format!("Invalid secrets file {}", path.display()),
+            })?;
+        Ok(Secrets(table.into_iter().map(|(k, v)| (k, Secret(v))).collect()))
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for Secrets {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        Secrets(iter.into_iter().map(|(k, v)| (k.into(), Secret(v.into()))).collect())
+    }
+}
+
+/// Credentials injected into requests for matching upstreams
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CredentialRule {
+    /// Target host, exact or `*.example.com`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Route alias name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    /// Sent as `Authorization: Bearer <value>`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bearer: Option<SecretSource>,
+    /// Headers to set
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<HeaderKey, SecretSource>,
+    /// Query parameters to set
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub query: BTreeMap<String, SecretSource>,
+}
+
+impl CredentialRule {
+    pub fn matches(&self, ctx: &RuleContext) -> bool {
+        rule_matches(self.host.as_deref(), self.route.as_deref(), ctx)
+    }
+
+    /// All secret sources used by this rule
+    pub fn sources(&self) -> impl Iterator<Item = &SecretSource> {
+        self.bearer.iter().chain(self.headers.values()).chain(self.query.values())
+    }
+}
+
+/// Inject credentials from matching rules into the outgoing headers and target.
+/// `target` is the URL (or path) the request is sent to.
+pub fn apply_credentials(
+    rules: &[CredentialRule],
+    secrets: &Secrets,
+    ctx: &RuleContext,
+    headers: &mut HeaderMap,
+    target: &mut String,
+) {
+    let mut params = Vec::new();
+
+    for rule in rules.iter().filter(|rule| rule.matches(ctx)) {
+        if let Some(source) = &rule.bearer {
+            if let Some(value) = resolve(source, secrets) {
+                set_header(headers, header::AUTHORIZATION, &format!("Bearer {}", value.expose()));
+            }
+        }
+        for (name, source) in &rule.headers {
+            if let Some(value) = resolve(source, secrets) {
+                set_header(headers, name.0.clone(), value.expose());
+            }
+        }
+        for (name, source) in &rule.query {
+            if let Some(value) = resolve(source, secrets) {
+                params.push((name.clone(), value));
+            }
+        }
+    }
+
+    if !params.is_empty() {
+        *target = set_query_params(target, &params);
+    }
+}
+
+fn resolve(source: &SecretSource, secrets: &Secrets) -> Option<Secret> {
+    match source.resolve(secrets) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            warn!("Not injecting credential: {}", e);
+            None
+        }
+    }
+}
+
+fn set_header(headers: &mut HeaderMap, name: header::HeaderName, value: &str) {
+    match HeaderValue::from_str(value) {
+        Ok(mut value) => {
+            value.set_sensitive(true);
+            headers.insert(name, value);
+        }
+        Err(_) => warn!("Not injecting credential for {}: value is not a valid header", name),
+    }
+}
+
+/// Replace (or add) query parameters on a URL or path
+fn set_query_params(target: &str, params: &[(String, Secret)]) -> String {
+    let (base, fragment) = match target.split_once('#') {
+        Some((base, fragment)) => (base, Some(fragment)),
+        None => (target, None),
+    };
+    let (path, query) = base.split_once('?').unwrap_or((base, ""));
+
+    let mut serializer = url::form_urlencoded::Serializer::new(String::new());
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        if !params.iter().any(|(name, _)| *name == key) {
+            serializer.append_pair(&key, &value);
+        }
+    }
+    for (name, value) in params {
+        serializer.append_pair(name, value.expose());
+    }
+
+    let mut out = format!("{}?{}", path, serializer.finish());
+    if let Some(fragment) = fragment {
+        out.push('#');
+        out.push_str(fragment);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secrets() -> Secrets {
+        [("maps", "maps-key"), ("stripe", "sk_test_123")].into_iter().collect()
+    }
+
+    fn ctx(host: &str) -> RuleContext {
+        RuleContext {
+            host: host.to_string(),
+            ..RuleContext::default()
+        }
+    }
+
+    #[test]
+    fn test_inject_headers_and_query() {
+        let rules: Vec<CredentialRule> = vec![
+            toml::from_str(r#"host = "api.stripe.com"
+bearer = { secret = "stripe" }
+headers = { "X-Api-Key" = { secret = "stripe" } }"#)
+            .unwrap(),
+            toml::from_str(r#"host = "maps.example.com"
+query = { key = { secret = "maps" } }"#)
+            .unwrap(),
+        ];
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer from-browser"));
+        let mut target = "https://api.stripe.com/v1/charges".to_string();
+        apply_credentials(&rules, &secrets(), &ctx("api.stripe.com"), &mut headers, &mut target);
+
+        assert_eq!(headers[header::AUTHORIZATION], "Bearer sk_test_123");
+        assert_eq!(headers["x-api-key"], "sk_test_123");
+        assert_eq!(target, "https://api.stripe.com/v1/charges");
+
+        let mut headers = HeaderMap::new();
+        let mut target = "https://maps.example.com/geo?q=a+b&key=old".to_string();
+        apply_credentials(&rules, &secrets(), &ctx("maps.example.com"), &mut headers, &mut target);
+
+        assert!(headers.is_empty());
+        assert_eq!(target, "https://maps.example.com/geo?q=a+b&key=maps-key");
+    }
+
+    #[test]
+    fn test_secret_is_not_printed() {
+        let secrets = secrets();
+        assert!(!format!("{:?}", secrets).contains("sk_test_123"));
+    }
+
+    #[test]
+    fn test_missing_source() {
+        let source = SecretSource::Env("HOLY_CORS_TEST_UNSET_VARIABLE".to_string());
+        assert_eq!(
+            source.resolve(&Secrets::default()).unwrap_err(),
+            "environment variable HOLY_CORS_TEST_UNSET_VARIABLE is not set"
+        );
+    }
+
+    #[test]
+    fn test_load_error_line() {
+        let path = std::env::temp_dir().join(format!("holy-cors-secrets-{}.toml", std::process::id()));
+        std::fs::write(&path, "stripe = \"sk_test_123\"\n= \"oops\"\n").unwrap();
+        let error = Secrets::load(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(error.ends_with("error at line 2"), "{}", error);
+        assert!(!error.contains("sk_test_123"));
+    }
+}
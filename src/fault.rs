@@ -0,0 +1,387 @@
+use bytes::Bytes;
+use http::{header, HeaderValue, Response, StatusCode};
+use http_body_util::combinators::BoxBody;
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, SizeHint};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::Sleep;
+
+use crate::rules::{rule_matches, RuleContext};
+
+/// Faults injected into a share of the requests to matching upstreams
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaultRule {
+    /// Target host, exact or `*.example.com`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Route alias name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    /// Fraction of matching requests the rule applies to, from 0 to 1
+    #[serde(default = "default_rate")]
+    pub rate: f64,
+    /// Milliseconds added before the request is forwarded
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub latency_ms: u64,
+    /// Up to this many more milliseconds, chosen at random per request
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub jitter_ms: u64,
+    /// Answer with this status instead of forwarding
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// Cut the connection after this many response body bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reset_after: Option<u64>,
+    /// End the response body cleanly after this many bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncate_after: Option<u64>,
+    /// Limit response throughput to this many bytes per second
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_per_second: Option<u64>,
+}
+
+fn default_rate() -> f64 {
+    1.0
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+impl FaultRule {
+    /// Check the rate, status and body faults once the config is loaded
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.rate) {
+            return Err(format!("rate must be between 0 and 1, got {}", self.rate));
+        }
+        if let Some(status) = self.status {
+            StatusCode::from_u16(status).map_err(|_| format!("invalid status {}", status))?;
+        }
+        if self.reset_after.is_some() && self.truncate_after.is_some() {
+            return Err("set either reset_after or truncate_after, not both".to_string());
+        }
+        if self.bytes_per_second == Some(0) {
+            return Err("bytes_per_second must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// What to do to one request, combined from every rule that fired
+#[derive(Debug, Default, PartialEq)]
+pub struct FaultPlan {
+    pub delay: Duration,
+    pub status: Option<StatusCode>,
+    reset_after: Option<u64>,
+    truncate_after: Option<u64>,
+    bytes_per_second: Option<u64>,
+}
+
+impl FaultPlan {
+    /// Wrap the response body with the planned reset, truncation or throttling
+    pub fn apply(&self, response: Response<BoxBody<Bytes, hyper::Error>>) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let no_body = matches!(response.status(), StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED);
+        let limit = self.reset_after.or(self.truncate_after);
+        if no_body || (limit.is_none() && self.bytes_per_second.is_none()) {
+            return response;
+        }
+
+        let (mut parts, body) = response.into_parts();
+        if let Some(reset_after) = self.reset_after {
+            // Promise more than will be sent so the server has to abort the connection
+            let declared = parts
+                .headers
+                .get(header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok());
+            if declared.is_none() {
+                parts.headers.remove(header::TRANSFER_ENCODING);
+                parts.headers.insert(header::CONTENT_LENGTH, HeaderValue::from(reset_after + 1));
+            }
+        } else if self.truncate_after.is_some() {
+            parts.headers.remove(header::CONTENT_LENGTH);
+        }
+        let body = FaultBody {
+            inner: body,
+            remaining: limit,
+            bytes_per_second: self.bytes_per_second,
+            pending: Bytes::new(),
+            sleep: None,
+            done: false,
+        };
+        Response::from_parts(parts, body.boxed())
+    }
+}
+
+/// Runtime switch and random source for fault injection
+pub struct Faults {
+    enabled: AtomicBool,
+    rng: Mutex<Rng>,
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl Faults {
+    /// Start enabled, seeded with `seed` or the clock
+    pub fn new(seed: Option<u64>) -> Self {
+        let seed = seed.unwrap_or_else(|| {
+            SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or_default()
+        });
+        Self {
+            enabled: AtomicBool::new(true),
+            rng: Mutex::new(Rng::new(seed)),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// The seed the random sequence started from
+    pub fn seed(&self) -> u64 {
+        self.rng.lock().unwrap_or_else(|e| e.into_inner()).seed
+    }
+
+    /// Restart the random sequence, so the same requests get the same faults again
+    pub fn reseed(&self, seed: u64) {
+        *self.rng.lock().unwrap_or_else(|e| e.into_inner()) = Rng::new(seed);
+    }
+
+    /// Roll the dice for every matching rule; `None` leaves the request alone
+    pub fn plan(&self, rules: &[FaultRule], ctx: &RuleContext) -> Option<FaultPlan> {
+        if rules.is_empty() || !self.enabled() {
+            return None;
+        }
+        let mut rng = self.rng.lock().unwrap_or_else(|e| e.into_inner());
+        let mut plan: Option<FaultPlan> = None;
+        for rule in rules.iter().filter(|rule| rule_matches(rule.host.as_deref(), rule.route.as_deref(), ctx)) {
+            if rule.rate < 1.0 && rng.unit() >= rule.rate {
+                continue;
+            }
+            let plan = plan.get_or_insert_with(FaultPlan::default);
+            let jitter = match rule.jitter_ms {
+                0 => 0,
+                jitter => rng.next() % (jitter + 1),
+            };
+            plan.delay += Duration::from_millis(rule.latency_ms + jitter);
+            plan.status = plan.status.or(rule.status.and_then(|s| StatusCode::from_u16(s).ok()));
+            plan.reset_after = min(plan.reset_after, rule.reset_after);
+            plan.truncate_after = min(plan.truncate_after, rule.truncate_after);
+            plan.bytes_per_second = min(plan.bytes_per_second, rule.bytes_per_second);
+        }
+        plan
+    }
+}
+
+fn min(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        _ => a.or(b),
+    }
+}
+
+/// SplitMix64: small, fast and the same everywhere for a given seed
+struct Rng {
+    seed: u64,
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self { seed, state: seed }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1)
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Response body that stops early and trickles data out at a fixed rate
+struct FaultBody {
+    inner: BoxBody<Bytes, hyper::Error>,
+    /// Bytes still let through, when limited
+    remaining: Option<u64>,
+    bytes_per_second: Option<u64>,
+    /// Data received but not yet sent
+    pending: Bytes,
+    sleep: Option<Pin<Box<Sleep>>>,
+    done: bool,
+}
+
+impl Body for FaultBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, hyper::Error>>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(sleep) = &mut this.sleep {
+                ready!(sleep.as_mut().poll(cx));
+                this.sleep = None;
+            }
+
+            if !this.pending.is_empty() {
+                let chunk = match this.bytes_per_second {
+                    Some(rate) => {
+                        // Send a tenth of a second's worth at a time
+                        let size = ((rate / 10).max(1) as usize).min(this.pending.len());
+                        let chunk = this.pending.split_to(size);
+                        let pause = Duration::from_secs_f64(chunk.len() as f64 / rate as f64);
+                        this.sleep = Some(Box::pin(tokio::time::sleep(pause)));
+                        chunk
+                    }
+                    None => std::mem::take(&mut this.pending),
+                };
+                return Poll::Ready(Some(Ok(Frame::data(chunk))));
+            }
+
+            if this.done || this.remaining == Some(0) {
+                this.done = true;
+                return Poll::Ready(None);
+            }
+
+            match ready!(Pin::new(&mut this.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(mut data) => {
+                        if let Some(remaining) = &mut this.remaining {
+                            let keep = (*remaining).min(data.len() as u64);
+                            data.truncate(keep as usize);
+                            *remaining -= keep;
+                        }
+                        this.pending = data;
+                    }
+                    Err(frame) => return Poll::Ready(Some(Ok(frame))),
+                },
+                other => {
+                    this.done = true;
+                    return Poll::Ready(other);
+                }
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done && self.pending.is_empty()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self.remaining {
+            Some(_) => SizeHint::default(),
+            None => self.inner.size_hint(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::{Full, StreamBody};
+
+    fn rule(toml: &str) -> FaultRule {
+        let rule: FaultRule = toml::from_str(toml).unwrap();
+        rule.validate().unwrap();
+        rule
+    }
+
+    fn ctx() -> RuleContext {
+        RuleContext {
+            host: "api.example.com".to_string(),
+            target_url: "https://api.example.com/orders".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_seeded_plans_repeat() {
+        let rules = vec![
+            rule("latency_ms = 100\njitter_ms = 50"),
+            rule("host = 'api.example.com'\nrate = 0.3\nstatus = 503"),
+            rule("host = 'other.example.com'\nstatus = 500"),
+        ];
+        let run = |faults: &Faults| -> Vec<FaultPlan> { (0..50).filter_map(|_| faults.plan(&rules, &ctx())).collect() };
+
+        let faults = Faults::new(Some(7));
+        let first = run(&faults);
+        assert_eq!(first.len(), 50);
+        assert!(first.iter().all(|plan| (100..=150).contains(&plan.delay.as_millis())));
+        let errors = first.iter().filter(|plan| plan.status == Some(StatusCode::SERVICE_UNAVAILABLE)).count();
+        assert!((5..=25).contains(&errors), "{} errors", errors);
+
+        faults.reseed(7);
+        assert_eq!(run(&faults), first);
+        faults.set_enabled(false);
+        assert!(faults.plan(&rules, &ctx()).is_none());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(toml::from_str::<FaultRule>("rate = 1.5").unwrap().validate().is_err());
+        assert!(toml::from_str::<FaultRule>("reset_after = 1\ntruncate_after = 2").unwrap().validate().is_err());
+        assert!(toml::from_str::<FaultRule>("status = 1000").unwrap().validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_truncate_and_reset() {
+        let chunks = || {
+            let frames = ["hello ", "faulty ", "world"].map(|s| Ok::<_, hyper::Error>(Frame::data(Bytes::from(s))));
+            StreamBody::new(futures_util::stream::iter(frames)).boxed()
+        };
+
+        let plan = FaultPlan {
+            truncate_after: Some(9),
+            ..Default::default()
+        };
+        let response = plan.apply(Response::new(chunks()));
+        assert!(!response.headers().contains_key(header::CONTENT_LENGTH));
+        assert_eq!(response.into_body().collect().await.unwrap().to_bytes(), "hello fau");
+
+        let plan = FaultPlan {
+            reset_after: Some(4),
+            ..Default::default()
+        };
+        let response = plan.apply(Response::new(chunks()));
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "5");
+        assert_eq!(response.into_body().collect().await.unwrap().to_bytes(), "hell");
+
+        let body = Full::new(Bytes::from("hi")).map_err(|never| match never {}).boxed();
+        let response = plan.apply(Response::builder().status(StatusCode::NO_CONTENT).body(body).unwrap());
+        assert!(!response.headers().contains_key(header::CONTENT_LENGTH));
+    }
+
+    #[tokio::test]
+    async fn test_throttle() {
+        let plan = FaultPlan {
+            bytes_per_second: Some(1000),
+            ..Default::default()
+        };
+        let body = Full::new(Bytes::from(vec![b'x'; 250])).map_err(|never| match never {}).boxed();
+        let started = std::time::Instant::now();
+        let bytes = plan.apply(Response::new(body)).into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(bytes.len(), 250);
+        assert!(started.elapsed() >= Duration::from_millis(240));
+    }
+}
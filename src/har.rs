@@ -0,0 +1,537 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bytes::Bytes;
+use http::{header, HeaderMap, Method, Response, StatusCode, Version};
+use http_body_util::combinators::BoxBody;
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame};
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{mpsc, Arc};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::warn;
+
+use crate::sigv4::civil_date;
+
+/// Closes the entries array and the log; rewritten after every entry so the
+/// file is valid HAR whenever the proxy stops
+const TAIL: &[u8] = b"\n]}}\n";
+
+/// Shown instead of header values marked sensitive (injected credentials)
+const REDACTED: &str = "[redacted]";
+
+/// The log up to its entries
+fn head() -> String {
+    let creator = json!({ "name": "holy-cors", "version": env!("CARGO_PKG_VERSION") });
+    format!(r#"{{"log":{{"version":"1.2","creator":{},"entries":["#, creator)
+}
+
+/// Appends proxied exchanges to a HAR 1.2 file
+///
+/// Entries are written by a dedicated thread, so finishing a response never
+/// waits on the disk.
+pub struct Recorder {
+    writer: mpsc::Sender<Job>,
+    max_body: usize,
+}
+
+/// Work for the writer thread, done in the order it was sent
+enum Job {
+    Entry(Value),
+    Reset(oneshot::Sender<Result<usize, String>>),
+    Flush(oneshot::Sender<()>),
+}
+
+struct HarFile {
+    file: File,
+    entries: usize,
+}
+
+impl Recorder {
+    /// Create (or truncate) the HAR file; bodies are kept up to `max_body` bytes.
+    /// With `keep`, entries already in the file are written back first.
+    pub fn create(path: &Path, max_body: usize, keep: bool) -> Result<Self, String> {
+        let existing = match std::fs::read_to_string(path) {
+            Ok(text) if keep => {
+                let har: Value = serde_json::from_str(&text)
+                    .map_err(|e| format!("Invalid HAR file {}: {}", path.display(), e))?;
+                har["log"]["entries"].as_array().cloned().unwrap_or_default()
+            }
+            _ => Vec::new(),
+        };
+
+        let mut file =
+            File::create(path).map_err(|e| format!("Failed to create HAR file {}: {}", path.display(), e))?;
+        let mut head = head();
+        for (i, entry) in existing.iter().enumerate() {
+            head.push_str(if i == 0 { "\n" } else { ",\n" });
+            head.push_str(&entry.to_string());
+        }
+        file.write_all(head.as_bytes())
+            .and_then(|_| file.write_all(TAIL))
+            .map_err(|e| format!("Failed to write HAR file {}: {}", path.display(), e))?;
+
+        let mut har = HarFile {
+            file,
+            entries: existing.len(),
+        };
+        let (writer, jobs) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("har-writer".to_string())
+            .spawn(move || {
+                for job in jobs {
+                    match job {
+                        Job::Entry(entry) => har.append(&entry),
+                        Job::Reset(done) => {
+                            let _ = done.send(har.reset());
+                        }
+                        Job::Flush(done) => {
+                            let _ = done.send(());
+                        }
+                    }
+                }
+            })
+            .map_err(|e| format!("Failed to start HAR writer: {}", e))?;
+
+        Ok(Self {
+            writer,
+            max_body,
+        })
+    }
+
+    /// Drop every recorded entry, returning how many there were
+    pub async fn reset(&self) -> Result<usize, String> {
+        let (done, reset) = oneshot::channel();
+        self.send(Job::Reset(done));
+        reset.await.map_err(|_| "HAR writer stopped".to_string())?
+    }
+
+    /// Wait until every entry finished so far is in the file
+    pub async fn flush(&self) {
+        let (done, flushed) = oneshot::channel();
+        self.send(Job::Flush(done));
+        let _ = flushed.await;
+    }
+
+    fn send(&self, job: Job) {
+        if self.writer.send(job).is_err() {
+            warn!("Failed to record HAR entry: writer stopped");
+        }
+    }
+
+    /// Start an entry for a request about to be sent upstream
+    pub fn start(self: &Arc<Self>, method: &Method, url: &str, headers: &HeaderMap, body: &[u8]) -> Exchange {
+        let query: Vec<Value> = url::Url::parse(url)
+            .map(|url| {
+                url.query_pairs()
+                    .map(|(name, value)| json!({ "name": name, "value": value }))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut request = json!({
+            "method": method.as_str(),
+            "url": url,
+            "httpVersion": "HTTP/1.1",
+            "cookies": [],
+            "headers": har_headers(headers),
+            "queryString": query,
+            "headersSize": -1,
+            "bodySize": body.len(),
+        });
+        if !body.is_empty() {
+            let mut post_data = self.content(body, body.len());
+            post_data["mimeType"] = json!(mime_type(headers));
+            request["postData"] = post_data;
+        }
+
+        Exchange {
+            recorder: Arc::clone(self),
+            started: SystemTime::now(),
+            start: Instant::now(),
+            request,
+        }
+    }
+
+    /// A body as HAR content: text when it is UTF-8, base64 otherwise,
+    /// truncated to the size limit
+    fn content(&self, body: &[u8], size: usize) -> Value {
+        let kept = &body[..body.len().min(self.max_body)];
+        let mut content = match std::str::from_utf8(kept) {
+            Ok(text) => json!({ "text": text }),
+            // A truncated body may end inside a character
+            Err(e) if kept.len() < size && e.error_len().is_none() => {
+                json!({ "text": String::from_utf8_lossy(&kept[..e.valid_up_to()]) })
+            }
+            Err(_) => json!({ "text": BASE64.encode(kept), "encoding": "base64" }),
+        };
+        if kept.len() < size {
+            content["comment"] = json!(format!("Truncated to {} of {} bytes", kept.len(), size));
+        }
+        content
+    }
+
+    /// Queue an entry for the writer thread
+    fn append(&self, entry: Value) {
+        self.send(Job::Entry(entry));
+    }
+}
+
+impl HarFile {
+    /// Append an entry, keeping the file valid JSON
+    fn append(&mut self, entry: &Value) {
+        let separator: &[u8] = if self.entries == 0 { b"\n" } else { b",\n" };
+        let result = self
+            .file
+            .seek(SeekFrom::End(-(TAIL.len() as i64)))
+            .and_then(|_| self.file.write_all(separator))
+            .and_then(|_| self.file.write_all(entry.to_string().as_bytes()))
+            .and_then(|_| self.file.write_all(TAIL))
+            .and_then(|_| self.file.flush());
+        match result {
+            Ok(()) => self.entries += 1,
+            Err(e) => warn!("Failed to record HAR entry: {}", e),
+        }
+    }
+
+    /// Drop every entry, returning how many there were
+    fn reset(&mut self) -> Result<usize, String> {
+        let head = head();
+        let result = self
+            .file
+            .set_len(0)
+            .and_then(|_| self.file.seek(SeekFrom::Start(0)))
+            .and_then(|_| self.file.write_all(head.as_bytes()))
+            .and_then(|_| self.file.write_all(TAIL))
+            .and_then(|_| self.file.flush());
+        result.map_err(|e| format!("Failed to reset HAR file: {}", e))?;
+        Ok(std::mem::take(&mut self.entries))
+    }
+}
+
+/// A request sent upstream, waiting for its response
+pub struct Exchange {
+    recorder: Arc<Recorder>,
+    started: SystemTime,
+    start: Instant,
+    request: Value,
+}
+
+impl Exchange {
+    /// Record the response; the entry is written once its body has streamed
+    /// to the browser (or was dropped)
+    pub fn response<B>(self, response: Response<B>) -> Response<BoxBody<Bytes, hyper::Error>>
+    where
+        B: Body<Data = Bytes, Error = hyper::Error> + Send + Sync + 'static,
+    {
+        let (parts, body) = response.into_parts();
+        let headers = self.response_json(parts.status, parts.version, &parts.headers);
+        let wait = self.start.elapsed();
+        let body = RecordBody {
+            inner: body.boxed(),
+            pending: Some(Pending {
+                exchange: self,
+                response: headers,
+                mime_type: mime_type(&parts.headers).to_string(),
+                wait,
+                received: Instant::now(),
+                body: Vec::new(),
+                size: 0,
+            }),
+        };
+        Response::from_parts(parts, body.boxed())
+    }
+
+    /// Record a request that got no response
+    pub fn failed(self, error: &str) {
+        let mut response = self.response_json(StatusCode::BAD_GATEWAY, Version::HTTP_11, &HeaderMap::new());
+        response["status"] = json!(0);
+        response["statusText"] = json!("");
+        response["_error"] = json!(error);
+        let wait = self.start.elapsed();
+        self.finish(response, wait, Duration::ZERO, json!({ "size": 0, "mimeType": "" }));
+    }
+
+    /// Record a WebSocket handshake; messages are added to the returned log
+    pub fn websocket(self, headers: &HeaderMap) -> WebSocketLog {
+        let response = self.response_json(StatusCode::SWITCHING_PROTOCOLS, Version::HTTP_11, headers);
+        WebSocketLog {
+            wait: self.start.elapsed(),
+            exchange: Some(self),
+            response,
+            messages: Vec::new(),
+        }
+    }
+
+    fn response_json(&self, status: StatusCode, version: Version, headers: &HeaderMap) -> Value {
+        json!({
+            "status": status.as_u16(),
+            "statusText": status.canonical_reason().unwrap_or_default(),
+            "httpVersion": format!("{:?}", version),
+            "cookies": [],
+            "headers": har_headers(headers),
+            "redirectURL": headers.get(header::LOCATION).and_then(|v| v.to_str().ok()).unwrap_or_default(),
+            "headersSize": -1,
+        })
+    }
+
+    fn finish(self, mut response: Value, wait: Duration, receive: Duration, content: Value) {
+        response["content"] = content;
+        response["bodySize"] = response["content"]["size"].clone();
+        let (wait, receive) = (millis(wait), millis(receive));
+        let entry = json!({
+            "startedDateTime": iso8601(self.started),
+            "time": wait + receive,
+            "request": self.request,
+            "response": response,
+            "cache": {},
+            "timings": {
+                "blocked": -1,
+                "dns": -1,
+                "connect": -1,
+                "ssl": -1,
+                "send": 0,
+                "wait": wait,
+                "receive": receive,
+            },
+        });
+        self.recorder.append(entry);
+    }
+}
+
+struct Pending {
+    exchange: Exchange,
+    response: Value,
+    mime_type: String,
+    wait: Duration,
+    received: Instant,
+    body: Vec<u8>,
+    size: usize,
+}
+
+impl Pending {
+    fn finish(self) {
+        let mut content = self.exchange.recorder.content(&self.body, self.size);
+        content["size"] = json!(self.size);
+        content["mimeType"] = json!(self.mime_type);
+        if let (0, Some(content)) = (self.size, content.as_object_mut()) {
+            content.remove("text");
+        }
+        let receive = self.received.elapsed();
+        self.exchange.finish(self.response, self.wait, receive, content);
+    }
+}
+
+/// A response body that keeps a copy for the HAR entry as it streams
+struct RecordBody {
+    inner: BoxBody<Bytes, hyper::Error>,
+    pending: Option<Pending>,
+}
+
+impl Body for RecordBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, hyper::Error>>> {
+        let frame = ready!(Pin::new(&mut self.inner).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => {
+                if let (Some(data), Some(pending)) = (frame.data_ref(), self.pending.as_mut()) {
+                    pending.size += data.len();
+                    let room = pending.exchange.recorder.max_body.saturating_sub(pending.body.len());
+                    pending.body.extend_from_slice(&data[..data.len().min(room)]);
+                }
+            }
+            _ => {
+                if let Some(pending) = self.pending.take() {
+                    pending.finish();
+                }
+            }
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for RecordBody {
+    fn drop(&mut self) {
+        // The browser went away, or the body was never read (a followed redirect)
+        if let Some(pending) = self.pending.take() {
+            pending.finish();
+        }
+    }
+}
+
+/// Messages of a proxied WebSocket, recorded as `_webSocketMessages` when it closes
+pub struct WebSocketLog {
+    exchange: Option<Exchange>,
+    response: Value,
+    wait: Duration,
+    messages: Vec<Value>,
+}
+
+impl WebSocketLog {
+    /// Record a message from the browser
+    pub fn send(&mut self, msg: &Message) {
+        self.push("send", msg);
+    }
+
+    /// Record a message from the upstream
+    pub fn receive(&mut self, msg: &Message) {
+        self.push("receive", msg);
+    }
+
+    fn push(&mut self, kind: &str, msg: &Message) {
+        let Some(exchange) = &self.exchange else {
+            return;
+        };
+        let max_body = exchange.recorder.max_body;
+        let (opcode, data) = match msg {
+            Message::Text(text) => {
+                let mut end = text.len().min(max_body);
+                while !text.is_char_boundary(end) {
+                    end -= 1;
+                }
+                (1, text[..end].to_string())
+            }
+            Message::Binary(data) => (2, BASE64.encode(&data[..data.len().min(max_body)])),
+            _ => return,
+        };
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        self.messages.push(json!({ "type": kind, "time": time, "opcode": opcode, "data": data }));
+    }
+}
+
+impl Drop for WebSocketLog {
+    fn drop(&mut self) {
+        let Some(exchange) = self.exchange.take() else {
+            return;
+        };
+        let mut response = std::mem::take(&mut self.response);
+        response["_webSocketMessages"] = json!(std::mem::take(&mut self.messages));
+        exchange.finish(response, self.wait, Duration::ZERO, json!({ "size": 0, "mimeType": "" }));
+    }
+}
+
+fn har_headers(headers: &HeaderMap) -> Vec<Value> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if value.is_sensitive() {
+                REDACTED.into()
+            } else {
+                String::from_utf8_lossy(value.as_bytes())
+            };
+            json!({ "name": name.as_str(), "value": value })
+        })
+        .collect()
+}
+
+fn mime_type(headers: &HeaderMap) -> &str {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+}
+
+fn millis(duration: Duration) -> f64 {
+    (duration.as_secs_f64() * 1000.0 * 1000.0).round() / 1000.0
+}
+
+/// `2024-05-01T12:00:00.000Z`
+pub fn iso8601(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_date((secs / 86400) as i64);
+    let rem = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sigv4::{sign, AwsCredentials};
+    use http_body_util::Full;
+
+    #[tokio::test]
+    async fn test_record_entries() {
+        let path = std::env::temp_dir().join(format!("holy-cors-test-{}.har", std::process::id()));
+        let recorder = Arc::new(Recorder::create(&path, 4, false).unwrap());
+
+        let mut headers = HeaderMap::new();
+        let mut secret = http::HeaderValue::from_static("Bearer token");
+        secret.set_sensitive(true);
+        headers.insert(header::AUTHORIZATION, secret);
+        headers.insert(header::CONTENT_TYPE, http::HeaderValue::from_static("text/plain"));
+
+        let exchange = recorder.start(&Method::POST, "https://example.com/a?x=1", &headers, b"hi");
+        let response = Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(Full::new(Bytes::from_static(b"hello world")).map_err(|_| unreachable!()))
+            .unwrap();
+        let body = exchange.response(response).into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "hello world");
+
+        // The file is complete after each entry
+        recorder.flush().await;
+        let har: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let entry = &har["log"]["entries"][0];
+        assert_eq!(entry["request"]["headers"][0]["value"], REDACTED);
+        assert_eq!(entry["request"]["queryString"][0]["value"], "1");
+        assert_eq!(entry["request"]["postData"]["text"], "hi");
+        assert_eq!(entry["response"]["content"]["text"], "hell");
+        assert_eq!(entry["response"]["content"]["size"], 11);
+
+        recorder.start(&Method::GET, "https://example.com/b", &HeaderMap::new(), b"").failed("refused");
+        recorder.flush().await;
+        let har: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(har["log"]["entries"][1]["response"]["_error"], "refused");
+
+        // Signed requests are recorded without their session token
+        let credentials = AwsCredentials {
+            access_key_id: "AKID".into(),
+            secret_access_key: "secret".into(),
+            session_token: Some("session".into()),
+        };
+        let uri: http::Uri = "https://s3.amazonaws.com/bucket".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        sign(&Method::GET, &uri, &mut headers, b"", &credentials, "us-east-1", "s3", SystemTime::now()).unwrap();
+        recorder.start(&Method::GET, &uri.to_string(), &headers, b"").failed("refused");
+        recorder.flush().await;
+        let har: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let recorded = har["log"]["entries"][2]["request"]["headers"].as_array().unwrap();
+        for name in ["x-amz-security-token", "authorization"] {
+            let header = recorded.iter().find(|h| h["name"] == name).unwrap();
+            assert_eq!(header["value"], REDACTED);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_iso8601() {
+        assert_eq!(iso8601(UNIX_EPOCH + Duration::from_millis(1_440_938_160_123)), "2015-08-30T12:36:00.123Z");
+    }
+}
@@ -0,0 +1,478 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bytes::Bytes;
+use http::{header, HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Incoming;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::mock::url_matches;
+use crate::rules::{rule_matches, RuleContext};
+use crate::transform::{read_body, Buffered, MAX_BODY_SIZE};
+
+/// Pause matching requests, their responses, or both, until released through the admin API
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Breakpoint {
+    /// Target host, exact or `*.example.com`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Route alias name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    /// Request method (any when unset)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    /// Target URL pattern, as for mock rules
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub phase: Phase,
+}
+
+/// Where a breakpoint stops the exchange
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    /// Before the request is forwarded
+    #[default]
+    Request,
+    /// Before the response reaches the browser
+    Response,
+    Both,
+}
+
+impl Phase {
+    fn includes(self, other: Phase) -> bool {
+        self == Phase::Both || self == other
+    }
+}
+
+impl Breakpoint {
+    pub fn matches(&self, method: &Method, ctx: &RuleContext) -> bool {
+        let method_ok = self.method.as_deref().is_none_or(|m| m.eq_ignore_ascii_case(method.as_str()));
+        let url_ok = self.url.as_deref().is_none_or(|pattern| url_matches(pattern, &ctx.target_url));
+        method_ok && url_ok && rule_matches(self.host.as_deref(), self.route.as_deref(), ctx)
+    }
+
+    /// Check the method once the config is loaded
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(method) = &self.method {
+            Method::from_bytes(method.as_bytes()).map_err(|_| format!("invalid method '{}'", method))?;
+        }
+        Ok(())
+    }
+}
+
+/// Changes to a paused message, sent with `POST /__holy/paused/{id}/resume`;
+/// unset fields are left alone
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Edit {
+    /// New request method
+    method: Option<String>,
+    /// New response status
+    status: Option<u16>,
+    /// Replacement header list, as `[name, value]` pairs
+    headers: Option<Vec<(String, String)>>,
+    body: Option<String>,
+    /// The body is base64 encoded
+    #[serde(default)]
+    base64: bool,
+}
+
+/// A paused request or response, as shown and edited through the admin API
+#[derive(Debug, Clone)]
+struct Message {
+    phase: Phase,
+    method: Method,
+    url: String,
+    status: Option<StatusCode>,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Message {
+    fn to_json(&self, id: u64, paused_for: Duration) -> Value {
+        let headers: Vec<(&str, String)> = self
+            .headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), String::from_utf8_lossy(value.as_bytes()).into_owned()))
+            .collect();
+        let mut value = json!({
+            "id": id,
+            "phase": self.phase,
+            "method": self.method.as_str(),
+            "url": self.url,
+            "headers": headers,
+            "paused_ms": paused_for.as_millis() as u64,
+        });
+        if let Some(status) = self.status {
+            value["status"] = json!(status.as_u16());
+        }
+        match std::str::from_utf8(&self.body) {
+            Ok(text) => value["body"] = json!(text),
+            Err(_) => {
+                value["body"] = json!(BASE64.encode(&self.body));
+                value["base64"] = json!(true);
+            }
+        }
+        value
+    }
+
+    /// Apply an edit, rejecting changes that don't fit the phase
+    fn edit(&mut self, edit: Edit) -> Result<(), String> {
+        if let Some(method) = edit.method {
+            if self.phase != Phase::Request {
+                return Err("method can only be changed on requests".to_string());
+            }
+            self.method = Method::from_bytes(method.as_bytes()).map_err(|_| format!("invalid method '{}'", method))?;
+        }
+        if let Some(status) = edit.status {
+            if self.phase != Phase::Response {
+                return Err("status can only be changed on responses".to_string());
+            }
+            self.status = Some(StatusCode::from_u16(status).map_err(|_| format!("invalid status {}", status))?);
+        }
+        if let Some(pairs) = edit.headers {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("invalid header name '{}'", name))?;
+                let value = HeaderValue::from_str(&value).map_err(|_| format!("invalid value for header {}", name))?;
+                headers.append(name, value);
+            }
+            self.headers = headers;
+        }
+        if let Some(body) = edit.body {
+            self.body = match edit.base64 {
+                true => Bytes::from(BASE64.decode(body).map_err(|e| format!("invalid base64 body: {}", e))?),
+                false => Bytes::from(body),
+            };
+            // The body is sent whole, so any declared framing would be stale
+            self.headers.remove(header::CONTENT_LENGTH);
+            self.headers.remove(header::TRANSFER_ENCODING);
+        }
+        Ok(())
+    }
+}
+
+/// How a paused message was let go
+enum Release {
+    Resume(Box<Message>),
+    Drop,
+}
+
+struct Paused {
+    message: Message,
+    since: Instant,
+    release: oneshot::Sender<Release>,
+}
+
+/// Breakpoints added at runtime and the messages waiting at any breakpoint
+#[derive(Default)]
+pub struct Intercept {
+    breakpoints: RwLock<Arc<Vec<Breakpoint>>>,
+    next_id: AtomicU64,
+    paused: Mutex<BTreeMap<u64, Paused>>,
+}
+
+/// Takes a message off the paused list when its request finishes or is cancelled
+struct Unpause<'a>(&'a Intercept, u64);
+
+impl Drop for Unpause<'_> {
+    fn drop(&mut self) {
+        self.0.paused.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.1);
+    }
+}
+
+impl Intercept {
+    /// The breakpoints added through the admin API
+    pub fn breakpoints(&self) -> Arc<Vec<Breakpoint>> {
+        Arc::clone(&self.breakpoints.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    pub fn set_breakpoints(&self, breakpoints: Vec<Breakpoint>) {
+        *self.breakpoints.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(breakpoints);
+    }
+
+    fn breaks(&self, config: &Config, phase: Phase, method: &Method, ctx: &RuleContext) -> bool {
+        let runtime = self.breakpoints();
+        config
+            .breakpoints
+            .iter()
+            .chain(runtime.iter())
+            .any(|breakpoint| breakpoint.phase.includes(phase) && breakpoint.matches(method, ctx))
+    }
+
+    /// Hold the request at a matching breakpoint, returning it as released
+    pub async fn request(
+        &self,
+        req: Request<Incoming>,
+        ctx: &RuleContext,
+        config: &Config,
+    ) -> Result<Request<BoxBody<Bytes, hyper::Error>>, (StatusCode, String)> {
+        let method = req.method().clone();
+        let mut req = req.map(|body| body.boxed());
+        // Ask for a plain body so a paused response can be read and edited
+        if self.breaks(config, Phase::Response, &method, ctx) {
+            req.headers_mut().insert(header::ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+        }
+        if !self.breaks(config, Phase::Request, &method, ctx) {
+            return Ok(req);
+        }
+
+        let (parts, body) = req.into_parts();
+        let body = body
+            .collect()
+            .await
+            .map_err(|_| (StatusCode::BAD_REQUEST, "Failed to read request body".to_string()))?
+            .to_bytes();
+        let message = Message {
+            phase: Phase::Request,
+            method,
+            url: ctx.target_url.clone(),
+            status: None,
+            headers: parts.headers.clone(),
+            body,
+        };
+        let message = self.pause(message, config.breakpoint_timeout).await?;
+
+        let mut parts = parts;
+        parts.method = message.method;
+        parts.headers = message.headers;
+        Ok(Request::from_parts(parts, full(message.body)))
+    }
+
+    /// Hold the response at a matching breakpoint, returning it as released
+    pub async fn response(
+        &self,
+        response: Response<BoxBody<Bytes, hyper::Error>>,
+        method: &Method,
+        ctx: &RuleContext,
+        config: &Config,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, (StatusCode, String)> {
+        if !self.breaks(config, Phase::Response, method, ctx) {
+            return Ok(response);
+        }
+
+        // Event streams never end, so there's no whole response to pause on
+        if is_event_stream(response.headers()) {
+            warn!("Not pausing the event stream from {} at its breakpoint", ctx.target_url);
+            return Ok(response);
+        }
+
+        let (parts, body) = response.into_parts();
+        let timeout = Duration::from_secs(config.breakpoint_timeout);
+        let body = match tokio::time::timeout(timeout, read_body(body)).await {
+            Ok(Ok(Buffered::Whole(body))) => body,
+            Ok(Ok(Buffered::Oversized(body))) => {
+                warn!("Not pausing the response from {} at its breakpoint: body over {} bytes", ctx.target_url, MAX_BODY_SIZE);
+                return Ok(Response::from_parts(parts, body));
+            }
+            Ok(Err(_)) => return Err((StatusCode::BAD_GATEWAY, "Failed to read upstream response".to_string())),
+            Err(_) => {
+                return Err((
+                    StatusCode::GATEWAY_TIMEOUT,
+                    format!(
+                        "Upstream response did not finish within {}s, so it could not be paused (see --breakpoint-timeout)",
+                        config.breakpoint_timeout
+                    ),
+                ))
+            }
+        };
+        let message = Message {
+            phase: Phase::Response,
+            method: method.clone(),
+            url: ctx.target_url.clone(),
+            status: Some(parts.status),
+            headers: parts.headers.clone(),
+            body,
+        };
+        let message = self.pause(message, config.breakpoint_timeout).await?;
+
+        let mut parts = parts;
+        parts.status = message.status.unwrap_or(parts.status);
+        parts.headers = message.headers;
+        if !parts.headers.contains_key(header::CONTENT_LENGTH) {
+            parts.headers.insert(header::CONTENT_LENGTH, HeaderValue::from(message.body.len()));
+        }
+        Ok(Response::from_parts(parts, full(message.body)))
+    }
+
+    /// Wait until the message is resumed or dropped, or the timeout passes
+    async fn pause(&self, message: Message, timeout_secs: u64) -> Result<Message, (StatusCode, String)> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let (release, released) = oneshot::channel();
+        info!(
+            "Paused {} {} {} at breakpoint as #{} (release with POST /__holy/paused/{}/resume)",
+            match message.phase {
+                Phase::Response => "response to",
+                _ => "request",
+            },
+            message.method,
+            message.url,
+            id,
+            id
+        );
+        let since = Instant::now();
+        self.paused.lock().unwrap_or_else(|e| e.into_inner()).insert(id, Paused { message, since, release });
+        let _unpause = Unpause(self, id);
+
+        match tokio::time::timeout(Duration::from_secs(timeout_secs), released).await {
+            Ok(Ok(Release::Resume(message))) => Ok(*message),
+            Ok(Ok(Release::Drop)) | Ok(Err(_)) => {
+                Err((StatusCode::BAD_GATEWAY, format!("Dropped at breakpoint #{}", id)))
+            }
+            Err(_) => {
+                warn!("Breakpoint #{} was not released within {}s", id, timeout_secs);
+                Err((
+                    StatusCode::GATEWAY_TIMEOUT,
+                    format!(
+                        "Paused at breakpoint #{} for {}s without being released (see --breakpoint-timeout)",
+                        id, timeout_secs
+                    ),
+                ))
+            }
+        }
+    }
+
+    /// Everything waiting at a breakpoint, oldest first
+    pub fn list(&self) -> Value {
+        let paused = self.paused.lock().unwrap_or_else(|e| e.into_inner());
+        let list: Vec<Value> = paused.iter().map(|(id, p)| p.message.to_json(*id, p.since.elapsed())).collect();
+        json!({ "paused": list })
+    }
+
+    /// Let a paused message continue, with the edit applied
+    pub fn resume(&self, id: u64, edit: Edit) -> Result<(), (StatusCode, String)> {
+        let mut paused = self.paused.lock().unwrap_or_else(|e| e.into_inner());
+        let mut message = paused.get(&id).ok_or_else(|| not_paused(id))?.message.clone();
+        message.edit(edit).map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid edit: {}", e)))?;
+        let Paused { release, .. } = paused.remove(&id).ok_or_else(|| not_paused(id))?;
+        release.send(Release::Resume(Box::new(message))).map_err(|_| not_paused(id))
+    }
+
+    /// Fail a paused message with a 502
+    pub fn drop_paused(&self, id: u64) -> Result<(), (StatusCode, String)> {
+        let paused = self.paused.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+        let paused = paused.ok_or_else(|| not_paused(id))?;
+        paused.release.send(Release::Drop).map_err(|_| not_paused(id))
+    }
+}
+
+fn not_paused(id: u64) -> (StatusCode, String) {
+    (StatusCode::NOT_FOUND, format!("Nothing paused as #{}", id))
+}
+
+/// Whether a response is a server-sent event stream
+fn is_event_stream(headers: &HeaderMap) -> bool {
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or_default();
+    content_type.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case("text/event-stream")
+}
+
+fn full(body: Bytes) -> BoxBody<Bytes, hyper::Error> {
+    Full::new(body).map_err(|never| match never {}).boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(phase: Phase) -> Message {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("2"));
+        Message {
+            phase,
+            method: Method::POST,
+            url: "https://api.example.com/orders".to_string(),
+            status: (phase == Phase::Response).then_some(StatusCode::OK),
+            headers,
+            body: Bytes::from_static(b"{}"),
+        }
+    }
+
+    #[test]
+    fn test_edit() {
+        let mut request = message(Phase::Request);
+        let edit: Edit = serde_json::from_str(r#"{"method": "PUT", "headers": [["x-a", "1"], ["x-a", "2"]], "body": "/w=="}"#).unwrap();
+        assert!(request.edit(Edit { base64: true, ..edit }).is_ok());
+        assert_eq!(request.method, Method::PUT);
+        assert_eq!(request.headers.get_all("x-a").iter().count(), 2);
+        assert_eq!(request.body, Bytes::from_static(&[0xff]));
+        assert_eq!(request.to_json(1, Duration::ZERO)["base64"], true);
+
+        let mut response = message(Phase::Response);
+        assert!(response.edit(Edit { method: Some("GET".to_string()), ..Default::default() }).is_err());
+        response.edit(serde_json::from_str(r#"{"status": 500, "body": "oops"}"#).unwrap()).unwrap();
+        assert_eq!(response.status, Some(StatusCode::INTERNAL_SERVER_ERROR));
+        assert!(!response.headers.contains_key(header::CONTENT_LENGTH));
+    }
+
+    #[tokio::test]
+    async fn test_pause_resume_and_timeout() {
+        let intercept = Arc::new(Intercept::default());
+        let paused = {
+            let intercept = Arc::clone(&intercept);
+            tokio::spawn(async move { intercept.pause(message(Phase::Request), 5).await })
+        };
+        while intercept.list()["paused"].as_array().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(intercept.list()["paused"][0]["url"], "https://api.example.com/orders");
+        assert_eq!(intercept.resume(2, Edit::default()).unwrap_err().0, StatusCode::NOT_FOUND);
+        intercept.resume(1, serde_json::from_str(r#"{"body": "{\"id\": 1}"}"#).unwrap()).unwrap();
+        assert_eq!(paused.await.unwrap().unwrap().body, r#"{"id": 1}"#);
+        assert!(intercept.list()["paused"].as_array().unwrap().is_empty());
+
+        let (status, message) = intercept.pause(message(Phase::Response), 0).await.unwrap_err();
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        assert!(message.contains("#2"));
+        assert!(intercept.list()["paused"].as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_phases() {
+        let breakpoint: Breakpoint = toml::from_str("url = 'https://api.example.com/*'\nphase = 'both'").unwrap();
+        let ctx = RuleContext {
+            host: "api.example.com".to_string(),
+            target_url: "https://api.example.com/orders".to_string(),
+            ..Default::default()
+        };
+        assert!(breakpoint.matches(&Method::GET, &ctx));
+        assert!(breakpoint.phase.includes(Phase::Request) && breakpoint.phase.includes(Phase::Response));
+        assert!(!Phase::Request.includes(Phase::Response));
+    }
+
+    #[tokio::test]
+    async fn test_unbounded_responses() {
+        use clap::Parser;
+        let intercept = Intercept::default();
+        intercept.set_breakpoints(vec![toml::from_str("phase = 'response'").unwrap()]);
+        let mut config = Config::parse_from(["holy-cors"]);
+        config.breakpoint_timeout = 0;
+        let ctx = RuleContext { target_url: "https://api.example.com/events".to_string(), ..Default::default() };
+        let endless = || {
+            let body = http_body_util::StreamBody::new(futures_util::stream::pending::<Result<hyper::body::Frame<Bytes>, hyper::Error>>());
+            Response::new(body.boxed())
+        };
+
+        // Event streams pass through without being read
+        let mut response = endless();
+        response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+        assert!(intercept.response(response, &Method::GET, &ctx, &config).await.is_ok());
+
+        // Other bodies must finish within the breakpoint timeout
+        let (status, _) = intercept.response(endless(), &Method::GET, &ctx, &config).await.unwrap_err();
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        assert!(intercept.list()["paused"].as_array().unwrap().is_empty());
+    }
+}
@@ -1,20 +1,46 @@
+mod admin;
 mod config;
+mod config_file;
+mod cookies;
 mod cors;
+mod credentials;
+mod fault;
+mod har;
+mod intercept;
+mod mock;
+mod oauth;
+mod openapi;
 mod proxy;
+mod redirect;
+mod reload;
+mod replay;
+mod resolve;
+mod rewrite;
+mod rules;
+mod server;
+mod shutdown;
+mod sigv4;
+mod state;
+mod transform;
+mod unix;
+mod upstream;
+mod websocket;
 
-use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
-use clap::Parser;
-use hyper::server::conn::http1;
-use hyper::service::service_fn;
-use hyper_util::rt::TokioIo;
-use tokio::net::TcpListener;
-use tracing::{error, info, Level};
+use tracing::{info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 
-use crate::config::Config;
-use crate::proxy::handle_request;
+use crate::config::{Command, Config, ConfigCommand, ListenAddr};
+use crate::config_file::FileConfig;
+use crate::har::Recorder;
+use crate::oauth::TokenCache;
+use crate::replay::Cassette;
+use crate::server::Listener;
+use crate::shutdown::{wait_for_signal, Shutdown};
+use crate::state::State;
+use crate::upstream::Upstream;
 
 const BANNER: &str = r#"
     _   _       _          ____  ___  ____  ____  _
@@ -32,8 +58,24 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         .install_default()
         .expect("Failed to install rustls crypto provider");
 
-    // Parse CLI arguments
-    let config = Config::parse();
+    // Parse CLI arguments, environment and config file
+    let matches = Config::args();
+    let (config, config_path) = match Config::load(&matches) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(Command::Config { action: ConfigCommand::Print }) = &config.command {
+        if let Some(path) = &config_path {
+            println!("# Loaded from {}", path.display());
+        }
+        print!("{}", FileConfig::from_config(&config)?.to_toml());
+        return Ok(());
+    }
+
     let config = Arc::new(config);
 
     // Initialize logging
@@ -55,8 +97,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     println!("  A fast CORS proxy for developers\n");
 
     // Print configuration
+    let listen_addrs = config.listen_addrs()?;
     info!("Starting Holy CORS proxy...");
-    info!("Listening on http://{}", config.socket_addr());
+    if let Some(path) = &config_path {
+        info!("Loaded configuration from {}", path.display());
+    }
+    for addr in &listen_addrs {
+        info!("Listening on {}", addr);
+    }
 
     if config.allow_all {
         info!("Mode: Allow ALL origins (development mode)");
@@ -67,47 +115,102 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         }
     }
 
+    for (name, target) in &config.routes {
+        info!("Route: /{}/ -> {}", name, target);
+    }
+
+    // Build upstream clients (TLS roots, insecure hosts, client certificates)
+    let tokens = Arc::new(TokenCache::default());
+    let cassette = Cassette::from_config(&config)?;
+    let upstream = Arc::new(Upstream::from_config(&config, Arc::clone(&tokens), cassette.clone())?);
+
+    let port = listen_addrs
+        .iter()
+        .find_map(|addr| match addr {
+            ListenAddr::Tcp(addr) => Some(addr.port()),
+            ListenAddr::Unix(_) => None,
+        })
+        .unwrap_or(config.port);
+
     println!();
-    info!("Usage: http://localhost:{}/{{TARGET_URL}}", config.port);
-    info!("Example: http://localhost:{}/https://api.github.com/users/octocat", config.port);
+    info!("Usage: http://localhost:{}/{{TARGET_URL}}", port);
+    info!("Example: http://localhost:{}/https://api.github.com/users/octocat", port);
     println!();
 
-    // Bind to address
-    let addr: SocketAddr = config.socket_addr().parse()?;
-    let listener = TcpListener::bind(addr).await?;
+    // Start the HAR file before any traffic
+    let recorder = match &config.record {
+        Some(path) => {
+            info!("Recording traffic to {}", path.display());
+            // Recording over the replayed file adds to it
+            let keep = config.replay.as_ref() == Some(path);
+            Some(Recorder::create(path, config.record_max_body, keep)?)
+        }
+        None => None,
+    };
+
+    // Bind all addresses before serving any of them
+    let listeners = Listener::bind_all(&listen_addrs)?;
 
     info!("Server is ready to accept connections");
 
-    // Accept connections
-    loop {
-        let (stream, remote_addr) = match listener.accept().await {
-            Ok(result) => result,
-            Err(e) => {
-                error!("Failed to accept connection: {}", e);
-                continue;
-            }
-        };
-
-        let config = Arc::clone(&config);
-
-        // Spawn a new task for each connection
-        tokio::spawn(async move {
-            let io = TokioIo::new(stream);
-
-            let service = service_fn(move |req| {
-                let config = Arc::clone(&config);
-                async move { handle_request(req, config).await }
-            });
-
-            if let Err(e) = http1::Builder::new()
-                .serve_connection(io, service)
-                .with_upgrades()
-                .await
-            {
-                if !e.to_string().contains("connection closed") {
-                    error!("Connection error from {}: {}", remote_addr, e);
-                }
-            }
-        });
+    // Accept connections on every listener with the same handler
+    let shutdown = Shutdown::new();
+    let state = State::new(Arc::clone(&config), upstream, tokens, cassette, recorder);
+    if !config.faults.is_empty() {
+        let seed = state.admin().faults().seed();
+        info!("Injecting faults from {} rule(s), seed {} (repeat with --fault-seed {})", config.faults.len(), seed, seed);
+    }
+    let tasks: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| tokio::spawn(listener.run(Arc::clone(&state), Arc::clone(&shutdown))))
+        .collect();
+
+    // Reload on SIGHUP or config file change
+    tokio::spawn(reload::run(matches, config_path, Arc::clone(&state), Arc::clone(&shutdown)));
+
+    // Run until SIGINT/SIGTERM, then stop accepting and drain
+    let signal = wait_for_signal().await;
+    let in_flight = shutdown.active();
+    info!(
+        "Received {}, shutting down: draining {} connection(s) for up to {}s (press Ctrl-C again to force)",
+        signal,
+        in_flight,
+        state.current().config.shutdown_timeout
+    );
+    shutdown.trigger();
+
+    for task in tasks {
+        task.await?;
+    }
+
+    let grace = Duration::from_secs(state.current().config.shutdown_timeout);
+    let remaining = tokio::select! {
+        remaining = shutdown.drain(grace) => remaining,
+        _ = wait_for_signal() => {
+            warn!("Forced shutdown");
+            shutdown.active()
+        }
+    };
+
+    // Write out the entries of exchanges that just finished
+    if let Some(recorder) = state.recorder() {
+        recorder.flush().await;
+    }
+
+    if remaining > 0 {
+        warn!(
+            "Shutdown complete: {} connection(s) served, {} drained, {} closed after the grace period",
+            shutdown.accepted(),
+            in_flight.saturating_sub(remaining),
+            remaining
+        );
+    } else {
+        info!(
+            "Shutdown complete: {} connection(s) served, {} drained",
+            shutdown.accepted(),
+            in_flight
+        );
     }
+
+    Ok(())
 }
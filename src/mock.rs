@@ -0,0 +1,229 @@
+use bytes::Bytes;
+use http::{header, HeaderMap, HeaderValue, Method, Response, StatusCode};
+use http_body_util::Full;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::{error, warn};
+
+use crate::cors::error_response;
+use crate::rules::{rule_matches, HeaderKey, RuleContext, Template};
+
+/// Canned response for requests matching a method and URL pattern, served
+/// without contacting the upstream
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MockRule {
+    /// Target host, exact or `*.example.com`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Route alias name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    /// Request method (any when unset)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    /// Target URL pattern where `*` matches anything; the query is only
+    /// compared when the pattern has one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default = "default_status")]
+    pub status: u16,
+    /// Response headers, with `{placeholders}`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<HeaderKey, Template>,
+    /// Inline response body
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// File holding the response body, read for every response
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body_file: Option<PathBuf>,
+    /// Milliseconds to wait before responding
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub delay_ms: u64,
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+impl MockRule {
+    pub fn matches(&self, method: &Method, ctx: &RuleContext) -> bool {
+        let method_ok = self.method.as_deref().is_none_or(|m| m.eq_ignore_ascii_case(method.as_str()));
+        let url_ok = self.url.as_deref().is_none_or(|pattern| url_matches(pattern, &ctx.target_url));
+        method_ok && url_ok && rule_matches(self.host.as_deref(), self.route.as_deref(), ctx)
+    }
+
+    /// Check the status, method and body once the config is loaded
+    pub fn validate(&self) -> Result<(), String> {
+        StatusCode::from_u16(self.status).map_err(|_| format!("invalid status {}", self.status))?;
+        if let Some(method) = &self.method {
+            Method::from_bytes(method.as_bytes()).map_err(|_| format!("invalid method '{}'", method))?;
+        }
+        match (&self.body, &self.body_file) {
+            (Some(_), Some(_)) => Err("set either body or body_file, not both".to_string()),
+            (None, Some(path)) if !path.is_file() => Err(format!("body_file {} not found", path.display())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Build the canned response; headers are rendered from the request's
+    pub async fn respond(&self, ctx: &RuleContext, request_headers: &HeaderMap) -> Response<Full<Bytes>> {
+        if self.delay_ms > 0 {
+            tokio::time::sleep(Duration::from_millis(self.delay_ms)).await;
+        }
+
+        let body = match (&self.body, &self.body_file) {
+            (Some(body), _) => Bytes::from(body.clone()),
+            (None, Some(path)) => match tokio::fs::read(path).await {
+                Ok(body) => Bytes::from(body),
+                Err(e) => {
+                    error!("Failed to read mock body {}: {}", path.display(), e);
+                    return error_response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        &format!("Failed to read mock body {}", path.display()),
+                    );
+                }
+            },
+            (None, None) => Bytes::new(),
+        };
+
+        let mut response = Response::new(Full::new(body.clone()));
+        *response.status_mut() = StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK);
+        let headers = response.headers_mut();
+        if !body.is_empty() {
+            headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type(&body)));
+        }
+        for (name, template) in &self.headers {
+            match HeaderValue::from_str(&template.render(ctx, request_headers)) {
+                Ok(value) => {
+                    headers.insert(name.0.clone(), value);
+                }
+                Err(_) => warn!("Skipping mock header {}: invalid value", name.0),
+            }
+        }
+        response
+    }
+}
+
+/// The first rule matching the request, if any
+pub fn find_mock<'a>(rules: &'a [MockRule], method: &Method, ctx: &RuleContext) -> Option<&'a MockRule> {
+    rules.iter().find(|rule| rule.matches(method, ctx))
+}
+
+/// Content type for a body without one configured
+fn content_type(body: &[u8]) -> &'static str {
+    if serde_json::from_slice::<serde::de::IgnoredAny>(body).is_ok() {
+        "application/json"
+    } else if std::str::from_utf8(body).is_ok() {
+        "text/plain; charset=utf-8"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+/// Match a target URL against a pattern; the query is only compared when the pattern has one
+pub fn url_matches(pattern: &str, target_url: &str) -> bool {
+    let url = match pattern.contains('?') {
+        true => target_url,
+        false => target_url.split('?').next().unwrap_or_default(),
+    };
+    glob_matches(pattern, url)
+}
+
+/// Match text against a pattern where `*` matches any run of characters
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    fn ctx(url: &str) -> RuleContext {
+        RuleContext {
+            host: url::Url::parse(url).unwrap().host_str().unwrap().to_string(),
+            target_url: url.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_glob() {
+        assert!(glob_matches("https://api.example.com/users/*", "https://api.example.com/users/42"));
+        assert!(glob_matches("https://*/users/*/posts", "https://api.example.com/users/42/posts"));
+        assert!(!glob_matches("https://*/users/*/posts", "https://api.example.com/users/42/posts/1"));
+        assert!(glob_matches("*a*a", "aa"));
+        assert!(!glob_matches("*ab*ba", "aba"));
+        assert!(glob_matches("https://example.com/", "https://example.com/"));
+    }
+
+    #[test]
+    fn test_matches() {
+        let rule = MockRule {
+            method: Some("post".to_string()),
+            url: Some("https://api.example.com/orders".to_string()),
+            ..Default::default()
+        };
+        assert!(rule.matches(&Method::POST, &ctx("https://api.example.com/orders?page=2")));
+        assert!(!rule.matches(&Method::GET, &ctx("https://api.example.com/orders")));
+        assert!(!rule.matches(&Method::POST, &ctx("https://api.example.com/orders/1")));
+
+        let rule = MockRule {
+            url: Some("https://api.example.com/search?q=*".to_string()),
+            ..Default::default()
+        };
+        assert!(rule.matches(&Method::GET, &ctx("https://api.example.com/search?q=cats")));
+        assert!(!rule.matches(&Method::GET, &ctx("https://api.example.com/search")));
+    }
+
+    #[tokio::test]
+    async fn test_respond() {
+        let rule: MockRule = toml::from_str(
+            r#"
+            status = 201
+            body = '{"id": 1}'
+            headers = { "X-Origin" = "{origin}", "Content-Type" = "application/vnd.api+json" }
+            "#,
+        )
+        .unwrap();
+        rule.validate().unwrap();
+        let ctx = RuleContext {
+            origin: "http://localhost:3000".to_string(),
+            ..ctx("https://api.example.com/orders")
+        };
+        let response = rule.respond(&ctx, &HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()["x-origin"], "http://localhost:3000");
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/vnd.api+json");
+        assert_eq!(response.into_body().collect().await.unwrap().to_bytes(), r#"{"id": 1}"#);
+
+        let rule = MockRule {
+            body: Some("a".to_string()),
+            body_file: Some(PathBuf::from("Cargo.toml")),
+            ..Default::default()
+        };
+        assert!(rule.validate().is_err());
+    }
+}
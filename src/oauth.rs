@@ -0,0 +1,312 @@
+use bytes::Bytes;
+use http::{header, HeaderValue, Method, Request, StatusCode};
+use http_body_util::{BodyExt, Full};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::info;
+
+use crate::credentials::{Secret, SecretSource, Secrets};
+use crate::rules::{rule_matches, RuleContext};
+use crate::upstream::Upstream;
+
+/// Tokens are refreshed this long before they expire
+const EXPIRY_MARGIN: Duration = Duration::from_secs(30);
+
+/// Longest wait for a token endpoint
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How the access token is obtained
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Grant {
+    #[default]
+    ClientCredentials,
+    RefreshToken,
+}
+
+/// Fetch a bearer token from an OAuth2 token endpoint for matching upstreams
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct OAuth2Rule {
+    /// Target host, exact or `*.example.com`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Route alias name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    /// Token endpoint URL
+    pub token_url: String,
+    #[serde(default)]
+    pub grant: Grant,
+    pub client_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<SecretSource>,
+    /// Initial refresh token for the `refresh_token` grant
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<SecretSource>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audience: Option<String>,
+}
+
+impl OAuth2Rule {
+    pub fn matches(&self, ctx: &RuleContext) -> bool {
+        rule_matches(self.host.as_deref(), self.route.as_deref(), ctx)
+    }
+
+    /// Check the rule once the config is loaded
+    pub fn validate(&self, secrets: &Secrets) -> Result<(), String> {
+        let url = url::Url::parse(&self.token_url).map_err(|e| format!("invalid token_url: {}", e))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err("token_url must be an http(s) URL".to_string());
+        }
+        if self.grant == Grant::RefreshToken && self.refresh_token.is_none() {
+            return Err("the refresh_token grant requires refresh_token".to_string());
+        }
+        for source in self.client_secret.iter().chain(&self.refresh_token) {
+            source.resolve(secrets)?;
+        }
+        Ok(())
+    }
+
+    fn cache_key(&self) -> String {
+        format!("{} {} {}", self.token_url, self.client_id, self.scope.as_deref().unwrap_or_default())
+    }
+}
+
+/// A cached access token and the refresh token to renew it with
+struct Token {
+    access_token: Secret,
+    expires_at: Option<Instant>,
+    refresh_token: Option<Secret>,
+}
+
+impl Token {
+    fn is_fresh(&self) -> bool {
+        self.expires_at.is_none_or(|at| Instant::now() + EXPIRY_MARGIN < at)
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+    refresh_token: Option<String>,
+}
+
+/// Access tokens shared by all requests, keyed by endpoint, client and scope.
+/// Kept across config reloads, so rotated refresh tokens aren't lost.
+#[derive(Default)]
+pub struct TokenCache {
+    /// One slot per key, so a fetch only holds up requests needing the same token
+    slots: std::sync::Mutex<HashMap<String, Arc<Mutex<Option<Token>>>>>,
+}
+
+impl TokenCache {
+    fn slot(&self, key: String) -> Arc<Mutex<Option<Token>>> {
+        let mut slots = self.slots.lock().unwrap_or_else(|e| e.into_inner());
+        Arc::clone(slots.entry(key).or_default())
+    }
+
+    /// Get a valid access token, fetching or refreshing it when needed.
+    /// Concurrent requests for the same token wait for a single fetch.
+    pub async fn token(&self, rule: &OAuth2Rule, secrets: &Secrets, upstream: &Upstream) -> Result<Secret, String> {
+        let slot = self.slot(rule.cache_key());
+        let mut cached = slot.lock().await;
+
+        let refresh_token = match cached.as_ref() {
+            Some(token) if token.is_fresh() => return Ok(token.access_token.clone()),
+            Some(token) => token.refresh_token.clone(),
+            None => None,
+        };
+
+        let token = tokio::time::timeout(FETCH_TIMEOUT, fetch(rule, secrets, upstream, refresh_token))
+            .await
+            .map_err(|_| format!("token endpoint did not answer within {}s", FETCH_TIMEOUT.as_secs()))??;
+        let access_token = token.access_token.clone();
+        *cached = Some(token);
+        Ok(access_token)
+    }
+
+    /// Drop a token the upstream rejected; the refresh token is kept. A
+    /// token another request already replaced is left alone, so a burst of
+    /// rejections fetches one new token.
+    pub async fn invalidate(&self, rule: &OAuth2Rule, rejected: &Secret) {
+        if let Some(token) = self.slot(rule.cache_key()).lock().await.as_mut() {
+            if token.access_token == *rejected {
+                token.expires_at = Some(Instant::now());
+            }
+        }
+    }
+}
+
+/// Request a token from the endpoint
+async fn fetch(
+    rule: &OAuth2Rule,
+    secrets: &Secrets,
+    upstream: &Upstream,
+    cached_refresh_token: Option<Secret>,
+) -> Result<Token, String> {
+    let refresh_token = match rule.grant {
+        Grant::ClientCredentials => None,
+        Grant::RefreshToken => match cached_refresh_token {
+            Some(token) => Some(token),
+            None => rule.refresh_token.as_ref().map(|s| s.resolve(secrets)).transpose()?,
+        },
+    };
+    let client_secret = rule.client_secret.as_ref().map(|s| s.resolve(secrets)).transpose()?;
+
+    // The serializer isn't Send, so it must be dropped before awaiting
+    let body = {
+        let mut form = url::form_urlencoded::Serializer::new(String::new());
+        match &refresh_token {
+            Some(token) => form.append_pair("grant_type", "refresh_token").append_pair("refresh_token", token.expose()),
+            None => form.append_pair("grant_type", "client_credentials"),
+        };
+        form.append_pair("client_id", &rule.client_id);
+        if let Some(secret) = &client_secret {
+            form.append_pair("client_secret", secret.expose());
+        }
+        if let Some(scope) = &rule.scope {
+            form.append_pair("scope", scope);
+        }
+        if let Some(audience) = &rule.audience {
+            form.append_pair("audience", audience);
+        }
+        form.finish()
+    };
+
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(&rule.token_url)
+        .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .header(header::ACCEPT, "application/json")
+        .body(Full::new(Bytes::from(body)))
+        .map_err(|e| format!("invalid token request: {}", e))?;
+
+    let host = request.uri().host().unwrap_or_default().to_string();
+    let response = upstream
+        .client_for(&host)
+        .request(request)
+        .await
+        .map_err(|e| format!("token endpoint unreachable: {}", e))?;
+
+    // The body may echo credentials, so only the status is reported
+    let status = response.status();
+    if status != StatusCode::OK {
+        return Err(format!("token endpoint returned {}", status));
+    }
+    let body = response
+        .into_body()
+        .collect()
+        .await
+        .map_err(|e| format!("failed to read token response: {}", e))?
+        .to_bytes();
+    let parsed: TokenResponse =
+        serde_json::from_slice(&body).map_err(|_| "token endpoint returned an invalid response".to_string())?;
+
+    info!(
+        "Fetched OAuth2 token from {}{}",
+        rule.token_url,
+        parsed.expires_in.map(|s| format!(" (expires in {}s)", s)).unwrap_or_default()
+    );
+
+    Ok(Token {
+        access_token: Secret::new(parsed.access_token),
+        expires_at: parsed.expires_in.map(|s| Instant::now() + Duration::from_secs(s)),
+        // Keep the old refresh token unless the server rotated it
+        refresh_token: parsed.refresh_token.map(Secret::new).or(refresh_token),
+    })
+}
+
+/// `Authorization: Bearer <token>`, hidden from debug output
+pub fn bearer_header(token: &Secret) -> Option<HeaderValue> {
+    let mut value = HeaderValue::from_str(&format!("Bearer {}", token.expose())).ok()?;
+    value.set_sensitive(true);
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use clap::Parser;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper_util::rt::TokioIo;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Token server issuing `token-N` and rotating `refresh-N`, recording request bodies
+    async fn mock_token_server() -> (String, Arc<std::sync::Mutex<Vec<String>>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/token", listener.local_addr().unwrap());
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let count = Arc::new(AtomicUsize::new(0));
+
+        let seen = Arc::clone(&requests);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (seen, count) = (Arc::clone(&seen), Arc::clone(&count));
+                let service = service_fn(move |req: Request<hyper::body::Incoming>| {
+                    let (seen, count) = (Arc::clone(&seen), Arc::clone(&count));
+                    async move {
+                        let body = req.into_body().collect().await?.to_bytes();
+                        seen.lock().unwrap().push(String::from_utf8_lossy(&body).into_owned());
+                        let n = count.fetch_add(1, Ordering::SeqCst) + 1;
+                        let json = format!(
+                            r#"{{"access_token":"token-{n}","expires_in":3600,"refresh_token":"refresh-{n}"}}"#
+                        );
+                        Ok::<_, hyper::Error>(hyper::Response::new(Full::new(Bytes::from(json))))
+                    }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+
+        (url, requests)
+    }
+
+    #[tokio::test]
+    async fn test_token_cache_and_refresh() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let (token_url, requests) = mock_token_server().await;
+        let upstream =
+            Upstream::from_config(&Config::parse_from(["holy-cors", "--bundled-roots"]), Arc::default(), None).unwrap();
+        let rule = OAuth2Rule {
+            token_url,
+            grant: Grant::RefreshToken,
+            client_id: "app".to_string(),
+            refresh_token: Some(SecretSource::Secret("refresh".to_string())),
+            ..OAuth2Rule::default()
+        };
+        let secrets: Secrets = [("refresh", "initial")].into_iter().collect();
+        let cache = TokenCache::default();
+
+        let first = cache.token(&rule, &secrets, &upstream).await.unwrap();
+        assert_eq!(first.expose(), "token-1");
+        assert_eq!(cache.token(&rule, &secrets, &upstream).await.unwrap().expose(), "token-1");
+
+        cache.invalidate(&rule, &first).await;
+        // A fetch in progress for another token doesn't hold this one up
+        let other = OAuth2Rule {
+            client_id: "other".to_string(),
+            ..rule.clone()
+        };
+        let _fetching = cache.slot(other.cache_key()).lock_owned().await;
+        assert_eq!(cache.token(&rule, &secrets, &upstream).await.unwrap().expose(), "token-2");
+
+        // A late rejection of the old token keeps the new one
+        cache.invalidate(&rule, &first).await;
+        assert_eq!(cache.token(&rule, &secrets, &upstream).await.unwrap().expose(), "token-2");
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].contains("grant_type=refresh_token&refresh_token=initial"));
+        // The rotated refresh token is used for the next refresh
+        assert!(requests[1].contains("refresh_token=refresh-1"));
+    }
+}
@@ -0,0 +1,873 @@
+use bytes::Bytes;
+use http::{header, HeaderMap, HeaderValue, Method, Response, StatusCode};
+use http_body_util::Full;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::fmt;
+use std::path::PathBuf;
+use tracing::warn;
+use url::Url;
+
+use crate::cors::error_response;
+use crate::rules::{rule_matches, RuleContext};
+
+/// Operation methods in a path item
+const METHODS: &[&str] = &["get", "put", "post", "delete", "options", "head", "patch", "trace"];
+
+/// Operation extension that marks it to be answered with its example
+const MOCK_EXTENSION: &str = "x-holy-cors-mock";
+
+/// How far validation follows nested schemas and `$ref`s
+const MAX_DEPTH: usize = 64;
+
+/// How deep generated examples go (recursive schemas end in `null`)
+const MAX_EXAMPLE_DEPTH: usize = 8;
+
+/// What to do when a request or response breaks the contract
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Validate {
+    Off,
+    /// Log violations and proxy as usual
+    #[default]
+    Log,
+    /// Answer 400 for bad requests and 502 for bad responses
+    Reject,
+}
+
+/// An OpenAPI 3 document describing the upstreams a rule matches
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct OpenApiRule {
+    /// Target host, exact or `*.example.com`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Route alias name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    /// OpenAPI document (YAML or JSON)
+    pub spec: PathBuf,
+    #[serde(default)]
+    pub validate: Validate,
+    /// Operations answered with examples instead of the upstream:
+    /// operationIds or `METHOD /path`, `*` for all
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mock: Vec<String>,
+}
+
+impl OpenApiRule {
+    pub fn matches(&self, ctx: &RuleContext) -> bool {
+        rule_matches(self.host.as_deref(), self.route.as_deref(), ctx)
+    }
+}
+
+/// The loaded OpenAPI documents, in rule order
+#[derive(Default)]
+pub struct Contracts(Vec<Contract>);
+
+struct Contract {
+    rule: OpenApiRule,
+    doc: Value,
+    /// Path prefixes from `servers`, longest first
+    base_paths: Vec<String>,
+    /// Path templates, concrete ones first
+    paths: Vec<PathTemplate>,
+}
+
+struct PathTemplate {
+    key: String,
+    regex: Regex,
+    params: Vec<String>,
+}
+
+impl Contracts {
+    /// Read and index the documents of all rules
+    pub fn load(rules: &[OpenApiRule]) -> Result<Self, String> {
+        rules.iter().map(Contract::load).collect::<Result<Vec<_>, _>>().map(Contracts)
+    }
+
+    /// The operation of the first document describing a request, if any
+    pub fn find(&self, method: &Method, ctx: &RuleContext) -> Option<Operation<'_>> {
+        let url = Url::parse(&ctx.target_url).ok()?;
+        let method = method.as_str().to_ascii_lowercase();
+        if !METHODS.contains(&method.as_str()) {
+            return None;
+        }
+        self.0
+            .iter()
+            .filter(|contract| contract.rule.matches(ctx))
+            .find_map(|contract| contract.operation(&method, url.path()))
+    }
+
+    /// Whether responses for a request will be validated
+    pub fn validates(&self, ctx: &RuleContext) -> bool {
+        self.0.iter().any(|c| c.rule.validate != Validate::Off && c.rule.matches(ctx))
+    }
+}
+
+impl Contract {
+    fn load(rule: &OpenApiRule) -> Result<Self, String> {
+        let path = &rule.spec;
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read OpenAPI document {}: {}", path.display(), e))?;
+        // YAML is a superset of JSON, so this reads both
+        let doc: Value = serde_yaml::from_str(&text)
+            .map_err(|e| format!("Invalid OpenAPI document {}: {}", path.display(), e))?;
+        if !doc["openapi"].as_str().is_some_and(|v| v.starts_with("3.")) {
+            return Err(format!("{} is not an OpenAPI 3 document", path.display()));
+        }
+
+        let mut base_paths: Vec<String> = doc["servers"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(server_path)
+            .collect();
+        base_paths.push(String::new());
+        base_paths.sort_by_key(|p| std::cmp::Reverse(p.len()));
+        base_paths.dedup();
+
+        let mut paths = Vec::new();
+        for key in doc["paths"].as_object().into_iter().flat_map(|paths| paths.keys()) {
+            let mut pattern = String::from("^");
+            let mut params = Vec::new();
+            let mut rest = key.as_str();
+            while let Some(start) = rest.find('{') {
+                let Some(len) = rest[start..].find('}') else {
+                    break;
+                };
+                pattern.push_str(&regex::escape(&rest[..start]));
+                pattern.push_str("([^/]+)");
+                params.push(rest[start + 1..start + len].to_string());
+                rest = &rest[start + len + 1..];
+            }
+            pattern.push_str(&regex::escape(rest));
+            pattern.push('$');
+            let regex = Regex::new(&pattern).map_err(|e| format!("Invalid path {} in {}: {}", key, path.display(), e))?;
+            paths.push(PathTemplate {
+                key: key.clone(),
+                regex,
+                params,
+            });
+        }
+        paths.sort_by_key(|template| template.params.len());
+
+        Ok(Self {
+            rule: rule.clone(),
+            doc,
+            base_paths,
+            paths,
+        })
+    }
+
+    fn operation(&self, method: &str, path: &str) -> Option<Operation<'_>> {
+        for base in &self.base_paths {
+            let Some(rest) = path.strip_prefix(base.as_str()) else {
+                continue;
+            };
+            if !rest.starts_with('/') {
+                continue;
+            }
+            for template in &self.paths {
+                let Some(captures) = template.regex.captures(rest) else {
+                    continue;
+                };
+                let item = &self.doc["paths"][&template.key];
+                if !item[method].is_object() {
+                    continue;
+                }
+                let path_params = template
+                    .params
+                    .iter()
+                    .zip(captures.iter().skip(1))
+                    .map(|(name, value)| (name.clone(), value.map_or("", |m| m.as_str()).to_string()))
+                    .collect();
+                return Some(Operation {
+                    contract: self,
+                    path: &template.key,
+                    method: method.to_string(),
+                    item,
+                    op: &item[method],
+                    path_params,
+                });
+            }
+        }
+        None
+    }
+}
+
+/// The path part of a server URL, with variables set to their defaults
+fn server_path(server: &Value) -> Option<String> {
+    let mut url = server["url"].as_str()?.to_string();
+    for (name, variable) in server["variables"].as_object().into_iter().flatten() {
+        let default = variable["default"].as_str().unwrap_or_default();
+        url = url.replace(&format!("{{{}}}", name), default);
+    }
+    let path = match Url::parse(&url) {
+        Ok(url) => url.path().to_string(),
+        Err(_) => url,
+    };
+    Some(path.trim_end_matches('/').to_string())
+}
+
+/// A documented operation matched by a request
+pub struct Operation<'a> {
+    contract: &'a Contract,
+    path: &'a str,
+    method: String,
+    item: &'a Value,
+    op: &'a Value,
+    path_params: Vec<(String, String)>,
+}
+
+impl fmt::Display for Operation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.method.to_ascii_uppercase(), self.path)
+    }
+}
+
+impl Operation<'_> {
+    pub fn validate(&self) -> Validate {
+        self.contract.rule.validate
+    }
+
+    /// Whether the operation is answered with its example
+    pub fn mocked(&self) -> bool {
+        let id = self.op["operationId"].as_str();
+        self.op[MOCK_EXTENSION] == Value::Bool(true)
+            || self.contract.rule.mock.iter().any(|mock| {
+                mock == "*" || Some(mock.as_str()) == id || mock.eq_ignore_ascii_case(&self.to_string())
+            })
+    }
+
+    /// Check parameters and the body of a request
+    pub fn validate_request(&self, target_url: &str, headers: &HeaderMap, body: &[u8]) -> Vec<String> {
+        let mut validator = Validator::new(&self.contract.doc);
+        if self.validate() == Validate::Off {
+            return validator.errors;
+        }
+
+        let query: Vec<(String, String)> = Url::parse(target_url)
+            .map(|url| url.query_pairs().into_owned().collect())
+            .unwrap_or_default();
+        for param in self.parameters() {
+            let (Some(name), Some(location)) = (param["name"].as_str(), param["in"].as_str()) else {
+                continue;
+            };
+            let values: Vec<String> = match location {
+                "path" => self.path_params.iter().filter(|(n, _)| n == name).map(|(_, v)| v.clone()).collect(),
+                "query" => query.iter().filter(|(n, _)| n == name).map(|(_, v)| v.clone()).collect(),
+                "header" => headers
+                    .get_all(name)
+                    .iter()
+                    .filter_map(|v| v.to_str().ok())
+                    .map(str::to_string)
+                    .collect(),
+                _ => continue,
+            };
+            validator.what = format!("{} parameter '{}'", location, name);
+            if values.is_empty() {
+                if param["required"] == Value::Bool(true) {
+                    validator.error("", "is required");
+                }
+                continue;
+            }
+            let value = validator.coerce(&param["schema"], &values);
+            validator.check(&param["schema"], &value, "", 0);
+        }
+
+        let request_body = validator.resolve(&self.op["requestBody"]);
+        if request_body.is_object() {
+            validator.what = "request body".to_string();
+            if body.is_empty() {
+                if request_body["required"] == Value::Bool(true) {
+                    validator.error("", "is required");
+                }
+            } else {
+                validator.check_content(&request_body["content"], headers, body);
+            }
+        }
+        validator.errors
+    }
+
+    /// Check the status and (JSON) body of a response
+    pub fn validate_response(&self, status: StatusCode, headers: &HeaderMap, body: &[u8]) -> Vec<String> {
+        let mut validator = Validator::new(&self.contract.doc);
+        if self.validate() == Validate::Off {
+            return validator.errors;
+        }
+
+        let responses = &self.op["responses"];
+        let code = status.as_u16().to_string();
+        let declared = [code.clone(), format!("{}XX", &code[..1]), format!("{}xx", &code[..1]), "default".to_string()]
+            .iter()
+            .find_map(|key| responses.get(key));
+        let Some(declared) = declared else {
+            validator.what = "response".to_string();
+            validator.error("", format!("status {} is not documented", code));
+            return validator.errors;
+        };
+        if !body.is_empty() {
+            validator.what = "response body".to_string();
+            let declared = validator.resolve(declared);
+            validator.check_content(&declared["content"], headers, body);
+        }
+        validator.errors
+    }
+
+    /// Log violations; with `validate = "reject"` also build the error response
+    pub fn report(&self, violations: &[String], status: StatusCode) -> Option<Response<Full<Bytes>>> {
+        if violations.is_empty() {
+            return None;
+        }
+        for violation in violations {
+            warn!("{} violates the OpenAPI contract: {}", self, violation);
+        }
+        (self.validate() == Validate::Reject).then(|| {
+            let message = format!("{} violates the OpenAPI contract: {}", self, violations.join("; "));
+            error_response(status, &message.replace(['"', '\\'], "'"))
+        })
+    }
+
+    /// A response built from the first documented success response and its example
+    pub fn example(&self) -> Response<Full<Bytes>> {
+        let doc = &self.contract.doc;
+        let responses = self.op["responses"].as_object();
+        let mut documented: Vec<(StatusCode, &Value)> = responses
+            .into_iter()
+            .flatten()
+            .filter_map(|(key, response)| {
+                let status = match key.as_str() {
+                    "default" => StatusCode::OK,
+                    key if key.len() == 3 && key[1..].eq_ignore_ascii_case("XX") => {
+                        StatusCode::from_u16(key[..1].parse::<u16>().ok()? * 100).ok()?
+                    }
+                    key => key.parse().ok()?,
+                };
+                Some((status, response))
+            })
+            .collect();
+        documented.sort_by_key(|(status, _)| (!status.is_success(), status.as_u16()));
+
+        let Some((status, response)) = documented.first() else {
+            return Response::new(Full::new(Bytes::new()));
+        };
+        let response = resolve(doc, response);
+        let mut builder = Response::builder().status(*status);
+
+        let content = response["content"].as_object();
+        let media = content.and_then(|content| {
+            content
+                .iter()
+                .find(|(mime, _)| is_json(mime))
+                .or_else(|| content.iter().next())
+        });
+        let body = match media {
+            Some((mime, media)) => {
+                let mime = if mime.contains('*') { "application/json" } else { mime.as_str() };
+                if let Ok(value) = HeaderValue::from_str(mime) {
+                    builder = builder.header(header::CONTENT_TYPE, value);
+                }
+                match media_example(doc, media) {
+                    Value::String(text) if !is_json(mime) => Bytes::from(text),
+                    example => Bytes::from(example.to_string()),
+                }
+            }
+            None => Bytes::new(),
+        };
+        builder.body(Full::new(body)).unwrap_or_default()
+    }
+
+    /// Path-level parameters, overridden by operation-level ones
+    fn parameters(&self) -> Vec<&Value> {
+        let doc = &self.contract.doc;
+        let mut params: Vec<&Value> = Vec::new();
+        let declared = self.item["parameters"].as_array().into_iter().chain(self.op["parameters"].as_array());
+        for param in declared.flatten().map(|p| resolve(doc, p)) {
+            params.retain(|p| p["name"] != param["name"] || p["in"] != param["in"]);
+            params.push(param);
+        }
+        params
+    }
+}
+
+/// Follow local `$ref`s (`#/components/...`)
+fn resolve<'a>(doc: &'a Value, mut value: &'a Value) -> &'a Value {
+    for _ in 0..MAX_DEPTH {
+        match value["$ref"].as_str().and_then(|r| r.strip_prefix('#')) {
+            Some(pointer) => value = doc.pointer(pointer).unwrap_or(&Value::Null),
+            None => break,
+        }
+    }
+    value
+}
+
+fn is_json(mime: &str) -> bool {
+    let essence = mime.split(';').next().unwrap_or_default().trim();
+    essence.eq_ignore_ascii_case("application/json") || essence.ends_with("+json")
+}
+
+/// The declared type of a schema, ignoring `null`
+fn schema_type(schema: &Value) -> Option<&str> {
+    match &schema["type"] {
+        Value::String(t) => Some(t),
+        Value::Array(types) => types.iter().filter_map(Value::as_str).find(|t| *t != "null"),
+        _ => schema.get("properties").map(|_| "object"),
+    }
+}
+
+/// Example of a media type: its own, the first of its `examples`, or one
+/// generated from its schema
+fn media_example(doc: &Value, media: &Value) -> Value {
+    if let Some(example) = media.get("example") {
+        return example.clone();
+    }
+    if let Some((_, example)) = media["examples"].as_object().and_then(|examples| examples.iter().next()) {
+        return resolve(doc, example)["value"].clone();
+    }
+    generate(doc, &media["schema"], 0)
+}
+
+/// A value satisfying a schema, preferring its examples and defaults
+fn generate(doc: &Value, schema: &Value, depth: usize) -> Value {
+    let schema = resolve(doc, schema);
+    if depth > MAX_EXAMPLE_DEPTH {
+        return Value::Null;
+    }
+    for key in ["example", "default", "const"] {
+        if let Some(value) = schema.get(key) {
+            return value.clone();
+        }
+    }
+    if let Some(value) = schema["examples"].get(0).or_else(|| schema["enum"].get(0)) {
+        return value.clone();
+    }
+    if let Some(all) = schema["allOf"].as_array() {
+        let mut merged = Map::new();
+        for part in all {
+            if let Value::Object(fields) = generate(doc, part, depth + 1) {
+                merged.extend(fields);
+            }
+        }
+        if let Value::Object(fields) = generate(doc, &json!({ "properties": schema["properties"] }), depth + 1) {
+            merged.extend(fields);
+        }
+        return Value::Object(merged);
+    }
+    if let Some(first) = schema["oneOf"].get(0).or_else(|| schema["anyOf"].get(0)) {
+        return generate(doc, first, depth + 1);
+    }
+
+    match schema_type(schema) {
+        Some("object") => Value::Object(
+            schema["properties"]
+                .as_object()
+                .into_iter()
+                .flatten()
+                .map(|(name, property)| (name.clone(), generate(doc, property, depth + 1)))
+                .collect(),
+        ),
+        Some("array") => match schema.get("items") {
+            Some(items) => json!([generate(doc, items, depth + 1)]),
+            None => json!([]),
+        },
+        Some("string") => json!(match schema["format"].as_str() {
+            Some("date") => "2024-01-01",
+            Some("date-time") => "2024-01-01T00:00:00Z",
+            Some("email") => "user@example.com",
+            Some("uuid") => "00000000-0000-0000-0000-000000000000",
+            Some("uri") | Some("url") => "https://example.com/",
+            _ => "string",
+        }),
+        Some("integer") | Some("number") => schema.get("minimum").cloned().unwrap_or(json!(0)),
+        Some("boolean") => json!(false),
+        _ => Value::Null,
+    }
+}
+
+/// Collects the ways a value breaks a schema
+struct Validator<'a> {
+    doc: &'a Value,
+    /// What is being validated, for messages
+    what: String,
+    errors: Vec<String>,
+}
+
+impl<'a> Validator<'a> {
+    fn new(doc: &'a Value) -> Self {
+        Self {
+            doc,
+            what: String::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    fn resolve(&self, value: &'a Value) -> &'a Value {
+        resolve(self.doc, value)
+    }
+
+    fn error(&mut self, pointer: &str, message: impl fmt::Display) {
+        if pointer.is_empty() {
+            self.errors.push(format!("{} {}", self.what, message));
+        } else {
+            self.errors.push(format!("{} at {} {}", self.what, pointer, message));
+        }
+    }
+
+    /// Check a body against the schema of its declared media type
+    fn check_content(&mut self, content: &'a Value, headers: &HeaderMap, body: &[u8]) {
+        let Some(content) = content.as_object() else {
+            return;
+        };
+        let mime = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(';').next())
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let media = content.get(&mime).or_else(|| {
+            content
+                .iter()
+                .find(|(key, _)| key.as_str() == "*/*" || key.strip_suffix('*').is_some_and(|prefix| mime.starts_with(prefix)))
+                .map(|(_, media)| media)
+        });
+        let Some(media) = media else {
+            self.error("", format!("has undocumented content type '{}'", mime));
+            return;
+        };
+        if !is_json(&mime) || media.get("schema").is_none() {
+            return;
+        }
+        match serde_json::from_slice::<Value>(body) {
+            Ok(value) => self.check(&media["schema"], &value, "", 0),
+            Err(_) => self.error("", "is not valid JSON"),
+        }
+    }
+
+    /// Parameter values as the types their schema expects
+    fn coerce(&self, schema: &Value, values: &[String]) -> Value {
+        let schema = self.resolve(schema);
+        if schema_type(schema) == Some("array") {
+            let items = self.resolve(&schema["items"]);
+            let values: Vec<&str> = match values {
+                [single] => single.split(',').collect(),
+                values => values.iter().map(String::as_str).collect(),
+            };
+            return values.into_iter().map(|v| scalar(items, v)).collect();
+        }
+        scalar(schema, &values[0])
+    }
+
+    fn check(&mut self, schema: &'a Value, value: &Value, pointer: &str, depth: usize) {
+        let schema = self.resolve(schema);
+        if depth > MAX_DEPTH || !schema.is_object() {
+            return;
+        }
+
+        let types: Vec<&str> = match &schema["type"] {
+            Value::String(t) => vec![t.as_str()],
+            Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if value.is_null() && (schema["nullable"] == Value::Bool(true) || types.contains(&"null")) {
+            return;
+        }
+        if !types.is_empty() && !types.iter().any(|t| type_matches(t, value)) {
+            self.error(pointer, format!("should be {}, not {}", types.join(" or "), kind(value)));
+            return;
+        }
+        if schema["enum"].as_array().is_some_and(|values| !values.contains(value)) {
+            self.error(pointer, "is not one of the allowed values");
+        }
+        if schema.get("const").is_some_and(|c| c != value) {
+            self.error(pointer, "does not equal the required constant");
+        }
+
+        match value {
+            Value::String(s) => {
+                let len = s.chars().count() as u64;
+                if schema["minLength"].as_u64().is_some_and(|min| len < min) {
+                    self.error(pointer, format!("is shorter than {} characters", schema["minLength"]));
+                }
+                if schema["maxLength"].as_u64().is_some_and(|max| len > max) {
+                    self.error(pointer, format!("is longer than {} characters", schema["maxLength"]));
+                }
+                let pattern = schema["pattern"].as_str().and_then(|p| Regex::new(p).ok());
+                if pattern.is_some_and(|re| !re.is_match(s)) {
+                    self.error(pointer, "does not match the pattern");
+                }
+            }
+            Value::Number(n) => {
+                let n = n.as_f64().unwrap_or_default();
+                // OpenAPI 3.0 has boolean exclusive bounds, 3.1 numeric ones
+                if let Some(min) = schema["minimum"].as_f64() {
+                    if n < min || (n == min && schema["exclusiveMinimum"] == Value::Bool(true)) {
+                        self.error(pointer, format!("is below the minimum {}", min));
+                    }
+                }
+                if schema["exclusiveMinimum"].as_f64().is_some_and(|min| n <= min) {
+                    self.error(pointer, format!("is not above {}", schema["exclusiveMinimum"]));
+                }
+                if let Some(max) = schema["maximum"].as_f64() {
+                    if n > max || (n == max && schema["exclusiveMaximum"] == Value::Bool(true)) {
+                        self.error(pointer, format!("is above the maximum {}", max));
+                    }
+                }
+                if schema["exclusiveMaximum"].as_f64().is_some_and(|max| n >= max) {
+                    self.error(pointer, format!("is not below {}", schema["exclusiveMaximum"]));
+                }
+            }
+            Value::Array(items) => {
+                let len = items.len() as u64;
+                if schema["minItems"].as_u64().is_some_and(|min| len < min) {
+                    self.error(pointer, format!("has fewer than {} items", schema["minItems"]));
+                }
+                if schema["maxItems"].as_u64().is_some_and(|max| len > max) {
+                    self.error(pointer, format!("has more than {} items", schema["maxItems"]));
+                }
+                if let Some(item_schema) = schema.get("items") {
+                    for (i, item) in items.iter().enumerate() {
+                        self.check(item_schema, item, &format!("{}/{}", pointer, i), depth + 1);
+                    }
+                }
+            }
+            Value::Object(fields) => {
+                for name in schema["required"].as_array().into_iter().flatten().filter_map(Value::as_str) {
+                    if !fields.contains_key(name) {
+                        self.error(pointer, format!("is missing required property '{}'", name));
+                    }
+                }
+                let properties = schema["properties"].as_object();
+                for (name, field) in fields {
+                    let field_pointer = format!("{}/{}", pointer, name);
+                    match (properties.and_then(|p| p.get(name)), schema.get("additionalProperties")) {
+                        (Some(property), _) => self.check(property, field, &field_pointer, depth + 1),
+                        (None, Some(Value::Bool(false))) => self.error(&field_pointer, "is not an allowed property"),
+                        (None, Some(additional)) => self.check(additional, field, &field_pointer, depth + 1),
+                        (None, None) => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        for part in schema["allOf"].as_array().into_iter().flatten() {
+            self.check(part, value, pointer, depth + 1);
+        }
+        if let Some(any) = schema["anyOf"].as_array() {
+            if !any.iter().any(|part| self.valid(part, value, depth + 1)) {
+                self.error(pointer, "matches none of the anyOf schemas");
+            }
+        }
+        if let Some(one) = schema["oneOf"].as_array() {
+            let matched = one.iter().filter(|part| self.valid(part, value, depth + 1)).count();
+            if matched != 1 {
+                self.error(pointer, format!("matches {} of the oneOf schemas instead of one", matched));
+            }
+        }
+        if schema.get("not").is_some_and(|not| self.valid(not, value, depth + 1)) {
+            self.error(pointer, "matches a schema it must not");
+        }
+    }
+
+    fn valid(&self, schema: &'a Value, value: &Value, depth: usize) -> bool {
+        let mut validator = Validator::new(self.doc);
+        validator.check(schema, value, "", depth);
+        validator.errors.is_empty()
+    }
+}
+
+/// A parameter value as the scalar type its schema expects, left a string
+/// when it doesn't parse
+fn scalar(schema: &Value, value: &str) -> Value {
+    let parsed = match schema_type(schema) {
+        Some("integer") => value.parse::<i64>().ok().map(Value::from),
+        Some("number") => value.parse::<f64>().ok().and_then(serde_json::Number::from_f64).map(Value::Number),
+        Some("boolean") => value.parse::<bool>().ok().map(Value::Bool),
+        _ => None,
+    };
+    parsed.unwrap_or_else(|| Value::String(value.to_string()))
+}
+
+fn type_matches(expected: &str, value: &Value) -> bool {
+    match expected {
+        "integer" => value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|n| n.fract() == 0.0),
+        "number" => value.is_number(),
+        "string" => value.is_string(),
+        "boolean" => value.is_boolean(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_i64() || n.is_u64() => "integer",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    const SPEC: &str = r##"
+openapi: 3.0.3
+servers:
+  - url: https://api.example.com/v1
+paths:
+  /users/{id}:
+    parameters:
+      - name: id
+        in: path
+        required: true
+        schema: { type: integer }
+    get:
+      operationId: getUser
+      responses:
+        "200":
+          description: A user
+          content:
+            application/json:
+              schema: { $ref: "#/components/schemas/User" }
+        "404":
+          description: Not found
+  /users:
+    post:
+      parameters:
+        - name: dry_run
+          in: query
+          schema: { type: boolean }
+      requestBody:
+        required: true
+        content:
+          application/json:
+            schema: { $ref: "#/components/schemas/User" }
+      responses:
+        "201":
+          description: Created
+          content:
+            application/json:
+              example: { id: 7, name: Ada }
+components:
+  schemas:
+    User:
+      type: object
+      required: [id, name]
+      additionalProperties: false
+      properties:
+        id: { type: integer, minimum: 1 }
+        name: { type: string, minLength: 1 }
+        email: { type: string, format: email, nullable: true }
+        roles:
+          type: array
+          items: { type: string, enum: [admin, member] }
+"##;
+
+    fn contracts(mock: &[&str]) -> Contracts {
+        let path = std::env::temp_dir().join(format!("holy-cors-openapi-{}-{}.yaml", std::process::id(), mock.len()));
+        std::fs::write(&path, SPEC).unwrap();
+        let rule = OpenApiRule {
+            spec: path.clone(),
+            mock: mock.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        };
+        let contracts = Contracts::load(&[rule]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        contracts
+    }
+
+    fn ctx(url: &str) -> RuleContext {
+        RuleContext {
+            target_url: url.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn json_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        headers
+    }
+
+    #[test]
+    fn test_find() {
+        let contracts = contracts(&[]);
+        let op = contracts.find(&Method::GET, &ctx("https://api.example.com/v1/users/42")).unwrap();
+        assert_eq!(op.to_string(), "GET /users/{id}");
+        assert_eq!(op.path_params, vec![("id".to_string(), "42".to_string())]);
+        assert!(contracts.find(&Method::DELETE, &ctx("https://api.example.com/v1/users/42")).is_none());
+        assert!(contracts.find(&Method::GET, &ctx("https://api.example.com/v1/teams")).is_none());
+    }
+
+    #[test]
+    fn test_validate_request() {
+        let contracts = contracts(&[]);
+        let url = "https://api.example.com/v1/users?dry_run=maybe";
+        let op = contracts.find(&Method::POST, &ctx(url)).unwrap();
+        let body = br#"{"id": 0, "name": "Ada", "email": null, "roles": ["admin", "root"], "age": 3}"#;
+        assert_eq!(
+            op.validate_request(url, &json_headers(), body),
+            vec![
+                "query parameter 'dry_run' should be boolean, not string",
+                "request body at /age is not an allowed property",
+                "request body at /id is below the minimum 1",
+                "request body at /roles/1 is not one of the allowed values",
+            ]
+        );
+        assert_eq!(op.validate_request(url.split('?').next().unwrap(), &json_headers(), b""), vec!["request body is required"]);
+
+        let url = "https://api.example.com/v1/users/abc";
+        let op = contracts.find(&Method::GET, &ctx(url)).unwrap();
+        assert_eq!(op.validate_request(url, &HeaderMap::new(), b""), vec!["path parameter 'id' should be integer, not string"]);
+    }
+
+    #[test]
+    fn test_validate_response() {
+        let contracts = contracts(&[]);
+        let op = contracts.find(&Method::GET, &ctx("https://api.example.com/v1/users/1")).unwrap();
+        assert!(op.validate_response(StatusCode::OK, &json_headers(), br#"{"id": 1, "name": "Ada"}"#).is_empty());
+        assert!(op.validate_response(StatusCode::NOT_FOUND, &HeaderMap::new(), b"").is_empty());
+        assert_eq!(
+            op.validate_response(StatusCode::OK, &json_headers(), br#"{"id": "1"}"#),
+            vec![
+                "response body is missing required property 'name'",
+                "response body at /id should be integer, not string",
+            ]
+        );
+        assert_eq!(
+            op.validate_response(StatusCode::INTERNAL_SERVER_ERROR, &HeaderMap::new(), b""),
+            vec!["response status 500 is not documented"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_example() {
+        let contracts = contracts(&["getUser", "post /users"]);
+        let op = contracts.find(&Method::GET, &ctx("https://api.example.com/v1/users/1")).unwrap();
+        assert!(op.mocked());
+        let response = op.example();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let user: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(user, json!({ "id": 1, "name": "string", "email": "user@example.com", "roles": ["admin"] }));
+        assert!(op.validate_response(StatusCode::OK, &json_headers(), &body).is_empty());
+
+        let op = contracts.find(&Method::POST, &ctx("https://api.example.com/v1/users")).unwrap();
+        assert!(op.mocked());
+        let response = op.example();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap(), json!({ "id": 7, "name": "Ada" }));
+    }
+}
@@ -1,17 +1,30 @@
 use bytes::Bytes;
-use http::{header, HeaderMap, Request, Response, StatusCode, Uri};
+use http::{header, HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri};
 use http_body_util::{combinators::BoxBody, BodyExt, Full};
 use hyper::body::Incoming;
-use hyper_rustls::HttpsConnectorBuilder;
-use hyper_util::client::legacy::Client;
-use hyper_util::rt::TokioExecutor;
 use std::sync::Arc;
-use tokio_tungstenite::connect_async;
 use tracing::{debug, error, info, warn};
 use url::Url;
 
+use crate::admin::{Admin, ADMIN_PREFIX};
 use crate::config::Config;
+use crate::cookies::{merge_cookie_header, rewrite_set_cookies, CookieJar, SESSION_HEADER};
 use crate::cors::{add_cors_headers, check_origin, error_response, handle_preflight, is_preflight, success_response};
+use crate::credentials::apply_credentials;
+use crate::fault::FaultPlan;
+use crate::har::Recorder;
+use crate::mock::{find_mock, MockRule};
+use crate::oauth::bearer_header;
+use crate::openapi::{Operation, Validate};
+use crate::redirect::{redirect_method, rewrite_locations, strip_redirect_headers, RedirectPolicy};
+use crate::replay::ReplayMode;
+use crate::rewrite::{ContentKind, RewriteBody, UrlRewriter};
+use crate::rules::{apply_header_rules, RuleContext};
+use crate::shutdown::Shutdown;
+use crate::transform::{apply_body_rules, read_body, BodyKind, Buffered, MAX_BODY_SIZE};
+use crate::unix::{self, UnixTarget, UNIX_PREFIX};
+use crate::upstream::Upstream;
+use crate::websocket::{handle_websocket, is_websocket_upgrade};
 
 /// Headers that should not be forwarded to the target
 const HOP_BY_HOP_HEADERS: &[&str] = &[
@@ -35,10 +48,23 @@ const SKIP_RESPONSE_HEADERS: &[&str] = &[
     "content-length",
 ];
 
+/// Where a proxied request is sent
+enum Target {
+    /// An http(s) URL
+    Url(String),
+    /// An HTTP server on a Unix domain socket
+    Unix(UnixTarget),
+}
+
 /// Main proxy request handler
 pub async fn handle_request(
     req: Request<Incoming>,
     config: Arc<Config>,
+    upstream: Arc<Upstream>,
+    cookies: Arc<CookieJar>,
+    recorder: Option<Arc<Recorder>>,
+    admin: Arc<Admin>,
+    shutdown: Arc<Shutdown>,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
     let method = req.method().clone();
     let headers = req.headers().clone();
@@ -58,6 +84,13 @@ pub async fn handle_request(
         return Ok(handle_preflight(&origin, &headers).map(|b| b.map_err(|_| unreachable!()).boxed()));
     }
 
+    // Serve the admin API
+    if uri.path().starts_with(ADMIN_PREFIX) {
+        let mut response = admin.handle(req, &config, &cookies, recorder.as_ref()).await;
+        add_cors_headers(response.headers_mut(), &origin, &headers);
+        return Ok(response.map(|b| b.map_err(|_| unreachable!()).boxed()));
+    }
+
     // Handle root path - return welcome message
     let path = uri.path();
     if path == "/" || path.is_empty() {
@@ -65,8 +98,13 @@ pub async fn handle_request(
             .map(|b| b.map_err(|_| unreachable!()).boxed()));
     }
 
-    // Extract target URL from path (everything after the first /)
-    let target_url = extract_target_url(&uri);
+    let mocks = admin.mocks();
+
+    // Extract target URL from a route alias or the path (everything after the first /)
+    let (route, target_url) = match config.route_target(uri.path(), uri.query()) {
+        Some((route, url)) => (Some(route), Some(url)),
+        None => (None, extract_target_url(&uri)),
+    };
     let target_url = match target_url {
         Some(url) => url,
         None => {
@@ -75,39 +113,187 @@ pub async fn handle_request(
         }
     };
 
-    // Parse and validate the target URL
-    let parsed_url = match Url::parse(&target_url) {
-        Ok(url) => url,
-        Err(e) => {
+    // Unix domain socket targets skip URL validation
+    if target_url.starts_with(UNIX_PREFIX) {
+        let Some(unix_target) = UnixTarget::parse(&target_url) else {
             return Ok(error_response(
                 StatusCode::BAD_REQUEST,
-                &format!("Invalid URL: {}", e),
+                "Invalid Unix socket target. Use /unix:/path/to.sock:/request/path",
             )
             .map(|b| b.map_err(|_| unreachable!()).boxed()));
-        }
-    };
-
-    // Validate scheme
-    match parsed_url.scheme() {
-        "http" | "https" => {}
-        scheme => {
+        };
+        if !config.allows_unix_socket(&unix_target, route.as_deref()) {
+            warn!("Rejected Unix socket target {}: not allowed", unix_target.socket.display());
             return Ok(error_response(
-                StatusCode::BAD_REQUEST,
-                &format!("Unsupported scheme: {}. Only http and https are allowed.", scheme),
+                StatusCode::FORBIDDEN,
+                "Unix socket not allowed. Use a route alias or --allow-unix-socket",
             )
             .map(|b| b.map_err(|_| unreachable!()).boxed()));
         }
+
+        let ctx = RuleContext {
+            host: String::new(),
+            route,
+            origin,
+            target_url,
+        };
+        if let Some(rule) = find_mock(&mocks.rules, &method, &ctx).or_else(|| find_mock(&config.mocks, &method, &ctx)) {
+            return Ok(mock_response(rule, &ctx, &headers).await);
+        }
+        info!("Proxying {} {} -> {}", method, uri, ctx.target_url);
+        let faults = admin.faults().plan(&config.faults, &ctx);
+        if let Some(response) = inject_faults(faults.as_ref(), &ctx, &headers).await {
+            return Ok(response);
+        }
+        let req = match admin.intercept().request(req, &ctx, &config).await {
+            Ok(req) => req,
+            Err((status, message)) => return Ok(intercept_error(status, &message, &ctx, &headers)),
+        };
+        let method = req.method().clone();
+        let response =
+            forward_request(req, Target::Unix(unix_target), &ctx, &config, &upstream, &cookies, recorder.as_ref()).await?;
+        return match admin.intercept().response(response, &method, &ctx, &config).await {
+            Ok(response) => Ok(with_body_faults(response, faults, &method)),
+            Err((status, message)) => Ok(intercept_error(status, &message, &ctx, &headers)),
+        };
+    }
+
+    // Parse and validate the target URL
+    let parsed_url = match parse_target_url(&target_url) {
+        Ok(url) => url,
+        Err(e) => {
+            return Ok(error_response(StatusCode::BAD_REQUEST, &e).map(|b| b.map_err(|_| unreachable!()).boxed()));
+        }
+    };
+
+    let ctx = RuleContext {
+        host: parsed_url.host_str().unwrap_or_default().to_string(),
+        route,
+        origin,
+        target_url: target_url.clone(),
+    };
+
+    // Serve canned responses for mocked endpoints, runtime ones first
+    if let Some(rule) = find_mock(&mocks.rules, &method, &ctx).or_else(|| find_mock(&config.mocks, &method, &ctx)) {
+        return Ok(mock_response(rule, &ctx, &headers).await);
+    }
+
+    // Answer operations mocked from an OpenAPI document with their examples
+    if let Some(operation) = upstream.contracts().find(&method, &ctx).filter(|op| op.mocked()) {
+        return contract_mock(req, &operation, &ctx).await;
     }
 
     info!("Proxying {} {} -> {}", method, uri, target_url);
 
+    // Delay or fail the request if a fault rule fires
+    let faults = admin.faults().plan(&config.faults, &ctx);
+    if let Some(response) = inject_faults(faults.as_ref(), &ctx, &headers).await {
+        return Ok(response);
+    }
+
     // Check for WebSocket upgrade
     if is_websocket_upgrade(&headers) {
-        return handle_websocket(&target_url).await;
+        return handle_websocket(req, &ctx, &config, &upstream, &cookies, recorder, shutdown).await;
     }
 
-    // Forward the request
-    forward_request(req, &target_url, &origin).await
+    // Hold the request at a matching breakpoint until it is released
+    let req = match admin.intercept().request(req, &ctx, &config).await {
+        Ok(req) => req,
+        Err((status, message)) => return Ok(intercept_error(status, &message, &ctx, &headers)),
+    };
+    let method = req.method().clone();
+
+    // Forward the request, then give a matching breakpoint the response
+    let response =
+        forward_request(req, Target::Url(target_url), &ctx, &config, &upstream, &cookies, recorder.as_ref()).await?;
+    match admin.intercept().response(response, &method, &ctx, &config).await {
+        Ok(response) => Ok(with_body_faults(response, faults, &method)),
+        Err((status, message)) => Ok(intercept_error(status, &message, &ctx, &headers)),
+    }
+}
+
+/// Error for a message dropped or timed out at a breakpoint
+fn intercept_error(
+    status: StatusCode,
+    message: &str,
+    ctx: &RuleContext,
+    headers: &HeaderMap,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut response = error_response(status, message);
+    add_cors_headers(response.headers_mut(), &ctx.origin, headers);
+    response.map(|b| b.map_err(|_| unreachable!()).boxed())
+}
+
+/// Wait out injected latency, then answer with the injected status if there is one
+async fn inject_faults(
+    plan: Option<&FaultPlan>,
+    ctx: &RuleContext,
+    headers: &HeaderMap,
+) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+    let plan = plan?;
+    if !plan.delay.is_zero() {
+        debug!("Delaying {} by {:?}", ctx.target_url, plan.delay);
+        tokio::time::sleep(plan.delay).await;
+    }
+    let status = plan.status?;
+    info!("Injecting {} for {}", status, ctx.target_url);
+    let mut response = error_response(status, &format!("Injected fault: {}", status));
+    add_cors_headers(response.headers_mut(), &ctx.origin, headers);
+    Some(response.map(|b| b.map_err(|_| unreachable!()).boxed()))
+}
+
+/// Reset, truncate or throttle the response body if a fault rule asks for it
+fn with_body_faults(
+    response: Response<BoxBody<Bytes, hyper::Error>>,
+    plan: Option<FaultPlan>,
+    method: &Method,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    match plan {
+        Some(plan) if method != Method::HEAD => plan.apply(response),
+        _ => response,
+    }
+}
+
+/// Serve a mock rule's response with the usual CORS headers
+async fn mock_response(rule: &MockRule, ctx: &RuleContext, headers: &HeaderMap) -> Response<BoxBody<Bytes, hyper::Error>> {
+    info!("Mocking {} -> {}", ctx.target_url, rule.status);
+    let mut response = rule.respond(ctx, headers).await;
+    add_cors_headers(response.headers_mut(), &ctx.origin, headers);
+    response.map(|b| b.map_err(|_| unreachable!()).boxed())
+}
+
+/// Serve the example of an OpenAPI operation, after checking the request
+async fn contract_mock(
+    req: Request<Incoming>,
+    operation: &Operation<'_>,
+    ctx: &RuleContext,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    info!("Mocking {} from OpenAPI operation {}", ctx.target_url, operation);
+    let headers = req.headers().clone();
+    let body = match req.into_body().collect().await {
+        Ok(collected) => collected.to_bytes(),
+        Err(e) => {
+            error!("Failed to read request body: {}", e);
+            return Ok(error_response(StatusCode::BAD_REQUEST, "Failed to read request body")
+                .map(|b| b.map_err(|_| unreachable!()).boxed()));
+        }
+    };
+
+    let violations = operation.validate_request(&ctx.target_url, &headers, &body);
+    let mut response = operation
+        .report(&violations, StatusCode::BAD_REQUEST)
+        .unwrap_or_else(|| operation.example());
+    add_cors_headers(response.headers_mut(), &ctx.origin, &headers);
+    Ok(response.map(|b| b.map_err(|_| unreachable!()).boxed()))
+}
+
+/// Parse a target URL, allowing only http and https
+fn parse_target_url(target_url: &str) -> Result<Url, String> {
+    let url = Url::parse(target_url).map_err(|e| format!("Invalid URL: {}", e))?;
+    match url.scheme() {
+        "http" | "https" => Ok(url),
+        scheme => Err(format!("Unsupported scheme: {}. Only http and https are allowed.", scheme)),
+    }
 }
 
 /// Extract the target URL from the request path
@@ -132,8 +318,8 @@ fn extract_target_url(uri: &Uri) -> Option<String> {
         decoded
     };
 
-    // Validate it looks like a URL
-    if url.starts_with("http://") || url.starts_with("https://") {
+    // Validate it looks like a URL (or a Unix socket target)
+    if url.starts_with("http://") || url.starts_with("https://") || url.starts_with(UNIX_PREFIX) {
         Some(url)
     } else {
         // Try adding https:// if it looks like a domain
@@ -169,48 +355,28 @@ fn urlencoding_decode(s: &str) -> String {
     result
 }
 
-/// Check if this is a WebSocket upgrade request
-fn is_websocket_upgrade(headers: &HeaderMap) -> bool {
-    headers
-        .get(header::UPGRADE)
-        .and_then(|v| v.to_str().ok())
-        .map(|v| v.eq_ignore_ascii_case("websocket"))
-        .unwrap_or(false)
+/// The parts of the browser's request sent upstream, kept across redirects
+struct Outgoing {
+    method: Method,
+    headers: HeaderMap,
+    body: Bytes,
+    /// Cookie jar the request uses, if the jar is enabled
+    jar_key: Option<String>,
 }
 
 /// Forward an HTTP request to the target
 async fn forward_request(
-    req: Request<Incoming>,
-    target_url: &str,
-    origin: &str,
+    req: Request<BoxBody<Bytes, hyper::Error>>,
+    target: Target,
+    ctx: &RuleContext,
+    config: &Config,
+    upstream: &Upstream,
+    cookies: &CookieJar,
+    recorder: Option<&Arc<Recorder>>,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
     let method = req.method().clone();
     let original_headers = req.headers().clone();
-
-    // Build HTTPS connector with HTTP/2 support using native roots
-    let https = HttpsConnectorBuilder::new()
-        .with_native_roots()
-        .expect("Failed to load native TLS roots")
-        .https_or_http()
-        .enable_http1()
-        .enable_http2()
-        .build();
-
-    let client: Client<_, Full<Bytes>> = Client::builder(TokioExecutor::new())
-        .http2_only(false)
-        .build(https);
-
-    // Parse target URI
-    let target_uri: Uri = match target_url.parse() {
-        Ok(uri) => uri,
-        Err(e) => {
-            return Ok(error_response(
-                StatusCode::BAD_REQUEST,
-                &format!("Invalid target URI: {}", e),
-            )
-            .map(|b| b.map_err(|_| unreachable!()).boxed()));
-        }
-    };
+    let jar_key = config.cookie_jar.jar_key(&original_headers, &ctx.origin);
 
     // Collect the request body
     let body_bytes = match req.into_body().collect().await {
@@ -222,21 +388,246 @@ async fn forward_request(
         }
     };
 
+    // Check the request against the upstream's OpenAPI document
+    let operation = upstream.contracts().find(&method, ctx);
+    if let Some(operation) = &operation {
+        let violations = operation.validate_request(&ctx.target_url, &original_headers, &body_bytes);
+        if let Some(mut response) = operation.report(&violations, StatusCode::BAD_REQUEST) {
+            add_cors_headers(response.headers_mut(), &ctx.origin, &original_headers);
+            return Ok(response.map(|b| b.map_err(|_| unreachable!()).boxed()));
+        }
+    }
+
+    let mut outgoing = Outgoing {
+        method,
+        headers: original_headers.clone(),
+        body: body_bytes,
+        jar_key,
+    };
+    let mut target = target;
+    let mut ctx = ctx.clone();
+    let mut redirects = 0;
+
+    let response = loop {
+        let response = match exchange(&outgoing, &target, &ctx, config, upstream, cookies, recorder).await {
+            Ok(response) => response,
+            Err(response) => return Ok(response),
+        };
+
+        // Follow redirects from http(s) targets, up to the limit
+        let status = response.status();
+        let location = response.headers().get(header::LOCATION).and_then(|v| v.to_str().ok());
+        let (Target::Url(current), Some(location)) = (&target, location) else {
+            break response;
+        };
+        if config.redirects != RedirectPolicy::Follow || !status.is_redirection() {
+            break response;
+        }
+        if redirects >= config.max_redirects {
+            warn!("Not following more than {} redirects from {}", config.max_redirects, ctx.target_url);
+            break response;
+        }
+
+        // Redirect targets get the same checks as the requested URL
+        let next = match Url::parse(current).and_then(|url| url.join(location)) {
+            Ok(url) => parse_target_url(url.as_str()),
+            Err(e) => Err(format!("Invalid URL: {}", e)),
+        };
+        let next = match next {
+            Ok(url) => url,
+            Err(e) => {
+                warn!("Rejected redirect from {} to {}: {}", ctx.target_url, location, e);
+                return Ok(error_response(StatusCode::BAD_GATEWAY, &format!("Rejected redirect: {}", e))
+                    .map(|b| b.map_err(|_| unreachable!()).boxed()));
+            }
+        };
+        redirects += 1;
+        info!("Following {} redirect -> {}", status.as_u16(), next);
+
+        let method = redirect_method(status, &outgoing.method);
+        let body_dropped = method != outgoing.method && method == Method::GET;
+        if body_dropped {
+            outgoing.body = Bytes::new();
+        }
+        outgoing.method = method;
+
+        // Rules, credentials and cookies are re-evaluated for the new target;
+        // route rules no longer apply once the redirect leaves the route's origin
+        let cross_origin = Url::parse(current).map(|url| url.origin()).ok() != Some(next.origin());
+        strip_redirect_headers(&mut outgoing.headers, body_dropped, cross_origin);
+        if cross_origin {
+            ctx.route = None;
+        }
+        ctx.host = next.host_str().unwrap_or_default().to_string();
+        ctx.target_url = next.to_string();
+        target = Target::Url(next.to_string());
+    };
+
+    // Check the upstream's response against the OpenAPI document
+    let (mut parts, mut body) = response.into_parts();
+    if let Some(operation) = operation.as_ref().filter(|op| op.validate() != Validate::Off) {
+        let mut bytes = Bytes::new();
+        let mut checked = true;
+        if BodyKind::of(&parts.headers) == Some(BodyKind::Json) && outgoing.method != Method::HEAD {
+            match read_body(body).await {
+                Ok(Buffered::Whole(read)) => {
+                    bytes = read;
+                    body = Full::new(bytes.clone()).map_err(|_| unreachable!()).boxed();
+                }
+                // Too large to check: pass it through unvalidated
+                Ok(Buffered::Oversized(rest)) => {
+                    warn!("Not validating the response of {}: body over {} bytes", ctx.target_url, MAX_BODY_SIZE);
+                    body = rest;
+                    checked = false;
+                }
+                Err(e) => {
+                    error!("Failed to read response body: {}", e);
+                    return Ok(error_response(StatusCode::BAD_GATEWAY, "Failed to read response body")
+                        .map(|b| b.map_err(|_| unreachable!()).boxed()));
+                }
+            }
+        }
+        let violations = if checked { operation.validate_response(parts.status, &parts.headers, &bytes) } else { Vec::new() };
+        if let Some(mut response) = operation.report(&violations, StatusCode::BAD_GATEWAY) {
+            add_cors_headers(response.headers_mut(), &ctx.origin, &original_headers);
+            return Ok(response.map(|b| b.map_err(|_| unreachable!()).boxed()));
+        }
+    }
+
+    // Build the response with CORS headers
+
+    // Make cookies acceptable to the browser for the proxy's host and paths
+    if !config.set_cookie.is_empty() {
+        let proxy_host = original_headers
+            .get(header::HOST)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<http::uri::Authority>().ok())
+            .map(|authority| authority.host().to_string());
+        let prefix = config.proxy_prefix(&ctx);
+        rewrite_set_cookies(&config.set_cookie, &mut parts.headers, &ctx, proxy_host.as_deref(), &prefix);
+    }
+
+    // Point redirects back through the proxy
+    if config.redirects != RedirectPolicy::Passthrough {
+        rewrite_locations(&mut parts.headers, &ctx.target_url, &config.proxy_prefix(&ctx));
+    }
+
+    // Decide what can be transformed while the encoding and length are known
+    let transformable = BodyKind::of(&parts.headers).is_some() && outgoing.method != Method::HEAD;
+    let rewritable = ContentKind::of(&parts.headers).filter(|_| config.rewrite_urls && outgoing.method != Method::HEAD);
+
+    // Remove headers we don't want to forward back
+    for header_name in SKIP_RESPONSE_HEADERS {
+        if let Ok(name) = header::HeaderName::from_bytes(header_name.as_bytes()) {
+            parts.headers.remove(&name);
+        }
+    }
+
+    // Add CORS headers
+    add_cors_headers(&mut parts.headers, &ctx.origin, &original_headers);
+
+    // Apply configured response header rules (placeholders read the upstream's headers)
+    let upstream_headers = parts.headers.clone();
+    apply_header_rules(&config.response_headers, &mut parts.headers, &ctx, &upstream_headers);
+
+    // Convert the response body to BoxBody
+    let mut boxed_body = body;
+
+    // Apply configured response body transforms
+    if transformable && config.response_body.iter().any(|rule| rule.matches(&ctx)) {
+        match read_body(boxed_body).await {
+            Ok(Buffered::Whole(body)) => {
+                let body = apply_body_rules(&config.response_body, &ctx, &mut parts.headers, &upstream_headers, &body)
+                    .unwrap_or(body);
+                parts.headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+                boxed_body = Full::new(body).map_err(|_| unreachable!()).boxed();
+            }
+            // Too large to transform: pass it through untouched
+            Ok(Buffered::Oversized(body)) => boxed_body = body,
+            Err(e) => {
+                error!("Failed to read response body: {}", e);
+                return Ok(error_response(StatusCode::BAD_GATEWAY, "Failed to read response body")
+                    .map(|b| b.map_err(|_| unreachable!()).boxed()));
+            }
+        }
+    }
+
+    // Point links in pages and stylesheets back through the proxy
+    if let Some(kind) = rewritable {
+        parts.headers.remove(header::CONTENT_LENGTH);
+        let rewriter = UrlRewriter::new(kind, &ctx.target_url, config.proxy_prefix(&ctx));
+        boxed_body = RewriteBody::new(boxed_body, rewriter).boxed();
+    }
+
+    Ok(Response::from_parts(parts, boxed_body))
+}
+
+/// Send one request to the target with the configured rules, credentials and
+/// cookies applied; errors are returned as the response for the browser
+#[allow(clippy::result_large_err)]
+async fn exchange(
+    outgoing: &Outgoing,
+    target: &Target,
+    ctx: &RuleContext,
+    config: &Config,
+    upstream: &Upstream,
+    cookies: &CookieJar,
+    recorder: Option<&Arc<Recorder>>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Response<BoxBody<Bytes, hyper::Error>>> {
+    let original_headers = &outgoing.headers;
+    let jar_key = outgoing.jar_key.as_deref();
+
+    // Parse target URI (socket targets are sent the request path only)
+    let mut target_str = match target {
+        Target::Url(url) => url.clone(),
+        Target::Unix(unix_target) => unix_target.path.clone(),
+    };
+
+    // Recordings leave out credentials injected into the query
+    let recorded_url = match target {
+        Target::Url(url) => url.clone(),
+        Target::Unix(_) => ctx.target_url.clone(),
+    };
+
+    // Resolve injected credentials; query parameters go into the target
+    let mut credentials = HeaderMap::new();
+    apply_credentials(&config.credentials, &config.secrets, ctx, &mut credentials, &mut target_str);
+
+    // The cookie jar applies to http(s) targets
+    let jar_url = match (jar_key, target) {
+        (Some(_), Target::Url(_)) => Url::parse(&target_str).ok(),
+        _ => None,
+    };
+
+    let target_uri: Uri = match target_str.parse() {
+        Ok(uri) => uri,
+        Err(e) => {
+            return Err(error_response(
+                StatusCode::BAD_REQUEST,
+                &format!("Invalid target URI: {}", e),
+            )
+            .map(|b| b.map_err(|_| unreachable!()).boxed()));
+        }
+    };
+
     // Build the proxied request
+    let mut body = outgoing.body.clone();
     let mut builder = Request::builder()
-        .method(method)
+        .method(outgoing.method.clone())
         .uri(&target_uri);
 
     // Forward headers (excluding hop-by-hop headers)
     for (name, value) in original_headers.iter() {
         let name_str = name.as_str().to_lowercase();
-        if !HOP_BY_HOP_HEADERS.contains(&name_str.as_str()) {
+        if !HOP_BY_HOP_HEADERS.contains(&name_str.as_str()) && name_str != SESSION_HEADER {
             builder = builder.header(name, value);
         }
     }
 
     // Set the Host header to the target
-    if let Some(host) = target_uri.host() {
+    if let Target::Unix(_) = target {
+        builder = builder.header(header::HOST, "localhost");
+    } else if let Some(host) = target_uri.host() {
         let host_value = if let Some(port) = target_uri.port() {
             format!("{}:{}", host, port)
         } else {
@@ -245,21 +636,128 @@ async fn forward_request(
         builder = builder.header(header::HOST, host_value);
     }
 
-    let proxy_req = match builder.body(Full::new(body_bytes)) {
+    // Apply configured header rewrite rules
+    if let Some(headers) = builder.headers_mut() {
+        // Bodies can only be rewritten uncompressed
+        if config.rewrite_urls
+            || config.response_body.iter().any(|rule| rule.matches(ctx))
+            || upstream.contracts().validates(ctx)
+        {
+            headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+        }
+        apply_header_rules(&config.request_headers, headers, ctx, original_headers);
+        for (name, value) in &credentials {
+            headers.insert(name, value.clone());
+        }
+        if let (Some(key), Some(url)) = (jar_key, &jar_url) {
+            if let Some(jar_cookies) = cookies.cookie_header(key, url) {
+                merge_cookie_header(headers, jar_cookies);
+            }
+        }
+
+        // Transform the body once the headers describing it are final
+        if config.request_body.iter().any(|rule| rule.matches(ctx)) {
+            if let Some(transformed) = apply_body_rules(&config.request_body, ctx, headers, original_headers, &body) {
+                body = transformed;
+            }
+        }
+    }
+
+    // Answer from the recording instead of the upstream when replaying
+    if let Some(cassette) = upstream.cassette() {
+        let headers = builder.headers_ref().cloned().unwrap_or_default();
+        match cassette.find(&outgoing.method, &recorded_url, &headers, &body) {
+            Some(response) => {
+                debug!("Replaying recorded response for {} {}", outgoing.method, recorded_url);
+                if let (Some(key), Some(url)) = (jar_key, &jar_url) {
+                    cookies.store(key, url, response.headers());
+                }
+                return Ok(response);
+            }
+            None if cassette.mode() == ReplayMode::Strict => {
+                warn!("No recorded response for {} {}", outgoing.method, recorded_url);
+                let mut response = error_response(
+                    StatusCode::BAD_GATEWAY,
+                    &format!("No recorded response for {} {}", outgoing.method, recorded_url),
+                );
+                add_cors_headers(response.headers_mut(), &ctx.origin, original_headers);
+                return Err(response.map(|b| b.map_err(|_| unreachable!()).boxed()));
+            }
+            None => {}
+        }
+    }
+
+    // Get an OAuth2 access token for the upstream, if configured
+    let mut oauth = None;
+    if let Some(rule) = config.oauth2.iter().find(|rule| rule.matches(ctx)) {
+        match upstream.tokens().token(rule, &config.secrets, upstream).await {
+            Ok(value) => {
+                if let (Some(token), Some(headers)) = (bearer_header(&value), builder.headers_mut()) {
+                    headers.insert(header::AUTHORIZATION, token);
+                }
+                oauth = Some((rule, value));
+            }
+            Err(e) => {
+                error!("Failed to get OAuth2 token: {}", e);
+                return Err(error_response(StatusCode::BAD_GATEWAY, &format!("Failed to get OAuth2 token: {}", e))
+                    .map(|b| b.map_err(|_| unreachable!()).boxed()));
+            }
+        }
+    }
+
+    // Sign last, once every header that goes upstream is final
+    if let (Some(rule), Some(headers)) =
+        (config.aws_sigv4.iter().find(|rule| rule.matches(ctx)), builder.headers_mut())
+    {
+        if let Err(e) = rule.sign(&outgoing.method, &target_uri, headers, &body) {
+            error!("Failed to sign request: {}", e);
+            return Err(error_response(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to sign request: {}", e))
+                .map(|b| b.map_err(|_| unreachable!()).boxed()));
+        }
+    }
+
+    let proxy_req = match builder.body(Full::new(body.clone())) {
         Ok(req) => req,
         Err(e) => {
             error!("Failed to build proxy request: {}", e);
-            return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to build request")
+            return Err(error_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to build request")
                 .map(|b| b.map_err(|_| unreachable!()).boxed()));
         }
     };
 
-    // Send the request
-    let response: Response<Incoming> = match client.request(proxy_req).await {
+    // Keep a copy to resend with a fresh token if the upstream rejects it
+    let retry = oauth.map(|(rule, sent)| (rule, sent, copy_request(&proxy_req)));
+
+    let mut entry = recorder.map(|r| r.start(&outgoing.method, &recorded_url, proxy_req.headers(), &body));
+    let mut result = send(target, upstream, proxy_req).await;
+    if let Some((rule, sent, mut retry)) = retry {
+        if matches!(&result, Ok(resp) if resp.status() == StatusCode::UNAUTHORIZED) {
+            info!("Upstream rejected the OAuth2 token, fetching a new one");
+            upstream.tokens().invalidate(rule, &sent).await;
+            match upstream.tokens().token(rule, &config.secrets, upstream).await {
+                Ok(value) => {
+                    if let Some(token) = bearer_header(&value) {
+                        retry.headers_mut().insert(header::AUTHORIZATION, token);
+                    }
+                    // Record the rejected attempt before starting the retry's entry
+                    if let (Some(entry), Ok(rejected)) = (entry.take(), result) {
+                        drop(entry.response(rejected));
+                    }
+                    entry = recorder.map(|r| r.start(&outgoing.method, &recorded_url, retry.headers(), &body));
+                    result = send(target, upstream, retry).await;
+                }
+                Err(e) => error!("Failed to refresh OAuth2 token: {}", e),
+            }
+        }
+    }
+    let response: Response<Incoming> = match result {
         Ok(resp) => resp,
         Err(e) => {
             error!("Proxy request failed: {}", e);
-            return Ok(error_response(
+            if let Some(entry) = entry {
+                entry.failed(&e.to_string());
+            }
+            return Err(error_response(
                 StatusCode::BAD_GATEWAY,
                 &format!("Failed to reach target: {}", e),
             )
@@ -267,62 +765,37 @@ async fn forward_request(
         }
     };
 
-    // Build the response with CORS headers
-    let (mut parts, body) = response.into_parts();
-
-    // Remove headers we don't want to forward back
-    for header_name in SKIP_RESPONSE_HEADERS {
-        if let Ok(name) = header::HeaderName::from_bytes(header_name.as_bytes()) {
-            parts.headers.remove(&name);
-        }
+    // Capture cookies before any rule can rewrite them
+    if let (Some(key), Some(url)) = (jar_key, &jar_url) {
+        cookies.store(key, url, response.headers());
     }
 
-    // Add CORS headers
-    add_cors_headers(&mut parts.headers, origin, &original_headers);
-
-    // Convert the response body to BoxBody
-    let boxed_body: BoxBody<Bytes, hyper::Error> = body.boxed();
-
-    Ok(Response::from_parts(parts, boxed_body))
+    Ok(match entry {
+        Some(entry) => entry.response(response),
+        None => response.map(|body| body.boxed()),
+    })
 }
 
-/// Handle WebSocket upgrade and proxy
-async fn handle_websocket(
-    target_url: &str,
-) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
-    info!("WebSocket upgrade requested for {}", target_url);
-
-    // Convert http:// to ws:// and https:// to wss://
-    let ws_url = target_url
-        .replacen("http://", "ws://", 1)
-        .replacen("https://", "wss://", 1);
-
-    // For now, we return an error indicating WebSocket support is limited
-    // Full WebSocket proxying requires a different approach with connection hijacking
-    // which isn't directly supported by hyper 1.0 without additional work
-
-    warn!("WebSocket proxying is experimental");
-
-    // Try to connect to the target WebSocket
-    let (_ws_stream, _) = match connect_async(&ws_url).await {
-        Ok(result) => result,
-        Err(e) => {
-            error!("Failed to connect to WebSocket target: {}", e);
-            return Ok(error_response(
-                StatusCode::BAD_GATEWAY,
-                &format!("Failed to connect to WebSocket: {}", e),
-            )
-            .map(|b| b.map_err(|_| unreachable!()).boxed()));
+/// Send a request using the client matching the target's TLS setup
+async fn send(
+    target: &Target,
+    upstream: &Upstream,
+    req: Request<Full<Bytes>>,
+) -> Result<Response<Incoming>, Box<dyn std::error::Error + Send + Sync>> {
+    match target {
+        Target::Url(_) => {
+            let client = upstream.client_for(req.uri().host().unwrap_or_default());
+            client.request(req).await.map_err(Into::into)
         }
-    };
-
-    // For full WebSocket proxying, we'd need to upgrade the incoming connection
-    // and bidirectionally proxy messages. This requires connection hijacking.
-    // For now, return an informational response.
+        Target::Unix(unix_target) => unix::send_request(&unix_target.socket, req).await,
+    }
+}
 
-    Ok(error_response(
-        StatusCode::NOT_IMPLEMENTED,
-        "WebSocket proxying requires connection hijacking. Use a direct WebSocket connection.",
-    )
-    .map(|b| b.map_err(|_| unreachable!()).boxed()))
+/// Copy a buffered request so it can be sent again
+fn copy_request(req: &Request<Full<Bytes>>) -> Request<Full<Bytes>> {
+    let mut copy = Request::new(req.body().clone());
+    *copy.method_mut() = req.method().clone();
+    *copy.uri_mut() = req.uri().clone();
+    *copy.headers_mut() = req.headers().clone();
+    copy
 }
@@ -0,0 +1,152 @@
+use clap::ValueEnum;
+use http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::config::ProxyPrefix;
+use crate::unix::UnixTarget;
+
+/// Request headers describing the body, dropped when a redirect turns the request into a GET
+const BODY_HEADERS: &[header::HeaderName] = &[
+    header::CONTENT_TYPE,
+    header::CONTENT_LENGTH,
+    header::CONTENT_ENCODING,
+    header::CONTENT_LANGUAGE,
+    header::CONTENT_LOCATION,
+];
+
+/// Request headers carrying the browser's credentials, dropped when a redirect leaves the origin
+const CREDENTIAL_HEADERS: &[header::HeaderName] =
+    &[header::AUTHORIZATION, header::COOKIE, header::PROXY_AUTHORIZATION];
+
+/// What to do with upstream redirects
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RedirectPolicy {
+    /// Return redirects untouched
+    #[default]
+    Passthrough,
+    /// Point Location and Content-Location back through the proxy
+    Rewrite,
+    /// Follow redirects in the proxy and return the final response
+    Follow,
+}
+
+/// The method to use after a redirect (RFC 9110 section 15.4): 303 switches to
+/// GET, and 301/302 turn POST into GET as browsers do; 307/308 keep the method.
+pub fn redirect_method(status: StatusCode, method: &Method) -> Method {
+    match status {
+        StatusCode::SEE_OTHER if method != Method::HEAD => Method::GET,
+        StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND if method == Method::POST => Method::GET,
+        _ => method.clone(),
+    }
+}
+
+/// Remove the headers that no longer apply once a request is redirected
+pub fn strip_redirect_headers(headers: &mut HeaderMap, body_dropped: bool, cross_origin: bool) {
+    if body_dropped {
+        for name in BODY_HEADERS {
+            headers.remove(name);
+        }
+    }
+    if cross_origin {
+        for name in CREDENTIAL_HEADERS {
+            headers.remove(name);
+        }
+    }
+}
+
+/// Rewrite the Location and Content-Location headers of a response from
+/// `target_url` so the browser reaches them through the proxy
+pub fn rewrite_locations(headers: &mut HeaderMap, target_url: &str, prefix: &ProxyPrefix) {
+    for name in [header::LOCATION, header::CONTENT_LOCATION] {
+        let rewritten = headers
+            .get(&name)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|location| rewrite_location(location, target_url, prefix))
+            .and_then(|location| HeaderValue::from_str(&location).ok());
+        if let Some(value) = rewritten {
+            headers.insert(name, value);
+        }
+    }
+}
+
+/// Map a Location (or any URL reference in a response from `target_url`) to
+/// the proxy path for it: same-origin URLs go through the current prefix,
+/// others through `/{URL}`. Non-http(s) references are left alone.
+pub fn rewrite_location(location: &str, target_url: &str, prefix: &ProxyPrefix) -> Option<String> {
+    if UnixTarget::parse(target_url).is_some() {
+        // Socket targets can only redirect within the socket or to a full URL
+        if location.starts_with("http://") || location.starts_with("https://") {
+            return Some(format!("/{}", location));
+        }
+        let path = location.strip_prefix('/').filter(|rest| !rest.starts_with('/'))?;
+        return prefix.map(&format!("/{}", path));
+    }
+
+    let base = Url::parse(target_url).ok()?;
+    let resolved = base.join(location).ok()?;
+    if !matches!(resolved.scheme(), "http" | "https") {
+        return None;
+    }
+    if resolved.origin() != base.origin() {
+        return Some(format!("/{}", resolved));
+    }
+
+    let mut path = match prefix.map(resolved.path()) {
+        Some(path) => path,
+        None => return Some(format!("/{}", resolved)),
+    };
+    if let Some(query) = resolved.query() {
+        path.push('?');
+        path.push_str(query);
+    }
+    if let Some(fragment) = resolved.fragment() {
+        path.push('#');
+        path.push_str(fragment);
+    }
+    Some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite_location() {
+        let routed = ProxyPrefix {
+            prefix: "/api".to_string(),
+            base: "/v2".to_string(),
+        };
+        let target = "https://api.example.com/v2/login";
+        assert_eq!(rewrite_location("/v2/home?x=1", target, &routed).as_deref(), Some("/api/home?x=1"));
+        assert_eq!(rewrite_location("home", target, &routed).as_deref(), Some("/api/home"));
+        assert_eq!(
+            rewrite_location("/other", target, &routed).as_deref(),
+            Some("/https://api.example.com/other")
+        );
+        assert_eq!(
+            rewrite_location("https://auth.example.com/sso", target, &routed).as_deref(),
+            Some("/https://auth.example.com/sso")
+        );
+        assert_eq!(rewrite_location("mailto:me@example.com", target, &routed), None);
+
+        let direct = ProxyPrefix {
+            prefix: "/http://localhost:8080".to_string(),
+            base: String::new(),
+        };
+        assert_eq!(
+            rewrite_location("http://localhost:8080/next", "http://localhost:8080/", &direct).as_deref(),
+            Some("/http://localhost:8080/next")
+        );
+    }
+
+    #[test]
+    fn test_redirect_method() {
+        assert_eq!(redirect_method(StatusCode::SEE_OTHER, &Method::PUT), Method::GET);
+        assert_eq!(redirect_method(StatusCode::SEE_OTHER, &Method::HEAD), Method::HEAD);
+        assert_eq!(redirect_method(StatusCode::FOUND, &Method::POST), Method::GET);
+        assert_eq!(redirect_method(StatusCode::FOUND, &Method::DELETE), Method::DELETE);
+        assert_eq!(redirect_method(StatusCode::PERMANENT_REDIRECT, &Method::POST), Method::POST);
+    }
+}
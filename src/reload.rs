@@ -0,0 +1,221 @@
+use clap::ArgMatches;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing::{error, info, warn};
+
+use crate::config::Config;
+use crate::config_file::FileConfig;
+use crate::replay::Cassette;
+use crate::shutdown::Shutdown;
+use crate::state::State;
+use crate::upstream::Upstream;
+
+/// How often the config file is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Options that only take effect on restart
+const RESTART_ONLY: &[&str] = &["port", "bind", "listen", "verbose", "record", "record_max_body"];
+
+/// Reload the configuration on SIGHUP or when the config file changes, until shutdown
+pub async fn run(matches: ArgMatches, path: Option<PathBuf>, state: Arc<State>, shutdown: Arc<Shutdown>) {
+    let matches = Arc::new(matches);
+    let mut path = path;
+    let mut modified = modified_time(path.as_deref()).await;
+    let mut hangup = hangup_signal();
+
+    loop {
+        let reason = tokio::select! {
+            _ = shutdown.wait() => return,
+            _ = hangup.recv() => "SIGHUP",
+            _ = tokio::time::sleep(POLL_INTERVAL) => {
+                let current = modified_time(path.as_deref()).await;
+                if current == modified {
+                    continue;
+                }
+                modified = current;
+                "config file changed"
+            }
+        };
+
+        // Loading reads the config, secrets, certificates and recordings from disk
+        let reloaded = {
+            let (matches, state) = (Arc::clone(&matches), Arc::clone(&state));
+            tokio::task::spawn_blocking(move || reload(&matches, &state, reason)).await
+        };
+        if let Ok(Some(new_path)) = reloaded {
+            if path.as_ref() != Some(&new_path) {
+                modified = modified_time(Some(&new_path)).await;
+                path = Some(new_path);
+            }
+        }
+    }
+}
+
+/// Load and apply the configuration, keeping the current one if it is invalid.
+/// Returns the config file now in use.
+fn reload(matches: &ArgMatches, state: &State, reason: &str) -> Option<PathBuf> {
+    let (config, path) = match Config::load(matches) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            error!("Config reload ({}) failed, keeping the current configuration: {}", reason, e);
+            return None;
+        }
+    };
+
+    // Keep replaying from where we are unless the replay options changed
+    let current = state.current();
+    let cassette = match Cassette::changed(&current.config, &config) {
+        true => Cassette::from_config(&config),
+        false => Ok(state.cassette()),
+    };
+    let upstream = cassette.and_then(|cassette| {
+        let upstream = Upstream::from_config(&config, Arc::clone(state.tokens()), cassette.clone())?;
+        Ok((upstream, cassette))
+    });
+    let (upstream, cassette) = match upstream {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            error!("Config reload ({}) failed, keeping the current configuration: {}", reason, e);
+            return None;
+        }
+    };
+
+    let changes = describe_changes(&current.config, &config);
+    if changes.is_empty() {
+        info!("Config reloaded ({}): no changes", reason);
+    } else {
+        info!("Config reloaded ({}): {}", reason, changes.join("; "));
+        for key in RESTART_ONLY {
+            if changes.iter().any(|c| changed_key(c) == *key) {
+                warn!("'{}' changed - restart Holy CORS to apply it", key);
+            }
+        }
+    }
+
+    // A new seed restarts the fault sequence, as `PUT /__holy/faults` does
+    if let Some(seed) = config.fault_seed.filter(|_| config.fault_seed != current.config.fault_seed) {
+        info!("Injecting faults from seed {}", seed);
+        state.admin().faults().reseed(seed);
+    }
+
+    state.replace(Arc::new(config), Arc::new(upstream), cassette);
+    path
+}
+
+/// List the settings that differ between two configurations
+pub fn describe_changes(old: &Config, new: &Config) -> Vec<String> {
+    let (Some(old), Some(new)) = (as_table(old), as_table(new)) else {
+        return vec!["configuration replaced".to_string()];
+    };
+
+    let mut changes = Vec::new();
+    diff_tables("", &old, &new, &mut changes);
+    changes
+}
+
+/// The setting a line from `describe_changes` is about
+fn changed_key(change: &str) -> &str {
+    change
+        .split_once(": ")
+        .map(|(key, _)| key)
+        .or_else(|| change.strip_suffix(" added"))
+        .or_else(|| change.strip_suffix(" removed"))
+        .unwrap_or(change)
+}
+
+fn as_table(config: &Config) -> Option<toml::Table> {
+    let file = FileConfig::from_config(config).ok()?;
+    toml::Table::try_from(file).ok()
+}
+
+fn diff_tables(prefix: &str, old: &toml::Table, new: &toml::Table, changes: &mut Vec<String>) {
+    for (key, new_value) in new {
+        let name = format!("{}{}", prefix, key);
+        match (old.get(key), new_value) {
+            (None, toml::Value::Table(new_table)) => {
+                diff_tables(&format!("{}.", name), &toml::Table::new(), new_table, changes);
+            }
+            (None, _) => changes.push(format!("{} added", name)),
+            (Some(toml::Value::Table(old_table)), toml::Value::Table(new_table)) => {
+                diff_tables(&format!("{}.", name), old_table, new_table, changes);
+            }
+            (Some(old_value), _) if old_value != new_value => {
+                changes.push(format!("{}: {} -> {}", name, old_value, new_value));
+            }
+            (Some(_), _) => {}
+        }
+    }
+    for (key, old_value) in old {
+        match (old_value, new.contains_key(key)) {
+            (toml::Value::Table(old_table), false) => {
+                diff_tables(&format!("{}{}.", prefix, key), old_table, &toml::Table::new(), changes);
+            }
+            (_, false) => changes.push(format!("{}{} removed", prefix, key)),
+            _ => {}
+        }
+    }
+}
+
+async fn modified_time(path: Option<&Path>) -> Option<SystemTime> {
+    tokio::fs::metadata(path?).await.and_then(|m| m.modified()).ok()
+}
+
+/// SIGHUP where available; never fires elsewhere
+struct Hangup {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl Hangup {
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal {
+            signal.recv().await;
+            return;
+        }
+        std::future::pending::<()>().await
+    }
+}
+
+fn hangup_signal() -> Hangup {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        Hangup {
+            signal: signal(SignalKind::hangup()).ok(),
+        }
+    }
+
+    #[cfg(not(unix))]
+    Hangup {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_describe_changes() {
+        let old = Config::parse_from(["holy-cors"]);
+        let mut new = Config::parse_from(["holy-cors", "--allow-origin", "http://localhost:3000"]);
+        new.routes.insert("github".to_string(), "https://api.github.com".to_string());
+
+        let changes = describe_changes(&old, &new);
+        assert_eq!(
+            changes,
+            vec![
+                r#"allow_origins: [] -> ["http://localhost:3000"]"#.to_string(),
+                "routes.github added".to_string(),
+            ]
+        );
+
+        assert!(describe_changes(&new, &new).is_empty());
+        assert_eq!(describe_changes(&new, &old)[1], "routes.github removed");
+
+        let ports = describe_changes(&old, &Config::parse_from(["holy-cors", "--port", "9000"]));
+        assert_eq!(ports.iter().map(|c| changed_key(c)).collect::<Vec<_>>(), ["port"]);
+        assert_eq!(changed_key("routes.github removed"), "routes.github");
+    }
+}
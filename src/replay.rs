@@ -0,0 +1,304 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bytes::Bytes;
+use clap::ValueEnum;
+use http::{header, HeaderMap, HeaderName, HeaderValue, Method, Response, StatusCode};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tracing::info;
+
+use crate::config::Config;
+
+/// What to do with requests the cassette has no response for
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplayMode {
+    /// Answer 502 without contacting the upstream
+    #[default]
+    Strict,
+    /// Forward to the upstream (and record it, with --record)
+    Fallthrough,
+}
+
+/// Headers that carry injected credentials, recorded as `[redacted]`
+const CREDENTIAL_HEADERS: &[&str] = &["authorization", "proxy-authorization", "x-amz-security-token"];
+
+/// A part of the request that selects a recorded response
+#[derive(Debug, Clone, PartialEq)]
+pub enum MatchOn {
+    Method,
+    Url,
+    /// The URL without its query
+    Path,
+    /// SHA-256 of the body
+    Body,
+    Header(HeaderName),
+}
+
+impl MatchOn {
+    /// Parse `method`, `url`, `path`, `body` or `header:NAME`
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "method" => Ok(MatchOn::Method),
+            "url" => Ok(MatchOn::Url),
+            "path" => Ok(MatchOn::Path),
+            "body" => Ok(MatchOn::Body),
+            _ => {
+                let name = s
+                    .strip_prefix("header:")
+                    .and_then(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+                    .ok_or_else(|| format!("Invalid replay match '{}' (expected method, url, path, body or header:NAME)", s))?;
+                if CREDENTIAL_HEADERS.contains(&name.as_str()) {
+                    return Err(format!("Cannot replay match on {}: recordings redact credentials", name));
+                }
+                Ok(MatchOn::Header(name))
+            }
+        }
+    }
+}
+
+/// A recorded response
+struct Recorded {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+/// Responses recorded for one request, replayed in order; the last one repeats
+struct Recordings {
+    responses: Vec<Recorded>,
+    next: AtomicUsize,
+}
+
+/// Responses loaded from a HAR file, keyed by the parts of the request they match on
+pub struct Cassette {
+    mode: ReplayMode,
+    match_on: Vec<MatchOn>,
+    recordings: HashMap<String, Recordings>,
+}
+
+impl Cassette {
+    /// Load the cassette named by `--replay`, if any
+    pub fn from_config(config: &Config) -> Result<Option<Arc<Self>>, String> {
+        let Some(path) = &config.replay else {
+            return Ok(None);
+        };
+        let cassette = Self::load(path, config.replay_mode, &config.replay_match)?;
+        info!("Replaying {} recorded request(s) from {}", cassette.len(), path.display());
+        Ok(Some(Arc::new(cassette)))
+    }
+
+    /// Whether a reload needs a new cassette; other changes keep the current
+    /// one, and with it the position in each request's recorded responses
+    pub fn changed(old: &Config, new: &Config) -> bool {
+        old.replay != new.replay || old.replay_mode != new.replay_mode || old.replay_match != new.replay_match
+    }
+
+    /// Load the responses of a HAR file (as written by `--record`)
+    pub fn load(path: &Path, mode: ReplayMode, match_on: &[String]) -> Result<Self, String> {
+        let match_on = match_on.iter().map(|s| MatchOn::parse(s)).collect::<Result<Vec<_>, _>>()?;
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read replay file {}: {}", path.display(), e))?;
+        let har: Value = serde_json::from_str(&text)
+            .map_err(|e| format!("Invalid replay file {}: {}", path.display(), e))?;
+        let entries = har["log"]["entries"]
+            .as_array()
+            .ok_or_else(|| format!("Invalid replay file {}: no log.entries", path.display()))?;
+
+        let mut cassette = Cassette {
+            mode,
+            match_on,
+            recordings: HashMap::new(),
+        };
+        for (i, entry) in entries.iter().enumerate() {
+            let request = &entry["request"];
+            let response = &entry["response"];
+            // Failed requests and WebSockets have nothing to replay
+            let status = match response["status"].as_u64().and_then(|s| StatusCode::from_u16(s as u16).ok()) {
+                Some(status) if status != StatusCode::SWITCHING_PROTOCOLS => status,
+                _ => continue,
+            };
+            let (Some(method), Some(url)) = (
+                request["method"].as_str().and_then(|m| Method::from_bytes(m.as_bytes()).ok()),
+                request["url"].as_str(),
+            ) else {
+                return Err(format!("Invalid replay file {}: entry {} has no method or url", path.display(), i));
+            };
+
+            let key = cassette.key(&method, url, &har_headers(&request["headers"]), &content(&request["postData"]));
+            let recorded = Recorded {
+                status,
+                headers: har_headers(&response["headers"]),
+                body: content(&response["content"]),
+            };
+            cassette
+                .recordings
+                .entry(key)
+                .or_insert_with(|| Recordings {
+                    responses: Vec::new(),
+                    next: AtomicUsize::new(0),
+                })
+                .responses
+                .push(recorded);
+        }
+        Ok(cassette)
+    }
+
+    pub fn mode(&self) -> ReplayMode {
+        self.mode
+    }
+
+    /// Number of distinct requests with recorded responses
+    pub fn len(&self) -> usize {
+        self.recordings.len()
+    }
+
+    /// The next recorded response for a request, if there is one
+    pub fn find(
+        &self,
+        method: &Method,
+        url: &str,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+        let recordings = self.recordings.get(&self.key(method, url, headers, body))?;
+        let i = recordings.next.fetch_add(1, Ordering::Relaxed).min(recordings.responses.len() - 1);
+        let recorded = &recordings.responses[i];
+
+        let mut response = Response::new(Full::new(recorded.body.clone()).map_err(|_| unreachable!()).boxed());
+        *response.status_mut() = recorded.status;
+        *response.headers_mut() = recorded.headers.clone();
+        // The recorded body may have been truncated
+        response.headers_mut().remove(header::TRANSFER_ENCODING);
+        response
+            .headers_mut()
+            .insert(header::CONTENT_LENGTH, HeaderValue::from(recorded.body.len()));
+        Some(response)
+    }
+
+    fn key(&self, method: &Method, url: &str, headers: &HeaderMap, body: &[u8]) -> String {
+        let parts: Vec<String> = self
+            .match_on
+            .iter()
+            .map(|part| match part {
+                MatchOn::Method => method.to_string(),
+                MatchOn::Url => url.to_string(),
+                MatchOn::Path => url.split(['?', '#']).next().unwrap_or_default().to_string(),
+                MatchOn::Body => digest::digest(&digest::SHA256, body)
+                    .as_ref()
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect(),
+                MatchOn::Header(name) => headers
+                    .get_all(name)
+                    .iter()
+                    .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            })
+            .collect();
+        parts.join("\n")
+    }
+}
+
+/// Headers from a HAR `headers` list, skipping invalid ones
+fn har_headers(list: &Value) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for h in list.as_array().into_iter().flatten() {
+        let (Some(name), Some(value)) = (h["name"].as_str(), h["value"].as_str()) else {
+            continue;
+        };
+        if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+            headers.append(name, value);
+        }
+    }
+    headers
+}
+
+/// The bytes of a HAR `content` or `postData` object
+fn content(content: &Value) -> Bytes {
+    let text = content["text"].as_str().unwrap_or_default();
+    match content["encoding"].as_str() {
+        Some("base64") => BASE64.decode(text).map(Bytes::from).unwrap_or_default(),
+        _ => Bytes::from(text.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HAR: &str = r#"{"log": {"version": "1.2", "entries": [
+        {"request": {"method": "GET", "url": "https://api.example.com/status?x=1", "headers": [{"name": "Accept", "value": "application/json"}]},
+         "response": {"status": 200, "headers": [{"name": "Content-Type", "value": "application/json"}], "content": {"text": "{\"n\":1}"}}},
+        {"request": {"method": "GET", "url": "https://api.example.com/status?x=2", "headers": [{"name": "Accept", "value": "application/json"}]},
+         "response": {"status": 200, "headers": [], "content": {"text": "{\"n\":2}"}}},
+        {"request": {"method": "POST", "url": "https://api.example.com/upload", "headers": [], "postData": {"text": "AAE=", "encoding": "base64"}},
+         "response": {"status": 201, "headers": [], "content": {"text": "AP8=", "encoding": "base64"}}},
+        {"request": {"method": "GET", "url": "https://api.example.com/down", "headers": []},
+         "response": {"status": 0, "headers": [], "content": {}}}
+    ]}}"#;
+
+    fn cassette(match_on: &[&str]) -> Cassette {
+        let path = std::env::temp_dir().join(format!("holy-cors-replay-{}-{}.har", std::process::id(), match_on.len()));
+        std::fs::write(&path, HAR).unwrap();
+        let match_on: Vec<String> = match_on.iter().map(|s| s.to_string()).collect();
+        let cassette = Cassette::load(&path, ReplayMode::Strict, &match_on).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        cassette
+    }
+
+    async fn body(response: Response<BoxBody<Bytes, hyper::Error>>) -> Bytes {
+        response.into_body().collect().await.unwrap().to_bytes()
+    }
+
+    #[tokio::test]
+    async fn test_replay_in_order() {
+        let cassette = cassette(&["method", "path"]);
+        let url = "https://api.example.com/status?x=3";
+        let headers = HeaderMap::new();
+        assert_eq!(body(cassette.find(&Method::GET, url, &headers, b"").unwrap()).await, r#"{"n":1}"#);
+        assert_eq!(body(cassette.find(&Method::GET, url, &headers, b"").unwrap()).await, r#"{"n":2}"#);
+        // The last response repeats
+        assert_eq!(body(cassette.find(&Method::GET, url, &headers, b"").unwrap()).await, r#"{"n":2}"#);
+        assert!(cassette.find(&Method::POST, url, &headers, b"").is_none());
+        assert!(cassette.find(&Method::GET, "https://api.example.com/down", &headers, b"").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_match_body_and_headers() {
+        let cassette = cassette(&["method", "url", "body", "header:accept"]);
+        let upload = "https://api.example.com/upload";
+        let response = cassette.find(&Method::POST, upload, &HeaderMap::new(), &[0, 1]).unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(body(response).await, &[0, 255][..]);
+        assert!(cassette.find(&Method::POST, upload, &HeaderMap::new(), &[0, 2]).is_none());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_static("text/html"));
+        assert!(cassette.find(&Method::GET, "https://api.example.com/status?x=1", &headers, b"").is_none());
+    }
+
+    #[test]
+    fn test_parse_match() {
+        assert_eq!(MatchOn::parse("header:X-Tenant"), Ok(MatchOn::Header(HeaderName::from_static("x-tenant"))));
+        assert!(MatchOn::parse("query").is_err());
+        assert!(MatchOn::parse("header:Authorization").is_err());
+    }
+
+    #[test]
+    fn test_changed() {
+        use clap::Parser;
+        let old = Config::parse_from(["holy-cors", "--replay", "api.har"]);
+        assert!(!Cassette::changed(&old, &Config::parse_from(["holy-cors", "--replay", "api.har", "--verbose"])));
+        assert!(Cassette::changed(&old, &Config::parse_from(["holy-cors", "--replay", "api.har", "--replay-match", "path"])));
+        assert!(Cassette::changed(&old, &Config::parse_from(["holy-cors"])));
+    }
+}
@@ -0,0 +1,198 @@
+use http::uri::{Authority, Uri};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::TokioIo;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::net::TcpStream;
+use tower_service::Service;
+use tracing::debug;
+
+/// A curl-style `--resolve HOST:PORT:ADDR` entry
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolveOverride {
+    /// Host name to match (lowercase)
+    pub host: String,
+    /// Port to match, `None` for any port (`*`)
+    pub port: Option<u16>,
+    /// Address to connect to instead
+    pub addr: IpAddr,
+    /// Port to connect to instead, `None` to keep the requested port
+    pub addr_port: Option<u16>,
+}
+
+impl ResolveOverride {
+    /// Parse `HOST:PORT:ADDR[:PORT]`, where PORT may be `*` and ADDR may be a bracketed IPv6 address
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let invalid = || format!("--resolve expects HOST:PORT:ADDR[:PORT], got '{}'", s);
+
+        let mut parts = s.splitn(3, ':');
+        let (host, port, addr) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(host), Some(port), Some(addr)) if !host.is_empty() => (host, port, addr),
+            _ => return Err(invalid()),
+        };
+
+        let port = match port {
+            "*" => None,
+            port => Some(port.parse::<u16>().map_err(|_| invalid())?),
+        };
+
+        // ADDR is an IP, a bracketed IPv6, or either of those with a port
+        let (addr, addr_port) = if let Ok(sock) = addr.parse::<SocketAddr>() {
+            (sock.ip(), Some(sock.port()))
+        } else {
+            let ip = addr.strip_prefix('[').and_then(|a| a.strip_suffix(']')).unwrap_or(addr);
+            (ip.parse::<IpAddr>().map_err(|_| invalid())?, None)
+        };
+
+        Ok(Self {
+            host: host.to_ascii_lowercase(),
+            port,
+            addr,
+            addr_port,
+        })
+    }
+}
+
+/// DNS overrides applied to every upstream connection
+#[derive(Debug, Clone, Default)]
+pub struct Resolver {
+    overrides: Arc<Vec<ResolveOverride>>,
+}
+
+impl Resolver {
+    pub fn new(overrides: Vec<ResolveOverride>) -> Self {
+        Self {
+            overrides: Arc::new(overrides),
+        }
+    }
+
+    /// Get the address to connect to for a host and port, if overridden
+    pub fn lookup(&self, host: &str, port: u16) -> Option<SocketAddr> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        self.overrides
+            .iter()
+            .find(|o| o.host.eq_ignore_ascii_case(host) && o.port.is_none_or(|p| p == port))
+            .map(|o| SocketAddr::new(o.addr, o.addr_port.unwrap_or(port)))
+    }
+
+    /// Open a TCP connection to a host, honouring the overrides
+    pub async fn connect(&self, host: &str, port: u16) -> std::io::Result<TcpStream> {
+        match self.lookup(host, port) {
+            Some(addr) => {
+                debug!("Resolved {}:{} -> {} (override)", host, port, addr);
+                TcpStream::connect(addr).await
+            }
+            None => TcpStream::connect((host, port)).await,
+        }
+    }
+}
+
+/// HTTP connector that sends overridden hosts to their configured address
+///
+/// Only the TCP destination changes: the Host header and TLS SNI still use
+/// the original host, since the TLS layer wraps this connector.
+#[derive(Clone)]
+pub struct ResolvingConnector {
+    inner: HttpConnector,
+    resolver: Resolver,
+}
+
+impl ResolvingConnector {
+    pub fn new(resolver: Resolver) -> Self {
+        let mut inner = HttpConnector::new();
+        inner.enforce_http(false);
+        Self { inner, resolver }
+    }
+}
+
+impl Service<Uri> for ResolvingConnector {
+    type Response = TokioIo<TcpStream>;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let dst = match rewrite_destination(&self.resolver, dst) {
+            Ok(dst) => dst,
+            Err(e) => return Box::pin(async move { Err(e) }),
+        };
+        let connecting = self.inner.call(dst);
+        Box::pin(async move { connecting.await.map_err(Into::into) })
+    }
+}
+
+/// Replace the authority of a URI with its override address, if any
+fn rewrite_destination(
+    resolver: &Resolver,
+    dst: Uri,
+) -> Result<Uri, Box<dyn std::error::Error + Send + Sync>> {
+    let Some(host) = dst.host() else {
+        return Ok(dst);
+    };
+    let default_port = if dst.scheme_str() == Some("https") { 443 } else { 80 };
+    let port = dst.port_u16().unwrap_or(default_port);
+
+    let Some(addr) = resolver.lookup(host, port) else {
+        return Ok(dst);
+    };
+    debug!("Resolved {}:{} -> {} (override)", host, port, addr);
+
+    let mut parts = dst.into_parts();
+    parts.authority = Some(Authority::try_from(addr.to_string())?);
+    Ok(Uri::from_parts(parts)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_override() {
+        let o = ResolveOverride::parse("API.example.com:443:127.0.0.1").unwrap();
+        assert_eq!(o.host, "api.example.com");
+        assert_eq!(o.port, Some(443));
+        assert_eq!(o.addr_port, None);
+
+        let o = ResolveOverride::parse("api.example.com:*:[::1]:8443").unwrap();
+        assert_eq!(o.port, None);
+        assert_eq!(o.addr, "::1".parse::<IpAddr>().unwrap());
+        assert_eq!(o.addr_port, Some(8443));
+
+        assert!(ResolveOverride::parse("api.example.com:443").is_err());
+        assert!(ResolveOverride::parse("api.example.com:443:not-an-ip").is_err());
+    }
+
+    #[test]
+    fn test_lookup() {
+        let resolver = Resolver::new(vec![
+            ResolveOverride::parse("api.example.com:443:127.0.0.1:8443").unwrap(),
+            ResolveOverride::parse("canary.example.com:*:10.0.0.5").unwrap(),
+        ]);
+
+        assert_eq!(
+            resolver.lookup("api.example.com", 443),
+            Some("127.0.0.1:8443".parse().unwrap())
+        );
+        assert_eq!(resolver.lookup("api.example.com", 80), None);
+        assert_eq!(
+            resolver.lookup("canary.example.com", 8080),
+            Some("10.0.0.5:8080".parse().unwrap())
+        );
+        assert_eq!(resolver.lookup("other.example.com", 443), None);
+    }
+
+    #[test]
+    fn test_rewrite_destination_keeps_scheme_and_path() {
+        let resolver = Resolver::new(vec![ResolveOverride::parse("api.example.com:443:127.0.0.1:8443").unwrap()]);
+        let dst: Uri = "https://api.example.com/v1".parse().unwrap();
+
+        let rewritten = rewrite_destination(&resolver, dst).unwrap();
+        assert_eq!(rewritten.to_string(), "https://127.0.0.1:8443/v1");
+    }
+}
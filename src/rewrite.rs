@@ -0,0 +1,604 @@
+use bytes::Bytes;
+use http::{header, HeaderMap};
+use http_body_util::combinators::BoxBody;
+use hyper::body::{Body, Frame};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use crate::config::ProxyPrefix;
+use crate::redirect::rewrite_location;
+
+/// HTML attributes holding a URL (`srcset` holds a list of them)
+const URL_ATTRIBUTES: &[&str] = &["href", "src", "action", "formaction", "poster", "background", "srcset"];
+
+/// Bytes held back at the end of a CSS chunk in case a keyword is split (`@import`)
+const CSS_KEYWORD_LEN: usize = 7;
+
+/// Markup left unfinished for longer than this is passed through untouched
+const MAX_PENDING: usize = 1024 * 1024;
+
+/// Kinds of documents whose URLs are rewritten
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContentKind {
+    Html,
+    Css,
+}
+
+impl ContentKind {
+    /// The kind of a response that can be rewritten: HTML or CSS, not compressed
+    pub fn of(headers: &HeaderMap) -> Option<Self> {
+        let encoding = headers.get(header::CONTENT_ENCODING).and_then(|v| v.to_str().ok());
+        if encoding.is_some_and(|e| !e.trim().eq_ignore_ascii_case("identity")) {
+            return None;
+        }
+        let content_type = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+        let mime = content_type.split(';').next()?.trim().to_ascii_lowercase();
+        match mime.as_str() {
+            "text/html" | "application/xhtml+xml" => Some(ContentKind::Html),
+            "text/css" => Some(ContentKind::Css),
+            _ => None,
+        }
+    }
+}
+
+/// Where references in the document resolve and the prefix they map through
+struct Links {
+    base: String,
+    prefix: ProxyPrefix,
+}
+
+impl Links {
+    fn rewrite(&self, reference: &str) -> Option<String> {
+        let reference = reference.trim();
+        if reference.is_empty() || reference.starts_with('#') {
+            return None;
+        }
+        rewrite_location(reference, &self.base, &self.prefix)
+    }
+}
+
+/// Elements whose contents are not markup
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RawText {
+    Script,
+    Style,
+}
+
+/// Streaming rewriter pointing the URLs of an HTML or CSS document at the proxy.
+/// Input may be split anywhere; unfinished markup is held back for the next chunk.
+pub struct UrlRewriter {
+    kind: ContentKind,
+    links: Links,
+    pending: Vec<u8>,
+    raw_text: Option<RawText>,
+    /// Whether the document has a `<base>` or one was injected
+    has_base: bool,
+}
+
+impl UrlRewriter {
+    /// A rewriter for a document fetched from `target_url`
+    pub fn new(kind: ContentKind, target_url: &str, prefix: ProxyPrefix) -> Self {
+        Self {
+            kind,
+            links: Links {
+                base: target_url.to_string(),
+                prefix,
+            },
+            pending: Vec::new(),
+            raw_text: None,
+            has_base: false,
+        }
+    }
+
+    /// Rewrite the next chunk of the document
+    pub fn write(&mut self, chunk: &[u8]) -> Vec<u8> {
+        self.pending.extend_from_slice(chunk);
+        self.process(false)
+    }
+
+    /// Rewrite whatever is left at the end of the document
+    pub fn finish(&mut self) -> Vec<u8> {
+        self.process(true)
+    }
+
+    fn process(&mut self, last: bool) -> Vec<u8> {
+        let input = std::mem::take(&mut self.pending);
+        let last = last || input.len() > MAX_PENDING;
+        let mut out = Vec::with_capacity(input.len());
+        let consumed = match self.kind {
+            ContentKind::Html => self.html(&input, last, &mut out),
+            ContentKind::Css => css(&self.links, &input, last, &mut out),
+        };
+        self.pending = input[consumed..].to_vec();
+        out
+    }
+
+    /// Rewrite markup, returning how much of the input was consumed
+    fn html(&mut self, input: &[u8], last: bool, out: &mut Vec<u8>) -> usize {
+        let mut i = 0;
+        while i < input.len() {
+            if let Some(raw) = self.raw_text {
+                let close: &[u8] = match raw {
+                    RawText::Script => b"</script",
+                    RawText::Style => b"</style",
+                };
+                let end = match find_ignore_case(input, i, close) {
+                    Some(end) => end,
+                    None if last => input.len(),
+                    None => {
+                        // Keep what could be the start of the closing tag
+                        let end = input.len().saturating_sub(close.len() - 1).max(i);
+                        return i + self.raw_text(raw, &input[i..end], false, out);
+                    }
+                };
+                i += self.raw_text(raw, &input[i..end], true, out);
+                self.raw_text = None;
+                continue;
+            }
+
+            let Some(lt) = input[i..].iter().position(|&b| b == b'<').map(|p| i + p) else {
+                out.extend_from_slice(&input[i..]);
+                return input.len();
+            };
+            out.extend_from_slice(&input[i..lt]);
+            i = lt;
+            let rest = &input[i..];
+
+            if rest.starts_with(b"<!--") {
+                match find_ignore_case(rest, 4, b"-->") {
+                    Some(end) => {
+                        out.extend_from_slice(&rest[..end + 3]);
+                        i += end + 3;
+                        continue;
+                    }
+                    None if last => break,
+                    None => return i,
+                }
+            }
+            let Some(&next) = rest.get(1) else {
+                if last {
+                    break;
+                }
+                return i;
+            };
+            if !(next.is_ascii_alphabetic() || matches!(next, b'/' | b'!' | b'?')) {
+                out.push(b'<');
+                i += 1;
+                continue;
+            }
+            match tag_end(rest) {
+                Some(end) => {
+                    self.tag(&rest[..=end], out);
+                    i += end + 1;
+                }
+                None if last => break,
+                None => return i,
+            }
+        }
+        out.extend_from_slice(&input[i..]);
+        input.len()
+    }
+
+    /// Pass script through and rewrite style, returning how much was consumed
+    fn raw_text(&self, raw: RawText, text: &[u8], last: bool, out: &mut Vec<u8>) -> usize {
+        match raw {
+            RawText::Script => {
+                out.extend_from_slice(text);
+                text.len()
+            }
+            RawText::Style => css(&self.links, text, last, out),
+        }
+    }
+
+    /// Rewrite the URL attributes of one tag, injecting `<base>` before the body
+    fn tag(&mut self, tag: &[u8], out: &mut Vec<u8>) {
+        let closing = tag.get(1) == Some(&b'/');
+        let name_start = if closing { 2 } else { 1 };
+        let name_len = tag[name_start..]
+            .iter()
+            .position(|&b| !(b.is_ascii_alphanumeric() || b == b'-' || b == b':'))
+            .unwrap_or(tag.len() - name_start);
+        let name = String::from_utf8_lossy(&tag[name_start..name_start + name_len]).to_ascii_lowercase();
+
+        if (closing && name == "head") || (!closing && name == "body") {
+            self.inject_base(out);
+        }
+        if closing || name.is_empty() {
+            out.extend_from_slice(tag);
+            return;
+        }
+
+        let self_closing = tag.ends_with(b"/>");
+        match name.as_str() {
+            "script" if !self_closing => self.raw_text = Some(RawText::Script),
+            "style" if !self_closing => self.raw_text = Some(RawText::Style),
+            "base" => self.has_base = true,
+            _ => {}
+        }
+
+        let mut copied = 0;
+        for attr in attributes(tag, name_start + name_len) {
+            let Some((start, end, quote)) = attr.value else {
+                continue;
+            };
+            let Ok(value) = std::str::from_utf8(&tag[start..end]) else {
+                continue;
+            };
+            let value = value.replace("&amp;", "&");
+            let rewritten = match attr.name.as_str() {
+                "srcset" => self.rewrite_srcset(&value),
+                "style" => {
+                    let style = value.replace("&quot;", "\"");
+                    let mut css_out = Vec::new();
+                    css(&self.links, style.as_bytes(), true, &mut css_out);
+                    String::from_utf8(css_out).ok().filter(|css_out| *css_out != style)
+                }
+                "href" if name == "base" => {
+                    // Later references resolve against the document's own base
+                    if let Some(base) = url::Url::parse(&self.links.base).ok().and_then(|b| b.join(&value).ok()) {
+                        let rewritten = self.links.rewrite(base.as_str());
+                        self.links.base = base.to_string();
+                        rewritten
+                    } else {
+                        None
+                    }
+                }
+                attr_name if URL_ATTRIBUTES.contains(&attr_name) => self.links.rewrite(&value),
+                _ => None,
+            };
+            let Some(rewritten) = rewritten else {
+                continue;
+            };
+
+            out.extend_from_slice(&tag[copied..start]);
+            let escaped = rewritten.replace('&', "&amp;");
+            match quote {
+                Some(b'\'') => out.extend_from_slice(escaped.replace('\'', "&#39;").as_bytes()),
+                Some(_) => out.extend_from_slice(escaped.replace('"', "&quot;").as_bytes()),
+                None => {
+                    out.push(b'"');
+                    out.extend_from_slice(escaped.replace('"', "&quot;").as_bytes());
+                    out.push(b'"');
+                }
+            }
+            copied = end;
+        }
+        out.extend_from_slice(&tag[copied..]);
+    }
+
+    /// Rewrite each candidate of a `srcset` list
+    fn rewrite_srcset(&self, value: &str) -> Option<String> {
+        let mut changed = false;
+        let candidates: Vec<String> = value
+            .split(',')
+            .map(|candidate| {
+                let candidate = candidate.trim();
+                let (url, descriptor) = candidate.split_once(char::is_whitespace).unwrap_or((candidate, ""));
+                match self.links.rewrite(url) {
+                    Some(url) => {
+                        changed = true;
+                        format!("{} {}", url, descriptor.trim()).trim_end().to_string()
+                    }
+                    None => candidate.to_string(),
+                }
+            })
+            .collect();
+        changed.then(|| candidates.join(", "))
+    }
+
+    /// Add a `<base>` for the document's proxy URL unless it has its own, so
+    /// relative URLs built by scripts resolve through the proxy
+    fn inject_base(&mut self, out: &mut Vec<u8>) {
+        if self.has_base {
+            return;
+        }
+        self.has_base = true;
+        if let Some(href) = self.links.rewrite(&self.links.base) {
+            let href = href.replace('&', "&amp;").replace('"', "&quot;");
+            out.extend_from_slice(format!(r#"<base href="{}">"#, href).as_bytes());
+        }
+    }
+}
+
+/// One attribute of a tag; `value` is the byte range of its value and its quote
+struct Attribute {
+    name: String,
+    value: Option<(usize, usize, Option<u8>)>,
+}
+
+/// Parse the attributes of a complete tag, starting after its name
+fn attributes(tag: &[u8], mut i: usize) -> Vec<Attribute> {
+    let mut attributes = Vec::new();
+    let end = tag.len() - 1;
+    loop {
+        while i < end && (tag[i].is_ascii_whitespace() || tag[i] == b'/') {
+            i += 1;
+        }
+        if i >= end {
+            return attributes;
+        }
+
+        let name_start = i;
+        while i < end && !tag[i].is_ascii_whitespace() && !matches!(tag[i], b'=' | b'/') {
+            i += 1;
+        }
+        let name = String::from_utf8_lossy(&tag[name_start..i]).to_ascii_lowercase();
+        while i < end && tag[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i >= end || tag[i] != b'=' {
+            attributes.push(Attribute { name, value: None });
+            continue;
+        }
+        i += 1;
+        while i < end && tag[i].is_ascii_whitespace() {
+            i += 1;
+        }
+
+        let value = match tag.get(i) {
+            Some(&q) if q == b'"' || q == b'\'' => {
+                let start = i + 1;
+                let len = tag[start..end].iter().position(|&b| b == q).unwrap_or(end - start);
+                i = (start + len + 1).min(end);
+                (start, start + len, Some(q))
+            }
+            _ => {
+                let start = i;
+                while i < end && !tag[i].is_ascii_whitespace() {
+                    i += 1;
+                }
+                (start, i, None)
+            }
+        };
+        attributes.push(Attribute { name, value: Some(value) });
+    }
+}
+
+/// Index of the `>` ending the tag at the start of `input`, skipping quoted values
+fn tag_end(input: &[u8]) -> Option<usize> {
+    let mut quote = None;
+    let mut after_equals = false;
+    for (i, &b) in input.iter().enumerate().skip(1) {
+        match quote {
+            Some(q) if b == q => quote = None,
+            Some(_) => {}
+            None if b == b'>' => return Some(i),
+            None if (b == b'"' || b == b'\'') && after_equals => quote = Some(b),
+            None if b == b'=' => after_equals = true,
+            None if !b.is_ascii_whitespace() => after_equals = false,
+            None => {}
+        }
+    }
+    None
+}
+
+/// Rewrite `url()` and `@import "..."` references in CSS, returning how much
+/// of the input was consumed
+fn css(links: &Links, input: &[u8], last: bool, out: &mut Vec<u8>) -> usize {
+    let mut i = 0;
+    let mut copied = 0;
+    while i < input.len() {
+        let rest = &input[i..];
+        let value = if starts_with_ignore_case(rest, b"url(") && !is_ident(input, i) {
+            css_url(input, i + 4)
+        } else if starts_with_ignore_case(rest, b"@import") {
+            css_import(input, i + 7)
+        } else {
+            i += 1;
+            continue;
+        };
+
+        let Some((start, end, close)) = value else {
+            if last {
+                i += 1;
+                continue;
+            }
+            // Wait for the rest of the reference
+            out.extend_from_slice(&input[copied..i]);
+            return i;
+        };
+
+        let rewritten = std::str::from_utf8(&input[start..end]).ok().and_then(|url| links.rewrite(url));
+        if let Some(rewritten) = rewritten {
+            out.extend_from_slice(&input[copied..start]);
+            match input[start.saturating_sub(1)] {
+                q @ (b'"' | b'\'') => {
+                    let encoded = if q == b'"' { "%22" } else { "%27" };
+                    out.extend_from_slice(rewritten.replace(q as char, encoded).as_bytes());
+                }
+                _ => out.extend_from_slice(rewritten.replace(['(', ')', ' '], "").as_bytes()),
+            }
+            copied = end;
+        }
+        i = close;
+    }
+
+    if last {
+        out.extend_from_slice(&input[copied..]);
+        return input.len();
+    }
+    let end = input.len().saturating_sub(CSS_KEYWORD_LEN).max(copied);
+    out.extend_from_slice(&input[copied..end]);
+    end
+}
+
+/// The value range of `url(...)` starting at `i` (after the parenthesis) and
+/// where the reference ends, if it is complete
+fn css_url(input: &[u8], mut i: usize) -> Option<(usize, usize, usize)> {
+    while input.get(i)?.is_ascii_whitespace() {
+        i += 1;
+    }
+    let (start, end) = match input[i] {
+        q @ (b'"' | b'\'') => {
+            let len = input[i + 1..].iter().position(|&b| b == q)?;
+            (i + 1, i + 1 + len)
+        }
+        _ => {
+            let len = input[i..].iter().position(|&b| b == b')')?;
+            let value = &input[i..i + len];
+            let trimmed = value.len() - value.iter().rev().take_while(|b| b.is_ascii_whitespace()).count();
+            (i, i + trimmed)
+        }
+    };
+    let close = input[end..].iter().position(|&b| b == b')')? + end + 1;
+    Some((start, end, close))
+}
+
+/// The value range of `@import "..."` starting at `i` (after the keyword);
+/// `@import url(...)` is handled as a `url()`
+fn css_import(input: &[u8], mut i: usize) -> Option<(usize, usize, usize)> {
+    while input.get(i)?.is_ascii_whitespace() {
+        i += 1;
+    }
+    match input[i] {
+        q @ (b'"' | b'\'') => {
+            let len = input[i + 1..].iter().position(|&b| b == q)?;
+            Some((i + 1, i + 1 + len, i + len + 2))
+        }
+        // Not a string: skip the keyword
+        _ => Some((i, i, i)),
+    }
+}
+
+/// Whether the byte before `i` continues an identifier (so `url(` is part of another name)
+fn is_ident(input: &[u8], i: usize) -> bool {
+    i > 0 && (input[i - 1].is_ascii_alphanumeric() || matches!(input[i - 1], b'-' | b'_'))
+}
+
+fn starts_with_ignore_case(input: &[u8], prefix: &[u8]) -> bool {
+    input.len() >= prefix.len() && input[..prefix.len()].eq_ignore_ascii_case(prefix)
+}
+
+fn find_ignore_case(input: &[u8], from: usize, needle: &[u8]) -> Option<usize> {
+    (from..=input.len().checked_sub(needle.len())?).find(|&i| starts_with_ignore_case(&input[i..], needle))
+}
+
+/// A response body streamed through a `UrlRewriter`
+pub struct RewriteBody {
+    inner: BoxBody<Bytes, hyper::Error>,
+    rewriter: UrlRewriter,
+    trailers: Option<Frame<Bytes>>,
+    done: bool,
+}
+
+impl RewriteBody {
+    pub fn new(inner: BoxBody<Bytes, hyper::Error>, rewriter: UrlRewriter) -> Self {
+        Self {
+            inner,
+            rewriter,
+            trailers: None,
+            done: false,
+        }
+    }
+}
+
+impl Body for RewriteBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, hyper::Error>>> {
+        loop {
+            if self.done {
+                return Poll::Ready(self.trailers.take().map(Ok));
+            }
+            let frame = match ready!(Pin::new(&mut self.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => frame,
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => {
+                    self.done = true;
+                    let rest = self.rewriter.finish();
+                    if !rest.is_empty() {
+                        return Poll::Ready(Some(Ok(Frame::data(Bytes::from(rest)))));
+                    }
+                    continue;
+                }
+            };
+            match frame.into_data() {
+                Ok(data) => {
+                    let out = self.rewriter.write(&data);
+                    if !out.is_empty() {
+                        return Poll::Ready(Some(Ok(Frame::data(Bytes::from(out)))));
+                    }
+                }
+                // Trailers end the body: flush the document first
+                Err(trailers) => {
+                    self.trailers = Some(trailers);
+                    self.done = true;
+                    let rest = self.rewriter.finish();
+                    if !rest.is_empty() {
+                        return Poll::Ready(Some(Ok(Frame::data(Bytes::from(rest)))));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewriter(kind: ContentKind) -> UrlRewriter {
+        let prefix = ProxyPrefix {
+            prefix: "/https://example.com".to_string(),
+            base: String::new(),
+        };
+        UrlRewriter::new(kind, "https://example.com/docs/index.html", prefix)
+    }
+
+    /// Rewrite `input` fed one byte at a time, checking it matches a single write
+    fn rewrite(kind: ContentKind, input: &str) -> String {
+        let mut whole = rewriter(kind);
+        let mut expected = whole.write(input.as_bytes());
+        expected.extend(whole.finish());
+
+        let mut split = rewriter(kind);
+        let mut out = Vec::new();
+        for byte in input.as_bytes() {
+            out.extend(split.write(&[*byte]));
+        }
+        out.extend(split.finish());
+        assert_eq!(String::from_utf8_lossy(&out), String::from_utf8_lossy(&expected));
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_rewrite_html() {
+        let html = r##"<html><head><link rel=stylesheet href="style.css"></head>
+<body><a href='/about?a=1&amp;b=2' title="x > y">About</a> <a href="#top">Top</a>
+<img srcset="a.png 1x, https://cdn.example.net/b.png 2x" src=//cdn.example.net/c.png>
+<form action="/login"><!-- <a href="/hidden"> --></form>
+<script>if (a < b) location = "/x";</script><a href="mailto:me@example.com">Mail</a>
+<div style="background: url('/bg.png')">1 < 2</div></body></html>"##;
+
+        assert_eq!(
+            rewrite(ContentKind::Html, html),
+            r##"<html><head><link rel=stylesheet href="/https://example.com/docs/style.css"><base href="/https://example.com/docs/index.html"></head>
+<body><a href='/https://example.com/about?a=1&amp;b=2' title="x > y">About</a> <a href="#top">Top</a>
+<img srcset="/https://example.com/docs/a.png 1x, /https://cdn.example.net/b.png 2x" src="/https://cdn.example.net/c.png">
+<form action="/https://example.com/login"><!-- <a href="/hidden"> --></form>
+<script>if (a < b) location = "/x";</script><a href="mailto:me@example.com">Mail</a>
+<div style="background: url('/https://example.com/bg.png')">1 < 2</div></body></html>"##
+        );
+    }
+
+    #[test]
+    fn test_document_base() {
+        let html = r#"<head><base href="https://example.com/app/"></head><body><img src="logo.png"></body>"#;
+        assert_eq!(
+            rewrite(ContentKind::Html, html),
+            r#"<head><base href="/https://example.com/app/"></head><body><img src="/https://example.com/app/logo.png"></body>"#
+        );
+    }
+
+    #[test]
+    fn test_rewrite_css() {
+        let css = r#"@import "theme.css"; @import url(print.css) print;
+body { background: URL( /img/bg.png ) } .a { mask: url("data:image/svg+xml;x") }"#;
+        assert_eq!(
+            rewrite(ContentKind::Css, css),
+            r#"@import "/https://example.com/docs/theme.css"; @import url(/https://example.com/docs/print.css) print;
+body { background: URL( /https://example.com/img/bg.png ) } .a { mask: url("data:image/svg+xml;x") }"#
+        );
+    }
+}
@@ -0,0 +1,357 @@
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
+use tracing::warn;
+
+/// What a proxied request is for, used to select and render rules
+#[derive(Debug, Clone, Default)]
+pub struct RuleContext {
+    /// Host of the target URL (empty for Unix socket targets)
+    pub host: String,
+    /// Route alias the request came through, if any
+    pub route: Option<String>,
+    /// Browser origin (empty for non-browser requests)
+    pub origin: String,
+    /// Full target URL
+    pub target_url: String,
+}
+
+impl RuleContext {
+    /// `scheme://host[:port]` of the target
+    pub fn target_origin(&self) -> String {
+        url::Url::parse(&self.target_url)
+            .map(|u| u.origin().ascii_serialization())
+            .unwrap_or_default()
+    }
+}
+
+/// Check a rule's optional `host` and `route` against a request;
+/// a rule with neither applies to every request
+pub fn rule_matches(host: Option<&str>, route: Option<&str>, ctx: &RuleContext) -> bool {
+    let host_ok = host.is_none_or(|pattern| host_matches(pattern, &ctx.host));
+    let route_ok = route.is_none_or(|route| ctx.route.as_deref() == Some(route));
+    host_ok && route_ok
+}
+
+/// Match a host against `example.com` or `*.example.com`
+pub fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => host
+            .len()
+            .checked_sub(suffix.len() + 1)
+            .is_some_and(|i| host.as_bytes()[i] == b'.' && host[i + 1..].eq_ignore_ascii_case(suffix)),
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+/// Header rewrite rule: remove, then rename, then set
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderRule {
+    /// Target host, exact or `*.example.com`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Route alias name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    /// Headers to drop; a trailing `*` matches a prefix (`Sec-Fetch-*`)
+    #[serde(default, deserialize_with = "header_patterns", skip_serializing_if = "Vec::is_empty")]
+    pub remove: Vec<String>,
+    /// Headers to rename, old name -> new name
+    #[serde(default, deserialize_with = "header_renames", skip_serializing_if = "BTreeMap::is_empty")]
+    pub rename: BTreeMap<String, String>,
+    /// Headers to set (replacing any existing value), with `{placeholders}`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub set: BTreeMap<HeaderKey, Template>,
+}
+
+impl HeaderRule {
+    pub fn matches(&self, ctx: &RuleContext) -> bool {
+        rule_matches(self.host.as_deref(), self.route.as_deref(), ctx)
+    }
+
+    /// Apply this rule to a header map. `source` holds the headers
+    /// placeholders like `{header:Name}` read from.
+    pub fn apply(&self, headers: &mut HeaderMap, ctx: &RuleContext, source: &HeaderMap) {
+        for pattern in &self.remove {
+            remove_matching(headers, pattern);
+        }
+
+        for (from, to) in &self.rename {
+            // Names were validated when the config was loaded
+            let (Ok(from), Ok(to)) = (HeaderName::from_bytes(from.as_bytes()), HeaderName::from_bytes(to.as_bytes())) else {
+                continue;
+            };
+            let values: Vec<HeaderValue> = headers.get_all(&from).iter().cloned().collect();
+            headers.remove(&from);
+            for value in values {
+                headers.append(&to, value);
+            }
+        }
+
+        for (name, template) in &self.set {
+            let value = template.render(ctx, source);
+            match HeaderValue::from_str(&value) {
+                Ok(value) => {
+                    headers.insert(name.0.clone(), value);
+                }
+                Err(_) => warn!("Skipping header rule for {}: invalid value", name.0),
+            }
+        }
+    }
+}
+
+/// Apply every rule matching the request, in order
+pub fn apply_header_rules(rules: &[HeaderRule], headers: &mut HeaderMap, ctx: &RuleContext, source: &HeaderMap) {
+    for rule in rules.iter().filter(|rule| rule.matches(ctx)) {
+        rule.apply(headers, ctx, source);
+    }
+}
+
+fn remove_matching(headers: &mut HeaderMap, pattern: &str) {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => {
+            let prefix = prefix.to_ascii_lowercase();
+            let names: Vec<HeaderName> = headers
+                .keys()
+                .filter(|name| name.as_str().starts_with(&prefix))
+                .cloned()
+                .collect();
+            for name in names {
+                headers.remove(name);
+            }
+        }
+        None => {
+            headers.remove(pattern);
+        }
+    }
+}
+
+/// A validated header name usable as a TOML key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderKey(pub HeaderName);
+
+impl Ord for HeaderKey {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.as_str().cmp(other.0.as_str())
+    }
+}
+
+impl PartialOrd for HeaderKey {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'de> Deserialize<'de> for HeaderKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        HeaderName::from_bytes(name.as_bytes())
+            .map(HeaderKey)
+            .map_err(|_| serde::de::Error::custom(format!("invalid header name '{}'", name)))
+    }
+}
+
+impl Serialize for HeaderKey {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+/// Placeholders available in templates
+const PLACEHOLDERS: &[&str] = &["target_origin", "target_host", "target_url", "origin"];
+
+/// A string with `{placeholder}` substitutions:
+/// `{target_origin}`, `{target_host}`, `{target_url}`, `{origin}`,
+/// `{header:NAME}` (a header of the message being rewritten, before any
+/// rule ran) and `{env:NAME}`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct Template(String);
+
+impl Template {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        for placeholder in placeholders(s)? {
+            let known = PLACEHOLDERS.contains(&placeholder)
+                || placeholder.strip_prefix("header:").is_some_and(|n| HeaderName::from_bytes(n.as_bytes()).is_ok())
+                || placeholder.strip_prefix("env:").is_some_and(|n| !n.is_empty());
+            if !known {
+                return Err(format!(
+                    "unknown placeholder '{{{}}}' (expected one of {}, header:NAME, env:NAME)",
+                    placeholder,
+                    PLACEHOLDERS.join(", ")
+                ));
+            }
+        }
+        Ok(Template(s.to_string()))
+    }
+
+    /// Substitute placeholders; missing headers and variables become empty
+    pub fn render(&self, ctx: &RuleContext, source: &HeaderMap) -> String {
+        let mut out = String::with_capacity(self.0.len());
+        let mut rest = self.0.as_str();
+
+        while let Some(start) = rest.find('{') {
+            out.push_str(&rest[..start]);
+            let Some(len) = rest[start..].find('}') else {
+                break;
+            };
+            let name = &rest[start + 1..start + len];
+            rest = &rest[start + len + 1..];
+
+            match name {
+                "target_origin" => out.push_str(&ctx.target_origin()),
+                "target_host" => out.push_str(&ctx.host),
+                "target_url" => out.push_str(&ctx.target_url),
+                "origin" => out.push_str(&ctx.origin),
+                _ => {
+                    if let Some(header) = name.strip_prefix("header:") {
+                        if let Some(value) = source.get(header).and_then(|v| v.to_str().ok()) {
+                            out.push_str(value);
+                        }
+                    } else if let Some(var) = name.strip_prefix("env:") {
+                        out.push_str(&std::env::var(var).unwrap_or_default());
+                    }
+                }
+            }
+        }
+
+        out.push_str(rest);
+        out
+    }
+}
+
+impl<'de> Deserialize<'de> for Template {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Template::parse(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Names between `{` and `}` in a template
+fn placeholders(s: &str) -> Result<Vec<&str>, String> {
+    let mut names = Vec::new();
+    let mut rest = s;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            return Err(format!("unclosed '{{' in '{}'", s));
+        };
+        names.push(&rest[start + 1..start + len]);
+        rest = &rest[start + len + 1..];
+    }
+    Ok(names)
+}
+
+fn header_patterns<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let patterns = Vec::<String>::deserialize(deserializer)?;
+    for pattern in &patterns {
+        let name = pattern.strip_suffix('*').unwrap_or(pattern);
+        if !name.is_empty() && HeaderName::from_bytes(name.as_bytes()).is_err() {
+            return Err(serde::de::Error::custom(format!("invalid header name '{}'", pattern)));
+        }
+    }
+    Ok(patterns)
+}
+
+fn header_renames<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<String, String>, D::Error> {
+    let renames = BTreeMap::<String, String>::deserialize(deserializer)?;
+    for name in renames.keys().chain(renames.values()) {
+        if HeaderName::from_bytes(name.as_bytes()).is_err() {
+            return Err(serde::de::Error::custom(format!("invalid header name '{}'", name)));
+        }
+    }
+    Ok(renames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ctx() -> RuleContext {
+        RuleContext {
+            host: "api.example.com".to_string(),
+            route: Some("api".to_string()),
+            origin: "http://localhost:3000".to_string(),
+            target_url: "https://api.example.com:8443/v1/users".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_host_matches() {
+        assert!(host_matches("api.example.com", "API.example.com"));
+        assert!(host_matches("*.example.com", "api.example.com"));
+        assert!(!host_matches("*.example.com", "example.com"));
+        assert!(!host_matches("*.example.com", "api.notexample.com"));
+    }
+
+    #[test]
+    fn test_rule_matches() {
+        assert!(rule_matches(None, None, &ctx()));
+        assert!(rule_matches(Some("*.example.com"), Some("api"), &ctx()));
+        assert!(!rule_matches(None, Some("github"), &ctx()));
+        assert!(!rule_matches(Some("other.com"), None, &ctx()));
+    }
+
+    #[test]
+    fn test_apply_rule() {
+        let rule: HeaderRule = toml::from_str(
+            r#"
+            remove = ["Referer", "Sec-Fetch-*"]
+            rename = { "X-Token" = "Authorization" }
+            set = { "Origin" = "{target_origin}", "User-Agent" = "holy-cors ({header:User-Agent})" }
+            "#,
+        )
+        .unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("referer", HeaderValue::from_static("http://localhost:3000/"));
+        headers.insert("sec-fetch-mode", HeaderValue::from_static("cors"));
+        headers.insert("sec-fetch-site", HeaderValue::from_static("cross-site"));
+        headers.insert("x-token", HeaderValue::from_static("Bearer abc"));
+        headers.insert("origin", HeaderValue::from_static("http://localhost:3000"));
+        headers.insert("user-agent", HeaderValue::from_static("Firefox"));
+        let source = headers.clone();
+
+        rule.apply(&mut headers, &ctx(), &source);
+
+        assert!(!headers.contains_key("referer"));
+        assert!(!headers.contains_key("sec-fetch-mode"));
+        assert!(!headers.contains_key("sec-fetch-site"));
+        assert!(!headers.contains_key("x-token"));
+        assert_eq!(headers["authorization"], "Bearer abc");
+        assert_eq!(headers["origin"], "https://api.example.com:8443");
+        assert_eq!(headers["user-agent"], "holy-cors (Firefox)");
+    }
+
+    #[test]
+    fn test_strip_response_headers() {
+        let rule: HeaderRule = toml::from_str(
+            r#"
+            host = "*.example.com"
+            remove = ["X-Frame-Options", "Content-Security-Policy", "Strict-Transport-Security"]
+            set = { "Cross-Origin-Resource-Policy" = "cross-origin" }
+            "#,
+        )
+        .unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-frame-options", HeaderValue::from_static("DENY"));
+        headers.insert("content-security-policy", HeaderValue::from_static("frame-ancestors 'none'"));
+        headers.insert("cross-origin-resource-policy", HeaderValue::from_static("same-origin"));
+        let source = headers.clone();
+
+        apply_header_rules(&[rule], &mut headers, &ctx(), &source);
+
+        assert!(!headers.contains_key("x-frame-options"));
+        assert!(!headers.contains_key("content-security-policy"));
+        assert_eq!(headers["cross-origin-resource-policy"], "cross-origin");
+    }
+
+    #[test]
+    fn test_template_validation() {
+        assert!(Template::parse("{target_host}").is_ok());
+        assert!(Template::parse("{env:API_TOKEN}").is_ok());
+        assert!(Template::parse("{nope}").is_err());
+        assert!(Template::parse("{target_host").is_err());
+    }
+}
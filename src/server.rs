@@ -0,0 +1,184 @@
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use socket2::{Domain, Socket, Type};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tracing::error;
+
+use crate::config::ListenAddr;
+use crate::proxy::handle_request;
+use crate::shutdown::Shutdown;
+use crate::state::State;
+
+/// A bound listening socket
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, std::path::PathBuf),
+}
+
+impl Listener {
+    /// Bind all configured addresses
+    pub fn bind_all(addrs: &[ListenAddr]) -> io::Result<Vec<Listener>> {
+        addrs
+            .iter()
+            .map(|addr| {
+                Self::bind(addr, addrs).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", addr, e)))
+            })
+            .collect()
+    }
+
+    fn bind(addr: &ListenAddr, all: &[ListenAddr]) -> io::Result<Listener> {
+        match addr {
+            ListenAddr::Tcp(addr) => {
+                // An IPv6 wildcard also accepts IPv4, unless IPv4 is bound separately on the same port
+                let v4_bound = all.iter().any(
+                    |a| matches!(a, ListenAddr::Tcp(other) if other.is_ipv4() && other.port() == addr.port()),
+                );
+                let dual_stack = addr.is_ipv6() && addr.ip().is_unspecified() && !v4_bound;
+                bind_tcp(*addr, dual_stack).map(Listener::Tcp)
+            }
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                // Remove a stale socket left behind by a previous run
+                if std::fs::symlink_metadata(path).is_ok_and(|m| {
+                    use std::os::unix::fs::FileTypeExt;
+                    m.file_type().is_socket()
+                }) {
+                    std::fs::remove_file(path)?;
+                }
+                tokio::net::UnixListener::bind(path).map(|l| Listener::Unix(l, path.clone()))
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix sockets are not supported on this platform",
+            )),
+        }
+    }
+
+    /// Accept connections until shutdown, serving each with the proxy handler
+    pub async fn run(self, state: Arc<State>, shutdown: Arc<Shutdown>) {
+        loop {
+            let accepted = tokio::select! {
+                accepted = self.accept() => accepted,
+                _ = shutdown.wait() => break,
+            };
+
+            match accepted {
+                Ok(Accepted::Tcp(stream, addr)) => serve(stream, addr.to_string(), &state, &shutdown),
+                #[cfg(unix)]
+                Ok(Accepted::Unix(stream)) => serve(stream, "unix socket".to_string(), &state, &shutdown),
+                Err(e) => error!("Failed to accept connection: {}", e),
+            }
+        }
+
+        // Remove our socket file so the next run can bind it
+        #[cfg(unix)]
+        if let Listener::Unix(_, path) = &self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    async fn accept(&self) -> io::Result<Accepted> {
+        match self {
+            Listener::Tcp(listener) => listener
+                .accept()
+                .await
+                .map(|(stream, addr)| Accepted::Tcp(stream, addr)),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => listener.accept().await.map(|(stream, _)| Accepted::Unix(stream)),
+        }
+    }
+}
+
+/// A newly accepted connection
+enum Accepted {
+    Tcp(tokio::net::TcpStream, SocketAddr),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+/// Bind a TCP listener, optionally accepting IPv4 on an IPv6 socket
+fn bind_tcp(addr: SocketAddr, dual_stack: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!dual_stack)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+/// Spawn a task serving one connection
+///
+/// On shutdown the connection finishes its in-flight request and then closes.
+fn serve<I>(
+    stream: I,
+    remote_addr: String,
+    state: &Arc<State>,
+    shutdown: &Arc<Shutdown>,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let state = Arc::clone(state);
+    let shutdown = Arc::clone(shutdown);
+    let guard = shutdown.track_connection();
+
+    tokio::spawn(async move {
+        let _guard = guard;
+        let io = TokioIo::new(stream);
+
+        let service_shutdown = Arc::clone(&shutdown);
+        let service = service_fn(move |req| {
+            // Pick up the configuration in effect when the request arrives
+            let current = state.current();
+            let config = Arc::clone(&current.config);
+            let upstream = Arc::clone(&current.upstream);
+            let cookies = Arc::clone(state.cookies());
+            let recorder = state.recorder().cloned();
+            let admin = Arc::clone(state.admin());
+            let shutdown = Arc::clone(&service_shutdown);
+            async move {
+                let (method, uri) = (req.method().clone(), req.uri().clone());
+                let (started, start) = (SystemTime::now(), Instant::now());
+                let response = handle_request(req, config, upstream, cookies, recorder, Arc::clone(&admin), shutdown).await;
+                if let Ok(response) = &response {
+                    admin.log_request(&method, &uri, response.status(), started, start.elapsed());
+                }
+                response
+            }
+        });
+
+        let conn = http1::Builder::new()
+            .serve_connection(io, service)
+            .with_upgrades();
+        tokio::pin!(conn);
+
+        let mut draining = false;
+        let result = loop {
+            tokio::select! {
+                result = conn.as_mut() => break result,
+                _ = shutdown.wait(), if !draining => {
+                    draining = true;
+                    conn.as_mut().graceful_shutdown();
+                }
+            }
+        };
+
+        if let Err(e) = result {
+            if !e.to_string().contains("connection closed") {
+                error!("Connection error from {}: {}", remote_addr, e);
+            }
+        }
+    });
+}
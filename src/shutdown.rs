@@ -0,0 +1,133 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Coordinates graceful shutdown between listeners, connections and WebSockets
+pub struct Shutdown {
+    /// Flipped to `true` once shutdown starts
+    signal: watch::Sender<bool>,
+    /// Number of connections (and proxied WebSockets) still running
+    active: watch::Sender<usize>,
+    /// Total connections accepted since startup
+    accepted: AtomicUsize,
+}
+
+/// Keeps a connection counted as active until dropped
+pub struct ActiveGuard {
+    shutdown: Arc<Shutdown>,
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.shutdown.active.send_modify(|n| *n -= 1);
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            signal: watch::channel(false).0,
+            active: watch::channel(0).0,
+            accepted: AtomicUsize::new(0),
+        })
+    }
+
+    /// Count a new connection as active
+    pub fn track_connection(self: &Arc<Self>) -> ActiveGuard {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        self.track()
+    }
+
+    /// Count a task (e.g. a proxied WebSocket) as active
+    pub fn track(self: &Arc<Self>) -> ActiveGuard {
+        self.active.send_modify(|n| *n += 1);
+        ActiveGuard {
+            shutdown: Arc::clone(self),
+        }
+    }
+
+    /// Start shutting down
+    pub fn trigger(&self) {
+        self.signal.send_replace(true);
+    }
+
+    /// Wait until shutdown has started
+    pub async fn wait(&self) {
+        let mut rx = self.signal.subscribe();
+        // The sender lives as long as self, so this cannot fail
+        let _ = rx.wait_for(|started| *started).await;
+    }
+
+    /// Number of connections and WebSockets still running
+    pub fn active(&self) -> usize {
+        *self.active.borrow()
+    }
+
+    /// Total connections accepted since startup
+    pub fn accepted(&self) -> usize {
+        self.accepted.load(Ordering::Relaxed)
+    }
+
+    /// Wait for active connections to finish, up to a grace period.
+    /// Returns the number still running when the grace period ended.
+    pub async fn drain(&self, grace: Duration) -> usize {
+        let mut rx = self.active.subscribe();
+        let _ = tokio::time::timeout(grace, rx.wait_for(|n| *n == 0)).await;
+        self.active()
+    }
+}
+
+/// Wait for SIGINT (Ctrl-C) or SIGTERM (`docker stop`)
+pub async fn wait_for_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => "SIGINT",
+                _ = sigterm.recv() => "SIGTERM",
+            },
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+                "SIGINT"
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl-C"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drain_waits_for_guards() {
+        let shutdown = Shutdown::new();
+        let guard = shutdown.track_connection();
+        let _ws = shutdown.track();
+        assert_eq!(shutdown.active(), 2);
+        assert_eq!(shutdown.accepted(), 1);
+
+        drop(guard);
+        assert_eq!(shutdown.drain(Duration::from_millis(10)).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_drain_completes_when_idle() {
+        let shutdown = Shutdown::new();
+        let guard = shutdown.track_connection();
+
+        let waiter = Arc::clone(&shutdown);
+        let handle = tokio::spawn(async move { waiter.drain(Duration::from_secs(5)).await });
+        drop(guard);
+
+        assert_eq!(handle.await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,378 @@
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use http::{Method, Uri};
+use ring::{digest, hmac};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::rules::{rule_matches, RuleContext};
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+
+/// Sign requests to matching upstreams with AWS Signature Version 4
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SigV4Rule {
+    /// Target host, exact or `*.example.com`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Route alias name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    /// AWS region, e.g. `us-east-1`
+    pub region: String,
+    /// Service signing name, e.g. `execute-api` or `s3`
+    pub service: String,
+    /// Profile in `~/.aws/credentials` (defaults to $AWS_PROFILE or `default`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+}
+
+impl SigV4Rule {
+    pub fn matches(&self, ctx: &RuleContext) -> bool {
+        rule_matches(self.host.as_deref(), self.route.as_deref(), ctx)
+    }
+
+    /// Sign a request whose headers (including Host) are final
+    pub fn sign(&self, method: &Method, uri: &Uri, headers: &mut HeaderMap, body: &[u8]) -> Result<(), String> {
+        let credentials = AwsCredentials::load(self.profile.as_deref())?;
+        sign(method, uri, headers, body, &credentials, &self.region, &self.service, SystemTime::now())
+    }
+}
+
+/// AWS access key, from the environment or the shared credentials file
+#[derive(Clone, PartialEq)]
+pub struct AwsCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+impl std::fmt::Debug for AwsCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AwsCredentials")
+            .field("access_key_id", &self.access_key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AwsCredentials {
+    /// Look up credentials the way the AWS CLI does: `AWS_ACCESS_KEY_ID` /
+    /// `AWS_SECRET_ACCESS_KEY` first, then the profile in the credentials file.
+    /// Read on every request so rotated credentials are picked up.
+    pub fn load(profile: Option<&str>) -> Result<Self, String> {
+        let env = |name| std::env::var(name).ok().filter(|v: &String| !v.is_empty());
+
+        if profile.is_none() {
+            if let (Some(access_key_id), Some(secret_access_key)) =
+                (env("AWS_ACCESS_KEY_ID"), env("AWS_SECRET_ACCESS_KEY"))
+            {
+                return Ok(Self {
+                    access_key_id,
+                    secret_access_key,
+                    session_token: env("AWS_SESSION_TOKEN"),
+                });
+            }
+        }
+
+        let profile = profile
+            .map(str::to_string)
+            .or_else(|| env("AWS_PROFILE"))
+            .unwrap_or_else(|| "default".to_string());
+        let path = env("AWS_SHARED_CREDENTIALS_FILE")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".aws").join("credentials")))
+            .ok_or("No AWS credentials: set AWS_ACCESS_KEY_ID or create ~/.aws/credentials")?;
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| format!("No AWS credentials: failed to read {}: {}", path.display(), e))?;
+
+        Self::parse_profile(&text, &profile)
+            .ok_or_else(|| format!("No AWS credentials for profile '{}' in {}", profile, path.display()))
+    }
+
+    /// Find a profile in the INI-style credentials file
+    fn parse_profile(text: &str, profile: &str) -> Option<Self> {
+        let mut in_profile = false;
+        let mut access_key_id = None;
+        let mut secret_access_key = None;
+        let mut session_token = None;
+
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                in_profile = section.trim() == profile;
+                continue;
+            }
+            if !in_profile {
+                continue;
+            }
+            if let Some((key, value)) = line.split_once('=') {
+                let value = Some(value.trim().to_string());
+                match key.trim() {
+                    "aws_access_key_id" => access_key_id = value,
+                    "aws_secret_access_key" => secret_access_key = value,
+                    "aws_session_token" => session_token = value,
+                    _ => {}
+                }
+            }
+        }
+
+        Some(Self {
+            access_key_id: access_key_id?,
+            secret_access_key: secret_access_key?,
+            session_token,
+        })
+    }
+}
+
+/// Add `X-Amz-Date`, the session token and `Authorization` to a request
+#[allow(clippy::too_many_arguments)]
+pub fn sign(
+    method: &Method,
+    uri: &Uri,
+    headers: &mut HeaderMap,
+    body: &[u8],
+    credentials: &AwsCredentials,
+    region: &str,
+    service: &str,
+    time: SystemTime,
+) -> Result<(), String> {
+    let (date, timestamp) = amz_date(time);
+    let payload_hash = hex(digest::digest(&digest::SHA256, body).as_ref());
+
+    let mut set = |name: &'static str, value: &str| -> Result<(), String> {
+        let mut value = HeaderValue::from_str(value).map_err(|_| format!("Invalid {} header value", name))?;
+        // Keep the session token out of logs and recordings
+        value.set_sensitive(name == "x-amz-security-token");
+        headers.insert(HeaderName::from_static(name), value);
+        Ok(())
+    };
+    set("x-amz-date", &timestamp)?;
+    if let Some(token) = &credentials.session_token {
+        set("x-amz-security-token", token)?;
+    }
+    // S3 requires the payload hash as a header; other services don't sign it
+    if service == "s3" {
+        set("x-amz-content-sha256", &payload_hash)?;
+    }
+    headers.remove(header::AUTHORIZATION);
+
+    // Sign Host, Content-Type and all X-Amz-* headers: others may be changed in transit
+    let mut signed: Vec<(String, String)> = Vec::new();
+    for name in headers.keys() {
+        let name = name.as_str();
+        if name == "host" || name == "content-type" || name.starts_with("x-amz-") {
+            let values: Vec<String> = headers
+                .get_all(name)
+                .iter()
+                .map(|v| collapse_whitespace(&String::from_utf8_lossy(v.as_bytes())))
+                .collect();
+            signed.push((name.to_string(), values.join(",")));
+        }
+    }
+    signed.sort();
+
+    let canonical_headers: String = signed.iter().map(|(name, value)| format!("{}:{}\n", name, value)).collect();
+    let signed_headers = signed.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(";");
+
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method.as_str(),
+        canonical_uri(uri.path(), service),
+        canonical_query(uri.query().unwrap_or("")),
+        canonical_headers,
+        signed_headers,
+        payload_hash
+    );
+
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "{}\n{}\n{}\n{}",
+        ALGORITHM,
+        timestamp,
+        scope,
+        hex(digest::digest(&digest::SHA256, canonical_request.as_bytes()).as_ref())
+    );
+
+    let key = [date.as_str(), region, service, "aws4_request"]
+        .iter()
+        .fold(format!("AWS4{}", credentials.secret_access_key).into_bytes(), |key, part| {
+            hmac_sha256(&key, part.as_bytes())
+        });
+    let signature = hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+
+    let authorization = format!(
+        "{} Credential={}/{}, SignedHeaders={}, Signature={}",
+        ALGORITHM, credentials.access_key_id, scope, signed_headers, signature
+    );
+    let mut value = HeaderValue::from_str(&authorization).map_err(|_| "Invalid AWS access key id".to_string())?;
+    value.set_sensitive(true);
+    headers.insert(header::AUTHORIZATION, value);
+    Ok(())
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data).as_ref().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn collapse_whitespace(value: &str) -> String {
+    value.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Percent-encode everything except unreserved characters (and `/` if asked)
+fn uri_encode(s: &str, keep_slash: bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => out.push(b as char),
+            b'/' if keep_slash => out.push('/'),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// S3 signs the path as sent; every other service encodes it once more
+fn canonical_uri(path: &str, service: &str) -> String {
+    if path.is_empty() {
+        return "/".to_string();
+    }
+    let encoded = uri_encode(&percent_decode(path), true);
+    if service == "s3" {
+        encoded
+    } else {
+        uri_encode(&encoded, true)
+    }
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let Some(byte) = s.get(i + 1..i + 3).and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                out.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Query parameters encoded and sorted by name, then value
+fn canonical_query(query: &str) -> String {
+    let mut pairs: Vec<(String, String)> = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (uri_encode(&percent_decode(key), false), uri_encode(&percent_decode(value), false))
+        })
+        .collect();
+    pairs.sort();
+    pairs
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// `(YYYYMMDD, YYYYMMDD'T'HHMMSS'Z')` in UTC
+fn amz_date(time: SystemTime) -> (String, String) {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (year, month, day) = civil_date((secs / 86400) as i64);
+    let rem = secs % 86400;
+
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let timestamp = format!("{}T{:02}{:02}{:02}Z", date, rem / 3600, rem % 3600 / 60, rem % 60);
+    (date, timestamp)
+}
+
+/// Year, month and day from days since 1970-01-01 (Howard Hinnant's algorithm)
+pub fn civil_date(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // Vectors from the AWS SigV4 test suite (aws-sig-v4-test-suite)
+    fn sign_vector(method: Method, uri: &str) -> String {
+        let credentials = AwsCredentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+        };
+        // 2015-08-30T12:36:00Z
+        let time = UNIX_EPOCH + Duration::from_secs(1_440_938_160);
+        let uri: Uri = uri.parse().unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static("example.amazonaws.com"));
+        sign(&method, &uri, &mut headers, b"", &credentials, "us-east-1", "service", time).unwrap();
+
+        assert_eq!(headers["x-amz-date"], "20150830T123600Z");
+        headers[header::AUTHORIZATION].to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_get_vanilla() {
+        assert_eq!(
+            sign_vector(Method::GET, "/"),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+
+    #[test]
+    fn test_get_vanilla_query_order() {
+        assert_eq!(
+            sign_vector(Method::GET, "/?Param2=value2&Param1=value1"),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=b97d918cfa904a5beff61c982a1b6f458b799221646efd99d3219ec94cdf2500"
+        );
+    }
+
+    #[test]
+    fn test_post_vanilla() {
+        assert_eq!(
+            sign_vector(Method::POST, "/"),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=5da7c1a2acd57cee7505fc6676e4e544621c30862966e37dddb68e92efbe5d6b"
+        );
+    }
+
+    #[test]
+    fn test_credentials_file() {
+        let text = "[default]\naws_access_key_id = AKIDDEFAULT\naws_secret_access_key = secret1\n\n\
+                    [dev]\naws_access_key_id=AKIDDEV\naws_secret_access_key=secret2\naws_session_token=token\n";
+        let dev = AwsCredentials::parse_profile(text, "dev").unwrap();
+        assert_eq!(dev.access_key_id, "AKIDDEV");
+        assert_eq!(dev.session_token.as_deref(), Some("token"));
+        assert!(AwsCredentials::parse_profile(text, "prod").is_none());
+        assert!(!format!("{:?}", dev).contains("secret2"));
+    }
+}
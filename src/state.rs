@@ -0,0 +1,88 @@
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+use crate::admin::Admin;
+use crate::config::Config;
+use crate::cookies::CookieJar;
+use crate::har::Recorder;
+use crate::oauth::TokenCache;
+use crate::replay::Cassette;
+use crate::upstream::Upstream;
+
+/// The configuration and upstream clients in effect for a request
+pub struct Snapshot {
+    pub config: Arc<Config>,
+    pub upstream: Arc<Upstream>,
+}
+
+/// Swappable handle to the current snapshot, shared with every connection
+///
+/// Each request takes the snapshot current when it arrives, so a reload
+/// applies to new requests without disturbing those already in flight.
+/// The cookie jar, OAuth2 tokens, the traffic recorder and admin API changes
+/// outlive reloads, as does the replay cassette unless its options change.
+pub struct State {
+    current: watch::Sender<Arc<Snapshot>>,
+    cookies: Arc<CookieJar>,
+    tokens: Arc<TokenCache>,
+    cassette: Mutex<Option<Arc<Cassette>>>,
+    recorder: Option<Arc<Recorder>>,
+    admin: Arc<Admin>,
+}
+
+impl State {
+    pub fn new(
+        config: Arc<Config>,
+        upstream: Arc<Upstream>,
+        tokens: Arc<TokenCache>,
+        cassette: Option<Arc<Cassette>>,
+        recorder: Option<Recorder>,
+    ) -> Arc<Self> {
+        let admin = Arc::new(Admin::new(config.fault_seed));
+        let snapshot = Arc::new(Snapshot { config, upstream });
+        Arc::new(Self {
+            current: watch::channel(snapshot).0,
+            cookies: Arc::default(),
+            tokens,
+            cassette: Mutex::new(cassette),
+            recorder: recorder.map(Arc::new),
+            admin,
+        })
+    }
+
+    /// Get the snapshot currently in effect
+    pub fn current(&self) -> Arc<Snapshot> {
+        Arc::clone(&self.current.borrow())
+    }
+
+    /// Get the cookie jar shared by all requests
+    pub fn cookies(&self) -> &Arc<CookieJar> {
+        &self.cookies
+    }
+
+    /// Get the OAuth2 tokens, which new upstreams share on reload
+    pub fn tokens(&self) -> &Arc<TokenCache> {
+        &self.tokens
+    }
+
+    /// Get the replay cassette in use, if replaying
+    pub fn cassette(&self) -> Option<Arc<Cassette>> {
+        self.cassette.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Get the HAR recorder, if recording is on
+    pub fn recorder(&self) -> Option<&Arc<Recorder>> {
+        self.recorder.as_ref()
+    }
+
+    /// Get the state changed through the admin API
+    pub fn admin(&self) -> &Arc<Admin> {
+        &self.admin
+    }
+
+    /// Replace the snapshot used by subsequent requests, and the cassette it replays
+    pub fn replace(&self, config: Arc<Config>, upstream: Arc<Upstream>, cassette: Option<Arc<Cassette>>) {
+        *self.cassette.lock().unwrap_or_else(|e| e.into_inner()) = cassette;
+        self.current.send_replace(Arc::new(Snapshot { config, upstream }));
+    }
+}
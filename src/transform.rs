@@ -0,0 +1,469 @@
+use bytes::{Bytes, BytesMut};
+use futures_util::{future, stream};
+use http::{header, HeaderMap, HeaderValue};
+use http_body_util::{combinators::BoxBody, BodyExt, BodyStream, StreamBody};
+use hyper::body::Frame;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use serde_json_path::JsonPath;
+use std::collections::BTreeMap;
+use tracing::warn;
+
+use crate::rules::{rule_matches, RuleContext, Template};
+
+/// Bodies larger than this are passed through untouched
+pub const MAX_BODY_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Body transform rule: regex replacements on the text, form to JSON
+/// conversion, then JSON deletes, sets and a merge patch. Strings in `set` and
+/// `merge_patch` may use template placeholders like `{env:NAME}`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BodyRule {
+    /// Target host, exact or `*.example.com`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Route alias name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    /// Regex substitutions, applied in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub replace: Vec<Replace>,
+    /// Convert a form-urlencoded body to a JSON object (repeated fields become arrays)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub form_to_json: bool,
+    /// JSON Pointers (`/a/0`) or JSONPaths (`$.items[*].id`) to remove
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub delete: Vec<String>,
+    /// JSON Pointer or JSONPath -> value to set; pointers create missing members
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub set: BTreeMap<String, Value>,
+    /// JSON merge patch (RFC 7396), as a table or a JSON string (for `null`)
+    #[serde(
+        default,
+        deserialize_with = "merge_patch",
+        serialize_with = "json_string",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub merge_patch: Option<Value>,
+}
+
+/// A regex substitution; `with` may refer to groups as `$1` or `${name}`
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Replace {
+    pub pattern: Pattern,
+    #[serde(default)]
+    pub with: String,
+}
+
+/// A compiled regex, compared and serialized as its source
+#[derive(Debug, Clone)]
+pub struct Pattern(pub Regex);
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Regex::new(&pattern)
+            .map(Pattern)
+            .map_err(|e| serde::de::Error::custom(format!("invalid pattern: {}", e)))
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+fn merge_patch<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Value>, D::Error> {
+    match Value::deserialize(deserializer)? {
+        Value::String(json) => serde_json::from_str(&json)
+            .map(Some)
+            .map_err(|e| serde::de::Error::custom(format!("invalid merge_patch JSON: {}", e))),
+        value => Ok(Some(value)),
+    }
+}
+
+/// TOML has no `null`, so merge patches are written back as JSON
+fn json_string<S: Serializer>(value: &Option<Value>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serializer.serialize_str(&value.to_string()),
+        None => serializer.serialize_none(),
+    }
+}
+
+impl BodyRule {
+    pub fn matches(&self, ctx: &RuleContext) -> bool {
+        rule_matches(self.host.as_deref(), self.route.as_deref(), ctx)
+    }
+
+    /// Check the JSON paths once the config is loaded
+    pub fn validate(&self) -> Result<(), String> {
+        for path in self.delete.iter().chain(self.set.keys()) {
+            if path.starts_with('$') {
+                JsonPath::parse(path).map_err(|e| format!("invalid JSONPath {}: {}", path, e))?;
+            } else if !path.is_empty() && !path.starts_with('/') {
+                return Err(format!("{} is neither a JSON Pointer (/a/b) nor a JSONPath ($.a.b)", path));
+            }
+        }
+        Ok(())
+    }
+
+    fn edits_json(&self) -> bool {
+        !self.delete.is_empty() || !self.set.is_empty() || self.merge_patch.is_some()
+    }
+
+    /// Apply this rule to a body, converting forms to JSON if asked; `None`
+    /// if the body is not UTF-8
+    fn apply(&self, body: &[u8], kind: &mut BodyKind, ctx: &RuleContext, source: &HeaderMap) -> Option<Vec<u8>> {
+        let mut body = body.to_vec();
+
+        if !self.replace.is_empty() {
+            let mut text = String::from_utf8(body).ok()?;
+            for replace in &self.replace {
+                text = replace.pattern.0.replace_all(&text, replace.with.as_str()).into_owned();
+            }
+            body = text.into_bytes();
+        }
+
+        if self.form_to_json && *kind == BodyKind::Form {
+            let mut json = serde_json::Map::new();
+            for (key, value) in url::form_urlencoded::parse(&body) {
+                let value = Value::String(value.into_owned());
+                match json.get_mut(key.as_ref()) {
+                    Some(Value::Array(values)) => values.push(value),
+                    Some(first) => *first = Value::Array(vec![first.take(), value]),
+                    None => {
+                        json.insert(key.into_owned(), value);
+                    }
+                }
+            }
+            body = serde_json::to_vec(&json).ok()?;
+            *kind = BodyKind::Json;
+        }
+
+        if self.edits_json() && *kind == BodyKind::Json {
+            let mut json: Value = match serde_json::from_slice(&body) {
+                Ok(json) => json,
+                Err(e) => {
+                    warn!("Skipping JSON body rule: invalid JSON: {}", e);
+                    return Some(body);
+                }
+            };
+            for path in &self.delete {
+                for pointer in pointers(&json, path).iter().rev() {
+                    delete_pointer(&mut json, pointer);
+                }
+            }
+            for (path, value) in &self.set {
+                for pointer in pointers(&json, path) {
+                    if !set_pointer(&mut json, &pointer, render(value, ctx, source)) {
+                        warn!("Skipping JSON body rule: cannot set {}", path);
+                    }
+                }
+            }
+            if let Some(patch) = &self.merge_patch {
+                merge(&mut json, &render(patch, ctx, source));
+            }
+            body = serde_json::to_vec(&json).ok()?;
+        }
+
+        Some(body)
+    }
+}
+
+/// What a body can be transformed as
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BodyKind {
+    Text,
+    Form,
+    Json,
+}
+
+impl BodyKind {
+    /// The kind of a body by its headers; binary, compressed, streaming and
+    /// oversized bodies can't be transformed
+    pub fn of(headers: &HeaderMap) -> Option<Self> {
+        let encoding = headers.get(header::CONTENT_ENCODING).and_then(|v| v.to_str().ok());
+        if encoding.is_some_and(|e| !e.trim().eq_ignore_ascii_case("identity")) {
+            return None;
+        }
+        let length = headers.get(header::CONTENT_LENGTH).and_then(|v| v.to_str().ok()?.parse::<u64>().ok());
+        if length.is_some_and(|length| length > MAX_BODY_SIZE) {
+            return None;
+        }
+
+        let content_type = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+        let mime = content_type.split(';').next()?.trim().to_ascii_lowercase();
+        let (kind, subtype) = mime.split_once('/')?;
+        match (kind, subtype) {
+            ("text", "event-stream") => None,
+            (_, "x-ndjson" | "jsonl") => None,
+            (_, "json") => Some(BodyKind::Json),
+            (_, subtype) if subtype.ends_with("+json") => Some(BodyKind::Json),
+            ("text", _) => Some(BodyKind::Text),
+            (_, "x-www-form-urlencoded") => Some(BodyKind::Form),
+            (_, "xml" | "javascript" | "graphql") => Some(BodyKind::Text),
+            (_, subtype) if subtype.ends_with("+xml") => Some(BodyKind::Text),
+            _ => None,
+        }
+    }
+}
+
+/// Apply every matching rule to a body, in order, updating `Content-Type`
+/// and `Content-Length`; `None` if the body can't be transformed or nothing
+/// changed. `source` holds the headers `{header:NAME}` placeholders read from.
+pub fn apply_body_rules(
+    rules: &[BodyRule],
+    ctx: &RuleContext,
+    headers: &mut HeaderMap,
+    source: &HeaderMap,
+    body: &Bytes,
+) -> Option<Bytes> {
+    let original = BodyKind::of(headers)?;
+    let mut kind = original;
+    let mut current = body.to_vec();
+    for rule in rules.iter().filter(|rule| rule.matches(ctx)) {
+        match rule.apply(&current, &mut kind, ctx, source) {
+            Some(next) => current = next,
+            None => warn!("Skipping body rule: body is not UTF-8"),
+        }
+    }
+    if current == body.as_ref() {
+        return None;
+    }
+
+    if kind != original {
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    }
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(current.len()));
+    Some(Bytes::from(current))
+}
+
+/// A body read up to `MAX_BODY_SIZE`
+pub enum Buffered {
+    /// The whole body
+    Whole(Bytes),
+    /// A body over the limit: the bytes already read, then the rest unread
+    Oversized(BoxBody<Bytes, hyper::Error>),
+}
+
+/// Read a body, stopping once it's larger than `MAX_BODY_SIZE` so bodies
+/// without a `Content-Length` can't be buffered without bound
+pub async fn read_body(mut body: BoxBody<Bytes, hyper::Error>) -> Result<Buffered, hyper::Error> {
+    let mut read = BytesMut::new();
+    while let Some(frame) = body.frame().await {
+        let Ok(data) = frame?.into_data() else {
+            continue;
+        };
+        read.extend_from_slice(&data);
+        if read.len() as u64 > MAX_BODY_SIZE {
+            let head = stream::once(future::ready(Ok(Frame::data(read.freeze()))));
+            return Ok(Buffered::Oversized(StreamBody::new(futures_util::StreamExt::chain(head, BodyStream::new(body))).boxed()));
+        }
+    }
+    Ok(Buffered::Whole(read.freeze()))
+}
+
+/// Render template placeholders in the strings of a value; strings that
+/// aren't valid templates (like `{ user { id } }`) are kept as they are
+fn render(value: &Value, ctx: &RuleContext, source: &HeaderMap) -> Value {
+    match value {
+        Value::String(s) if s.contains('{') => match Template::parse(s) {
+            Ok(template) => Value::String(template.render(ctx, source)),
+            Err(_) => value.clone(),
+        },
+        Value::Array(items) => Value::Array(items.iter().map(|v| render(v, ctx, source)).collect()),
+        Value::Object(map) => Value::Object(map.iter().map(|(k, v)| (k.clone(), render(v, ctx, source))).collect()),
+        _ => value.clone(),
+    }
+}
+
+/// JSON Pointers for a path: the pointer itself, or every JSONPath match
+fn pointers(json: &Value, path: &str) -> Vec<String> {
+    if !path.starts_with('$') {
+        return vec![path.to_string()];
+    }
+    match JsonPath::parse(path) {
+        Ok(path) => path
+            .query_located(json)
+            .locations()
+            .map(|location| location.to_json_pointer())
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn pointer_tokens(pointer: &str) -> Vec<String> {
+    pointer.split('/').skip(1).map(|t| t.replace("~1", "/").replace("~0", "~")).collect()
+}
+
+/// Remove the value at a JSON Pointer, if it exists
+fn delete_pointer(json: &mut Value, pointer: &str) {
+    let mut tokens = pointer_tokens(pointer);
+    let Some(last) = tokens.pop() else {
+        return;
+    };
+    let parent = tokens.iter().try_fold(json, |value, token| match value {
+        Value::Object(map) => map.get_mut(token),
+        Value::Array(items) => token.parse::<usize>().ok().and_then(|i| items.get_mut(i)),
+        _ => None,
+    });
+    match parent {
+        Some(Value::Object(map)) => {
+            map.remove(&last);
+        }
+        Some(Value::Array(items)) => {
+            if let Some(i) = last.parse::<usize>().ok().filter(|&i| i < items.len()) {
+                items.remove(i);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Set the value at a JSON Pointer, creating missing object members; `-`
+/// appends to an array. Returns false if the path runs into a scalar.
+fn set_pointer(json: &mut Value, pointer: &str, new: Value) -> bool {
+    let mut value = json;
+    for token in pointer_tokens(pointer) {
+        if value.is_null() {
+            *value = Value::Object(Default::default());
+        }
+        value = match value {
+            Value::Object(map) => map.entry(token).or_insert(Value::Null),
+            Value::Array(items) => match token.parse::<usize>() {
+                Ok(i) if i < items.len() => &mut items[i],
+                _ if token == "-" || token.parse() == Ok(items.len()) => {
+                    items.push(Value::Null);
+                    items.last_mut().expect("just pushed")
+                }
+                _ => return false,
+            },
+            _ => return false,
+        };
+    }
+    *value = new;
+    true
+}
+
+/// Apply a JSON merge patch (RFC 7396)
+fn merge(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+    let Value::Object(map) = target else {
+        return;
+    };
+    for (key, value) in patch {
+        if value.is_null() {
+            map.remove(key);
+        } else {
+            merge(map.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn transform(rule: &str, body: &str, content_type: &str) -> (String, HeaderMap) {
+        let rule: BodyRule = toml::from_str(rule).unwrap();
+        rule.validate().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+        let out = apply_body_rules(&[rule], &RuleContext::default(), &mut headers, &HeaderMap::new(), &Bytes::from(body.to_string()));
+        let out = out.map(|b| b.to_vec()).unwrap_or_else(|| body.as_bytes().to_vec());
+        (String::from_utf8(out).unwrap(), headers)
+    }
+
+    #[test]
+    fn test_replace() {
+        let rule = r#"replace = [{ pattern = 'https://api\.internal(:\d+)?', with = "https://api.example.com" }]"#;
+        assert_eq!(
+            transform(rule, "<a href='https://api.internal:8080/x'>", "text/html").0,
+            "<a href='https://api.example.com/x'>"
+        );
+    }
+
+    #[test]
+    fn test_json_edits() {
+        let rule = r#"
+delete = ["/debug", "$.items[*].secret"]
+set = { "/features/new_ui" = true, "$.items[*].price" = 0 }
+merge_patch = '{"meta": {"trace": null, "version": 2}}'
+"#;
+        let body = json!({
+            "debug": {"sql": "select"},
+            "items": [{"id": 1, "secret": "a", "price": 5}, {"id": 2, "secret": "b", "price": 7}],
+            "meta": {"trace": "abc", "version": 1},
+        });
+        let out: Value = serde_json::from_str(&transform(rule, &body.to_string(), "application/json").0).unwrap();
+        assert_eq!(
+            out,
+            json!({
+                "features": {"new_ui": true},
+                "items": [{"id": 1, "price": 0}, {"id": 2, "price": 0}],
+                "meta": {"version": 2},
+            })
+        );
+    }
+
+    #[test]
+    fn test_form_to_json() {
+        std::env::set_var("HOLY_CORS_TEST_CLIENT", "web");
+        let rule = r#"
+form_to_json = true
+set = { "/client" = "{env:HOLY_CORS_TEST_CLIENT}", "/query" = "{ user { id } }" }
+"#;
+        let (body, headers) = transform(rule, "name=Ada+L&tag=a&tag=b", "application/x-www-form-urlencoded");
+        assert_eq!(
+            serde_json::from_str::<Value>(&body).unwrap(),
+            json!({"name": "Ada L", "tag": ["a", "b"], "client": "web", "query": "{ user { id } }"})
+        );
+        assert_eq!(headers[header::CONTENT_TYPE], "application/json");
+        assert_eq!(headers[header::CONTENT_LENGTH], body.len().to_string().as_str());
+    }
+
+    #[test]
+    fn test_body_kind() {
+        let kind = |content_type: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+            BodyKind::of(&headers)
+        };
+        assert_eq!(kind("application/problem+json; charset=utf-8"), Some(BodyKind::Json));
+        assert_eq!(kind("text/html"), Some(BodyKind::Text));
+        assert_eq!(kind("text/event-stream"), None);
+        assert_eq!(kind("image/png"), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_body() {
+        let body = |chunks: usize| {
+            let frames = (0..chunks).map(|_| Ok::<_, hyper::Error>(Frame::data(Bytes::from(vec![b'x'; 1024 * 1024]))));
+            StreamBody::new(stream::iter(frames)).boxed()
+        };
+        assert!(matches!(read_body(body(2)).await.unwrap(), Buffered::Whole(bytes) if bytes.len() == 2 * 1024 * 1024));
+
+        // Bodies over the limit come back whole, without a Content-Length to go by
+        let Buffered::Oversized(rest) = read_body(body(12)).await.unwrap() else {
+            panic!("expected an oversized body");
+        };
+        assert_eq!(rest.collect().await.unwrap().to_bytes().len(), 12 * 1024 * 1024);
+    }
+}
@@ -0,0 +1,102 @@
+use bytes::Bytes;
+use http::{Request, Response};
+use http_body_util::Full;
+use hyper::body::Incoming;
+use std::path::PathBuf;
+
+/// Prefix marking a Unix domain socket target, e.g. `unix:/var/run/app.sock:/path`
+pub const UNIX_PREFIX: &str = "unix:";
+
+/// An HTTP server listening on a Unix domain socket
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnixTarget {
+    /// Path to the socket file
+    pub socket: PathBuf,
+    /// Request path (and query) sent to the server
+    pub path: String,
+}
+
+impl UnixTarget {
+    /// Parse `unix:SOCKET[:PATH]`, defaulting the request path to `/`
+    pub fn parse(target: &str) -> Option<Self> {
+        let rest = target.strip_prefix(UNIX_PREFIX)?;
+        let (socket, path) = match rest.split_once(':') {
+            Some((socket, path)) => (socket, path),
+            None => match rest.split_once('?') {
+                // No request path, only a query string
+                Some((socket, _)) => (socket, &rest[socket.len()..]),
+                None => (rest, ""),
+            },
+        };
+
+        if socket.is_empty() {
+            return None;
+        }
+
+        let path = if path.starts_with('/') {
+            path.to_string()
+        } else {
+            format!("/{}", path)
+        };
+
+        Some(Self {
+            socket: PathBuf::from(socket),
+            path,
+        })
+    }
+}
+
+/// Send a request over a Unix domain socket using HTTP/1.1
+#[cfg(unix)]
+pub async fn send_request(
+    socket: &std::path::Path,
+    req: Request<Full<Bytes>>,
+) -> Result<Response<Incoming>, Box<dyn std::error::Error + Send + Sync>> {
+    use hyper_util::rt::TokioIo;
+    use tokio::net::UnixStream;
+    use tracing::debug;
+
+    let stream = UnixStream::connect(socket)
+        .await
+        .map_err(|e| format!("{}: {}", socket.display(), e))?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+
+    tokio::spawn(async move {
+        if let Err(e) = conn.await {
+            debug!("Unix socket connection error: {}", e);
+        }
+    });
+
+    Ok(sender.send_request(req).await?)
+}
+
+/// Unix domain sockets are not available on this platform
+#[cfg(not(unix))]
+pub async fn send_request(
+    _socket: &std::path::Path,
+    _req: Request<Full<Bytes>>,
+) -> Result<Response<Incoming>, Box<dyn std::error::Error + Send + Sync>> {
+    Err("Unix socket targets are not supported on this platform".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_unix_target() {
+        let target = UnixTarget::parse("unix:/var/run/docker.sock:/v1.43/containers/json?all=1").unwrap();
+        assert_eq!(target.socket, PathBuf::from("/var/run/docker.sock"));
+        assert_eq!(target.path, "/v1.43/containers/json?all=1");
+
+        let target = UnixTarget::parse("unix:/tmp/app.sock").unwrap();
+        assert_eq!(target.socket, PathBuf::from("/tmp/app.sock"));
+        assert_eq!(target.path, "/");
+
+        let target = UnixTarget::parse("unix:/tmp/app.sock?debug=1").unwrap();
+        assert_eq!(target.path, "/?debug=1");
+
+        assert!(UnixTarget::parse("unix::/path").is_none());
+        assert!(UnixTarget::parse("https://example.com").is_none());
+    }
+}
@@ -0,0 +1,270 @@
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use crate::config::{ClientIdentity, Config};
+use crate::oauth::TokenCache;
+use crate::openapi::Contracts;
+use crate::replay::Cassette;
+use crate::resolve::{Resolver, ResolvingConnector};
+
+/// HTTP client used to reach upstream targets
+pub type HttpClient = Client<HttpsConnector<ResolvingConnector>, Full<Bytes>>;
+
+/// Upstream HTTP clients, one per distinct TLS setup
+pub struct Upstream {
+    default: HttpClient,
+    default_tls: Arc<ClientConfig>,
+    hosts: HashMap<String, (HttpClient, Arc<ClientConfig>)>,
+    resolver: Resolver,
+    tokens: Arc<TokenCache>,
+    cassette: Option<Arc<Cassette>>,
+    contracts: Contracts,
+}
+
+impl Upstream {
+    /// Build the upstream clients from the TLS and DNS options in the config,
+    /// sharing the OAuth2 tokens and replay cassette kept across reloads
+    pub fn from_config(
+        config: &Config,
+        tokens: Arc<TokenCache>,
+        cassette: Option<Arc<Cassette>>,
+    ) -> Result<Self, String> {
+        let roots = Arc::new(root_store(config)?);
+        let identities = config.client_identities()?;
+
+        let overrides = config.resolve_overrides()?;
+        for o in &overrides {
+            let port = o.port.map_or("*".to_string(), |p| p.to_string());
+            let target = o.addr_port.map_or(o.addr.to_string(), |p| format!("{}:{}", o.addr, p));
+            info!("Resolving {}:{} -> {}", o.host, port, target);
+        }
+        let resolver = Resolver::new(overrides);
+
+        let default_tls = Arc::new(tls_config(&roots, false, None)?);
+        let default = build_client(&default_tls, &resolver);
+
+        // Hosts with insecure mode or a client certificate get their own client
+        let mut special: HashSet<String> = identities.keys().cloned().collect();
+        special.extend(config.insecure_hosts.iter().map(|h| h.to_ascii_lowercase()));
+
+        let mut hosts = HashMap::new();
+        for host in special {
+            let insecure = config.is_insecure_host(&host);
+            if insecure {
+                warn!("TLS certificate verification is DISABLED for {}", host);
+            }
+            let identity = identities.get(&host);
+            if identity.is_some() {
+                info!("Using client certificate for {}", host);
+            }
+
+            let tls = Arc::new(tls_config(&roots, insecure, identity)?);
+            hosts.insert(host, (build_client(&tls, &resolver), tls));
+        }
+
+        let contracts = Contracts::load(&config.openapi)?;
+        for rule in &config.openapi {
+            info!("Using OpenAPI document {}", rule.spec.display());
+        }
+
+        Ok(Self {
+            default,
+            default_tls,
+            hosts,
+            resolver,
+            tokens,
+            cassette,
+            contracts,
+        })
+    }
+
+    /// Get the DNS overrides shared by all upstream connections
+    pub fn resolver(&self) -> &Resolver {
+        &self.resolver
+    }
+
+    /// Get the OAuth2 access tokens fetched for upstreams
+    pub fn tokens(&self) -> &TokenCache {
+        &self.tokens
+    }
+
+    /// Get the recorded responses to replay, if any
+    pub fn cassette(&self) -> Option<&Cassette> {
+        self.cassette.as_deref()
+    }
+
+    /// Get the OpenAPI documents of upstreams
+    pub fn contracts(&self) -> &Contracts {
+        &self.contracts
+    }
+
+    /// Get the HTTP client to use for a target host
+    pub fn client_for(&self, host: &str) -> &HttpClient {
+        match self.hosts.get(&host.to_ascii_lowercase()) {
+            Some((client, _)) => client,
+            None => &self.default,
+        }
+    }
+
+    /// Get the TLS configuration to use for a target host
+    pub fn tls_config_for(&self, host: &str) -> Arc<ClientConfig> {
+        match self.hosts.get(&host.to_ascii_lowercase()) {
+            Some((_, tls)) => Arc::clone(tls),
+            None => Arc::clone(&self.default_tls),
+        }
+    }
+}
+
+/// Build the HTTPS client (HTTP/1.1 and HTTP/2) for a TLS configuration
+fn build_client(tls: &Arc<ClientConfig>, resolver: &Resolver) -> HttpClient {
+    let https = HttpsConnectorBuilder::new()
+        .with_tls_config(ClientConfig::clone(tls))
+        .https_or_http()
+        .enable_http1()
+        .enable_http2()
+        .wrap_connector(ResolvingConnector::new(resolver.clone()));
+
+    Client::builder(TokioExecutor::new())
+        .http2_only(false)
+        .build(https)
+}
+
+/// Load the trusted roots: system or bundled, plus any --ca-file
+fn root_store(config: &Config) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+
+    if config.bundled_roots {
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    } else {
+        let native = rustls_native_certs::load_native_certs();
+        for e in &native.errors {
+            warn!("Failed to load a system certificate: {}", e);
+        }
+        let (added, _) = roots.add_parsable_certificates(native.certs);
+        if added == 0 {
+            warn!("No system root certificates found, falling back to bundled roots");
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        }
+    }
+
+    if let Some(path) = &config.ca_file {
+        let certs = load_certs(path)?;
+        let (added, ignored) = roots.add_parsable_certificates(certs);
+        if added == 0 {
+            return Err(format!("No usable CA certificates in {}", path.display()));
+        }
+        info!("Loaded {} CA certificate(s) from {}", added, path.display());
+        if ignored > 0 {
+            warn!("Ignored {} invalid certificate(s) in {}", ignored, path.display());
+        }
+    }
+
+    Ok(roots)
+}
+
+/// Build a rustls client configuration
+fn tls_config(
+    roots: &Arc<RootCertStore>,
+    insecure: bool,
+    identity: Option<&ClientIdentity>,
+) -> Result<ClientConfig, String> {
+    let builder = ClientConfig::builder();
+    let builder = if insecure {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerification::new()))
+    } else {
+        builder.with_root_certificates(Arc::clone(roots))
+    };
+
+    match identity {
+        Some(identity) => {
+            let certs = load_certs(&identity.cert)?;
+            let key = PrivateKeyDer::from_pem_file(&identity.key).map_err(|e| {
+                format!("Failed to load private key {}: {}", identity.key.display(), e)
+            })?;
+            builder
+                .with_client_auth_cert(certs, key)
+                .map_err(|e| format!("Invalid client certificate {}: {}", identity.cert.display(), e))
+        }
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+/// Read all PEM certificates from a file
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Failed to read certificates from {}: {}", path.display(), e))?;
+
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+/// Certificate verifier that accepts any server certificate (--insecure-host)
+///
+/// Handshake signatures are still checked so the connection is well-formed,
+/// but the server's identity is not.
+#[derive(Debug)]
+struct NoVerification {
+    provider: Arc<CryptoProvider>,
+}
+
+impl NoVerification {
+    fn new() -> Self {
+        let provider = CryptoProvider::get_default()
+            .cloned()
+            .unwrap_or_else(|| Arc::new(rustls::crypto::ring::default_provider()));
+        Self { provider }
+    }
+}
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        warn!("Skipping TLS certificate verification for {}", server_name.to_str());
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
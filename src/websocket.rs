@@ -0,0 +1,268 @@
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use http::{header, HeaderMap, HeaderValue, Method, Request, Response, StatusCode};
+use http_body_util::{combinators::BoxBody, BodyExt, Empty};
+use hyper::body::Incoming;
+use hyper_util::rt::TokioIo;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Role};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{client_async_tls_with_config, Connector, WebSocketStream};
+use tracing::{debug, error, info};
+use url::Url;
+
+use crate::config::Config;
+use crate::cookies::{merge_cookie_header, CookieJar, SESSION_HEADER};
+use crate::cors::error_response;
+use crate::credentials::apply_credentials;
+use crate::har::{Recorder, WebSocketLog};
+use crate::oauth::bearer_header;
+use crate::replay::ReplayMode;
+use crate::rules::{apply_header_rules, RuleContext};
+use crate::shutdown::Shutdown;
+use crate::upstream::Upstream;
+
+/// Client handshake headers that tungstenite generates itself
+const HANDSHAKE_HEADERS: &[&str] = &[
+    "connection",
+    "upgrade",
+    "host",
+    "sec-websocket-key",
+    "sec-websocket-version",
+    "sec-websocket-extensions",
+    "sec-websocket-accept",
+];
+
+/// Check if this is a WebSocket upgrade request
+pub fn is_websocket_upgrade(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.eq_ignore_ascii_case("websocket"))
+        .unwrap_or(false)
+}
+
+/// Handle WebSocket upgrade and proxy
+///
+/// The upstream handshake is completed first so failures can still be
+/// reported to the browser as a normal HTTP error.
+pub async fn handle_websocket(
+    req: Request<Incoming>,
+    ctx: &RuleContext,
+    config: &Config,
+    upstream: &Upstream,
+    cookies: &CookieJar,
+    recorder: Option<Arc<Recorder>>,
+    shutdown: Arc<Shutdown>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    let target_url = ctx.target_url.as_str();
+    info!("WebSocket upgrade requested for {}", target_url);
+
+    let Some(key) = req.headers().get(header::SEC_WEBSOCKET_KEY).cloned() else {
+        return Ok(error_response(StatusCode::BAD_REQUEST, "Missing Sec-WebSocket-Key header")
+            .map(|b| b.map_err(|_| unreachable!()).boxed()));
+    };
+
+    // Recorded WebSocket sessions cannot be replayed
+    if upstream.cassette().is_some_and(|c| c.mode() == ReplayMode::Strict) {
+        return Ok(error_response(StatusCode::BAD_GATEWAY, "WebSocket connections cannot be replayed")
+            .map(|b| b.map_err(|_| unreachable!()).boxed()));
+    }
+
+    // Convert http:// to ws:// and https:// to wss://
+    let mut ws_url = target_url
+        .replacen("http://", "ws://", 1)
+        .replacen("https://", "wss://", 1);
+
+    // Recordings leave out credentials injected into the query
+    let recorded_url = ws_url.clone();
+
+    // Resolve injected credentials; query parameters go into the URL
+    let mut credentials = HeaderMap::new();
+    apply_credentials(&config.credentials, &config.secrets, ctx, &mut credentials, &mut ws_url);
+
+    let mut ws_request = match ws_url.as_str().into_client_request() {
+        Ok(ws_request) => ws_request,
+        Err(e) => {
+            return Ok(error_response(StatusCode::BAD_REQUEST, &format!("Invalid WebSocket URL: {}", e))
+                .map(|b| b.map_err(|_| unreachable!()).boxed()));
+        }
+    };
+
+    // Forward the browser's headers (subprotocols, cookies, ...)
+    for (name, value) in req.headers() {
+        if !HANDSHAKE_HEADERS.contains(&name.as_str()) && name != SESSION_HEADER {
+            ws_request.headers_mut().append(name, value.clone());
+        }
+    }
+    apply_header_rules(&config.request_headers, ws_request.headers_mut(), ctx, req.headers());
+    for (name, value) in &credentials {
+        ws_request.headers_mut().insert(name, value.clone());
+    }
+    if let (Some(key), Ok(url)) = (config.cookie_jar.jar_key(req.headers(), &ctx.origin), Url::parse(target_url)) {
+        if let Some(jar_cookies) = cookies.cookie_header(&key, &url) {
+            merge_cookie_header(ws_request.headers_mut(), jar_cookies);
+        }
+    }
+    if let Some(rule) = config.oauth2.iter().find(|rule| rule.matches(ctx)) {
+        match upstream.tokens().token(rule, &config.secrets, upstream).await {
+            Ok(token) => {
+                if let Some(value) = bearer_header(&token) {
+                    ws_request.headers_mut().insert(header::AUTHORIZATION, value);
+                }
+            }
+            Err(e) => {
+                error!("Failed to get OAuth2 token: {}", e);
+                return Ok(error_response(StatusCode::BAD_GATEWAY, &format!("Failed to get OAuth2 token: {}", e))
+                    .map(|b| b.map_err(|_| unreachable!()).boxed()));
+            }
+        }
+    }
+
+    let entry = recorder.map(|r| r.start(&Method::GET, &recorded_url, ws_request.headers(), b""));
+
+    // Connect to the target with the host's DNS override and TLS setup
+    let (host, port) = match Url::parse(target_url) {
+        Ok(url) => (
+            url.host_str().unwrap_or_default().to_string(),
+            url.port_or_known_default().unwrap_or(80),
+        ),
+        Err(_) => (String::new(), 80),
+    };
+    let stream = match upstream.resolver().connect(&host, port).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            error!("Failed to connect to WebSocket target: {}", e);
+            if let Some(entry) = entry {
+                entry.failed(&e.to_string());
+            }
+            return Ok(error_response(
+                StatusCode::BAD_GATEWAY,
+                &format!("Failed to connect to WebSocket: {}", e),
+            )
+            .map(|b| b.map_err(|_| unreachable!()).boxed()));
+        }
+    };
+    let connector = Connector::Rustls(upstream.tls_config_for(&host));
+    let (upstream_ws, upstream_response) =
+        match client_async_tls_with_config(ws_request, stream, None, Some(connector)).await {
+            Ok(result) => result,
+            Err(e) => {
+                error!("Failed to connect to WebSocket target: {}", e);
+                if let Some(entry) = entry {
+                    entry.failed(&e.to_string());
+                }
+                return Ok(error_response(
+                    StatusCode::BAD_GATEWAY,
+                    &format!("Failed to connect to WebSocket: {}", e),
+                )
+                .map(|b| b.map_err(|_| unreachable!()).boxed()));
+            }
+        };
+
+    // Accept the browser's upgrade, echoing the subprotocol the target chose
+    let mut response = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "Upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_ACCEPT, derive_accept_key(key.as_bytes()))
+        .body(Empty::new().map_err(|_| unreachable!()).boxed())
+        .unwrap();
+    if let Some(protocol) = upstream_response.headers().get(header::SEC_WEBSOCKET_PROTOCOL) {
+        response
+            .headers_mut()
+            .insert(header::SEC_WEBSOCKET_PROTOCOL, HeaderValue::clone(protocol));
+    }
+
+    let log = entry.map(|entry| entry.websocket(upstream_response.headers()));
+    let target_url = target_url.to_string();
+    let guard = shutdown.track();
+    tokio::spawn(async move {
+        let _guard = guard;
+        match hyper::upgrade::on(req).await {
+            Ok(upgraded) => {
+                let client_ws =
+                    WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Server, None).await;
+                pump(client_ws, upstream_ws, log, &shutdown).await;
+                debug!("WebSocket closed for {}", target_url);
+            }
+            Err(e) => error!("WebSocket upgrade failed: {}", e),
+        }
+    });
+
+    Ok(response)
+}
+
+/// Relay messages both ways until either side closes or the proxy shuts down;
+/// the recording is written when the log is dropped
+async fn pump<C, U>(
+    mut client: WebSocketStream<C>,
+    mut upstream: WebSocketStream<U>,
+    mut log: Option<WebSocketLog>,
+    shutdown: &Shutdown,
+) where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        tokio::select! {
+            msg = client.next() => match msg {
+                Some(Ok(msg)) => {
+                    if let Some(log) = &mut log {
+                        log.send(&msg);
+                    }
+                    if !relay(msg, &mut upstream).await {
+                        break;
+                    }
+                }
+                _ => {
+                    let _ = upstream.close(None).await;
+                    break;
+                }
+            },
+            msg = upstream.next() => match msg {
+                Some(Ok(msg)) => {
+                    if let Some(log) = &mut log {
+                        log.receive(&msg);
+                    }
+                    if !relay(msg, &mut client).await {
+                        break;
+                    }
+                }
+                _ => {
+                    let _ = client.close(None).await;
+                    break;
+                }
+            },
+            _ = shutdown.wait() => {
+                let frame = CloseFrame {
+                    code: CloseCode::Away,
+                    reason: "Proxy shutting down".into(),
+                };
+                let _ = client.close(Some(frame.clone())).await;
+                let _ = upstream.close(Some(frame)).await;
+                break;
+            }
+        }
+    }
+}
+
+/// Forward one message, returning false once the connection should end
+async fn relay<S>(msg: Message, to: &mut WebSocketStream<S>) -> bool
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match msg {
+        // Each side answers its own pings
+        Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => true,
+        Message::Close(frame) => {
+            let _ = to.close(frame).await;
+            false
+        }
+        msg => to.send(msg).await.is_ok(),
+    }
+}